@@ -15,8 +15,19 @@
 * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Directory of the web UI that is bundled into the binary
+const STATIC_DIR: &str = "static";
+
 fn main() {
     let output = Command::new("git")
         .args(&["rev-parse", "HEAD"])
@@ -24,4 +35,77 @@ fn main() {
         .unwrap();
     let git_hash = String::from_utf8(output.stdout).unwrap();
     println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    bundle_assets();
+}
+
+/// Writes the list of files in `static/` with gzip and brotli compressed
+/// variants to `$OUT_DIR/assets.rs`, which is included by `src/assets.rs`.
+fn bundle_assets() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join(STATIC_DIR);
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("assets");
+
+    let mut files = Vec::new();
+    list_files(&root, &mut files);
+    files.sort();
+
+    let mut code = String::from("&[\n");
+    for file in files {
+        let path = file
+            .strip_prefix(&root)
+            .unwrap()
+            .components()
+            .map(|component| component.as_os_str().to_str().unwrap())
+            .collect::<Vec<_>>()
+            .join("/");
+        let content = fs::read(&file).unwrap();
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(&content).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut brotli = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut brotli, 4096, 11, 22);
+            writer.write_all(&content).unwrap();
+        }
+
+        let variant = |extension: &str, compressed: Vec<u8>| {
+            // clients would only download more
+            if compressed.len() >= content.len() {
+                return "None".to_owned();
+            }
+            let out = out_dir.join(format!("{}.{}", path, extension));
+            fs::create_dir_all(out.parent().unwrap()).unwrap();
+            fs::write(&out, compressed).unwrap();
+            format!("Some(include_bytes!({:?}))", out)
+        };
+        let gzip = variant("gz", gzip);
+        let brotli = variant("br", brotli);
+
+        writeln!(
+            code,
+            "    Asset {{ path: {:?}, content: include_bytes!({:?}), gzip: {}, brotli: {} }},",
+            path, file, gzip, brotli
+        )
+        .unwrap();
+    }
+    code.push_str("]\n");
+
+    fs::write(
+        PathBuf::from(env::var("OUT_DIR").unwrap()).join("assets.rs"),
+        code,
+    )
+    .unwrap();
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            list_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
 }
@@ -0,0 +1,4 @@
+-- Email addresses need to be confirmed before they can be used for signing in
+ALTER TABLE triox_users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+-- Addresses of existing accounts are trusted, only new ones need to be confirmed
+UPDATE triox_users SET email_verified = TRUE WHERE email IS NOT NULL;
@@ -0,0 +1,2 @@
+-- Storage quota in bytes, NULL means unlimited
+ALTER TABLE triox_users ADD COLUMN quota BIGINT DEFAULT NULL;
@@ -0,0 +1,8 @@
+-- Invite codes for the "invite" and "allowlist" registration modes
+CREATE TABLE IF NOT EXISTS triox_invites (
+  code VARCHAR(32) PRIMARY KEY NOT NULL,
+  created_by INTEGER NOT NULL REFERENCES triox_users(id) ON DELETE CASCADE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  used_at TIMESTAMPTZ DEFAULT NULL,
+  used_by INTEGER REFERENCES triox_users(id) ON DELETE SET NULL
+);
@@ -0,0 +1,7 @@
+-- Accounts of OpenID Connect providers linked to Triox users
+CREATE TABLE IF NOT EXISTS triox_oidc_identities (
+  provider VARCHAR(60) NOT NULL,
+  subject VARCHAR(255) NOT NULL,
+  user_id INTEGER NOT NULL REFERENCES triox_users(id) ON DELETE CASCADE,
+  PRIMARY KEY (provider, subject)
+);
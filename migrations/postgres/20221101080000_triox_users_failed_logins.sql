@@ -0,0 +1,4 @@
+-- Failed sign in attempts since the last successful one
+ALTER TABLE triox_users ADD COLUMN failed_logins INTEGER NOT NULL DEFAULT 0;
+-- Sign in attempts are rejected until this time
+ALTER TABLE triox_users ADD COLUMN locked_until TIMESTAMPTZ DEFAULT NULL;
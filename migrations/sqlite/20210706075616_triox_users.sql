@@ -0,0 +1,11 @@
+-- Add migration script here
+-- AUTOINCREMENT keeps IDs of deleted users from being reused like SERIAL in Postgres
+CREATE TABLE IF NOT EXISTS triox_users (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  name VARCHAR(60) UNIQUE NOT NULL,
+  email VARCHAR(60) UNIQUE DEFAULT NULL,
+  password TEXT NOT NULL,
+  locale CHAR(5),
+  role SMALLINT NOT NULL DEFAULT 0,
+  status VARCHAR(40)
+);
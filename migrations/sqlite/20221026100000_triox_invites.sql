@@ -0,0 +1,9 @@
+-- Invite codes for the "invite" and "allowlist" registration modes,
+-- timestamps are UNIX timestamps in seconds
+CREATE TABLE IF NOT EXISTS triox_invites (
+  code VARCHAR(32) PRIMARY KEY NOT NULL,
+  created_by INTEGER NOT NULL REFERENCES triox_users(id) ON DELETE CASCADE,
+  created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)),
+  used_at INTEGER DEFAULT NULL,
+  used_by INTEGER REFERENCES triox_users(id) ON DELETE SET NULL
+);
@@ -0,0 +1,2 @@
+-- Authentication provider that manages the account ("local" or "ldap")
+ALTER TABLE triox_users ADD COLUMN provider VARCHAR(20) NOT NULL DEFAULT 'local';
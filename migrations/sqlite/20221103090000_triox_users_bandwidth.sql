@@ -0,0 +1,3 @@
+-- Bandwidth limit of file transfers in bytes per second,
+-- NULL means the configured default applies
+ALTER TABLE triox_users ADD COLUMN bandwidth BIGINT DEFAULT NULL;
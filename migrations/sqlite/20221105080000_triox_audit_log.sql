@@ -0,0 +1,19 @@
+-- Security relevant events, entries are kept after the user is deleted
+CREATE TABLE IF NOT EXISTS triox_audit_log (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  -- UNIX timestamp in seconds
+  created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)),
+  -- acting user, NULL for failed sign ins of unknown users
+  user_id INTEGER DEFAULT NULL,
+  ip VARCHAR(45) DEFAULT NULL,
+  event VARCHAR(40) NOT NULL,
+  -- file path of files app operations
+  path TEXT DEFAULT NULL,
+  -- event specific information, e.g. the affected account
+  detail TEXT DEFAULT NULL,
+  success BOOLEAN NOT NULL,
+  error TEXT DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS triox_audit_log_created_at ON triox_audit_log (created_at);
+CREATE INDEX IF NOT EXISTS triox_audit_log_user_id ON triox_audit_log (user_id);
@@ -16,51 +16,48 @@
 */
 
 use actix_identity::Identity;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
 use super::auth::runners::Password;
+use crate::audit::{Event, Record};
 use crate::errors::*;
+use crate::middleware::rate_limit::{Policy, RateLimit};
 use crate::AppData;
 
 #[my_codegen::post(
     path = "crate::V1_API_ROUTES.account.delete",
-    wrap = "crate::CheckLogin"
+    wrap = "crate::CheckLogin",
+    wrap = "RateLimit::new(Policy::Account)"
 )]
 async fn delete_account(
+    req: HttpRequest,
     id: Identity,
     payload: web::Json<Password>,
     data: AppData,
 ) -> ServiceResult<impl Responder> {
-    use argon2_creds::Config;
-    use sqlx::Error::RowNotFound;
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
 
-    let username = id.identity().unwrap();
+    let res = delete(user_id, &payload.password, &data).await;
+    Record::new(Event::AccountDelete)
+        .user(user_id)
+        .save(&req, &data, &res)
+        .await;
+    res?;
 
-    let rec = sqlx::query_as!(
-        Password,
-        r#"SELECT password  FROM triox_users WHERE name = ($1)"#,
-        &username,
-    )
-    .fetch_one(&data.db)
-    .await;
+    id.forget();
+    Ok(HttpResponse::Ok())
+}
 
-    match rec {
-        Ok(s) => {
-            if Config::verify(&s.password, &payload.password)? {
-                sqlx::query!("DELETE FROM triox_users WHERE name = ($1)", &username)
-                    .execute(&data.db)
-                    .await?;
+async fn delete(user_id: i32, password: &str, data: &AppData) -> ServiceResult<()> {
+    use argon2_creds::Config;
+    use sqlx::Error::RowNotFound;
 
-                // delete storage path of the user
-                let path: std::path::PathBuf =
-                    [".", "data", "users", &username].iter().collect();
+    let rec = data.db.password(user_id).await;
 
-                std::fs::remove_dir_all(path).map_err(|err| {
-                    log::error!("STORAGE PATH: {:?}", err);
-                    err
-                })?;
-                id.forget();
-                Ok(HttpResponse::Ok())
+    match rec {
+        Ok(hash) => {
+            if Config::verify(&hash, password)? {
+                remove_user(user_id, data).await
             } else {
                 Err(ServiceError::InvalidCredentials)
             }
@@ -70,6 +67,26 @@ async fn delete_account(
     }
 }
 
+/// Deletes the account and the storage directory of a user.
+pub async fn remove_user(user_id: i32, data: &AppData) -> ServiceResult<()> {
+    if !data.db.delete_user(user_id).await? {
+        return Err(ServiceError::AccountNotFound);
+    }
+
+    // delete storage path of the user
+    let path = crate::apps::files::storage::user_path(user_id);
+
+    match std::fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        // users that never stored a file have no storage directory
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => {
+            log::error!("STORAGE PATH: {:?}", err);
+            Err(err.into())
+        }
+    }
+}
+
 pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
     cfg.service(delete_account);
 }
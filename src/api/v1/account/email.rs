@@ -14,14 +14,15 @@
 * You should have received a copy of the GNU Affero General Public License
 * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use std::borrow::Cow;
-
 use actix_identity::Identity;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 
 use super::{AccountCheckPayload, AccountCheckResp};
+use crate::audit::{Event, Record};
+use crate::db::violates_unique;
 use crate::errors::*;
+use crate::middleware::rate_limit::{Policy, RateLimit};
 use crate::AppData;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -29,65 +30,63 @@ pub struct Email {
     pub email: String,
 }
 
-#[my_codegen::post(path = "crate::V1_API_ROUTES.account.email_exists")]
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.account.email_exists",
+    wrap = "RateLimit::new(Policy::Account)"
+)]
 pub async fn email_exists(
     payload: web::Json<AccountCheckPayload>,
     data: AppData,
 ) -> ServiceResult<impl Responder> {
-    let res = sqlx::query!(
-        "SELECT EXISTS (SELECT 1 from triox_users WHERE email = $1)",
-        &payload.val,
-    )
-    .fetch_one(&data.db)
-    .await?;
-
-    let mut resp = AccountCheckResp { exists: false };
-
-    if let Some(x) = res.exists {
-        if x {
-            resp.exists = true;
-        }
-    }
+    let resp = AccountCheckResp {
+        exists: data.db.email_exists(&payload.val).await?,
+    };
 
     Ok(HttpResponse::Ok().json(resp))
 }
 
-/// update email
+/// update email, the new address stays unverified until the user opens the
+/// link sent to it
 #[my_codegen::post(
     path = "crate::V1_API_ROUTES.account.update_email",
-    wrap = "crate::CheckLogin"
+    wrap = "crate::CheckLogin",
+    wrap = "RateLimit::new(Policy::Account)"
 )]
 async fn set_email(
+    req: HttpRequest,
     id: Identity,
     payload: web::Json<Email>,
     data: AppData,
 ) -> ServiceResult<impl Responder> {
-    let username = id.identity().unwrap();
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
 
-    data.creds.email(&payload.email)?;
+    let res = update_email(user_id, &payload.email, &data).await;
+    Record::new(Event::EmailUpdate)
+        .user(user_id)
+        .detail(&payload.email)
+        .save(&req, &data, &res)
+        .await;
+    res?;
 
-    let res = sqlx::query!(
-        "UPDATE triox_users set email = $1
-        WHERE name = $2",
-        &payload.email,
-        &username,
-    )
-    .execute(&data.db)
-    .await;
-    if res.is_err() {
-        if let Err(sqlx::Error::Database(err)) = res {
-            if err.code() == Some(Cow::from("23505"))
-                && err.message().contains("triox_users_email_key")
-            {
-                return Err(ServiceError::EmailTaken);
-            } else {
-                return Err(sqlx::Error::Database(err).into());
-            }
-        };
+    if let Err(e) = super::verify::send_verification_email(user_id, &data).await {
+        log::warn!("Unable to send verification email: {}", e);
     }
     Ok(HttpResponse::Ok())
 }
 
+async fn update_email(user_id: i32, email: &str, data: &AppData) -> ServiceResult<()> {
+    data.creds.email(email)?;
+
+    // keep the verification state if the address didn't change
+    match data.db.set_email(user_id, email).await {
+        Ok(_) => Ok(()),
+        Err(err) if violates_unique(&err, "triox_users", "email") => {
+            Err(ServiceError::EmailTaken)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
 pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
     cfg.service(email_exists);
     cfg.service(set_email);
@@ -0,0 +1,119 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse, Responder};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::middleware::rate_limit::{Policy, RateLimit};
+use crate::AppData;
+
+/// Length of generated invite codes
+pub const INVITE_CODE_LENGTH: usize = 24;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub used: bool,
+}
+
+pub mod runners {
+    use super::*;
+    use crate::api::v1::admin::runners::is_admin;
+
+    /// creates an invite code, regular users are limited to `server.invites_per_user` codes
+    pub async fn create_invite(user_id: i32, data: &AppData) -> ServiceResult<String> {
+        let limit = if is_admin(user_id, data).await? {
+            i64::MAX
+        } else {
+            data.settings().server.invites_per_user as i64
+        };
+
+        let code: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(INVITE_CODE_LENGTH)
+            .map(char::from)
+            .collect();
+
+        if !data.db.create_invite(&code, user_id, limit).await? {
+            return Err(ServiceError::InviteLimitReached);
+        }
+        Ok(code)
+    }
+
+    pub async fn list_invites(
+        user_id: i32,
+        data: &AppData,
+    ) -> ServiceResult<Vec<Invite>> {
+        Ok(data.db.invites(user_id).await?)
+    }
+
+    /// marks an invite as used, fails if it doesn't exist or was already used
+    pub async fn claim_invite(code: &str, data: &AppData) -> ServiceResult<()> {
+        if !data.db.claim_invite(code).await? {
+            return Err(ServiceError::InvalidInvite);
+        }
+        Ok(())
+    }
+
+    /// makes a claimed invite available again, e.g. when the registration failed
+    pub async fn release_invite(code: &str, data: &AppData) -> ServiceResult<()> {
+        data.db.release_invite(code).await?;
+        Ok(())
+    }
+
+    /// records the account that was created with a claimed invite
+    pub async fn redeem_invite(
+        code: &str,
+        user_id: i32,
+        data: &AppData,
+    ) -> ServiceResult<()> {
+        data.db.redeem_invite(code, user_id).await?;
+        Ok(())
+    }
+}
+
+/// create an invite code
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.account.create_invite",
+    wrap = "crate::CheckLogin",
+    wrap = "RateLimit::new(Policy::Account)"
+)]
+async fn create_invite(id: Identity, data: AppData) -> ServiceResult<impl Responder> {
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
+    let code = runners::create_invite(user_id, &data).await?;
+    Ok(HttpResponse::Ok().json(Invite { code, used: false }))
+}
+
+/// invite codes created by the user
+#[my_codegen::get(
+    path = "crate::V1_API_ROUTES.account.invites",
+    wrap = "crate::CheckLogin",
+    wrap = "RateLimit::new(Policy::Account)"
+)]
+async fn invites(id: Identity, data: AppData) -> ServiceResult<impl Responder> {
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
+    let invites = runners::list_invites(user_id, &data).await?;
+    Ok(HttpResponse::Ok().json(invites))
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_invite);
+    cfg.service(invites);
+}
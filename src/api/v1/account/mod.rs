@@ -19,9 +19,11 @@ use serde::{Deserialize, Serialize};
 
 pub mod delete;
 pub mod email;
+pub mod invite;
 #[cfg(test)]
 pub mod test;
 pub mod username;
+pub mod verify;
 
 pub use super::auth;
 
@@ -32,6 +34,12 @@ pub mod routes {
         pub email_exists: &'static str,
         pub update_email: &'static str,
         pub username_exists: &'static str,
+        pub update_username: &'static str,
+        pub verify_email: &'static str,
+        pub resend_verification: &'static str,
+        pub request_verification: &'static str,
+        pub invites: &'static str,
+        pub create_invite: &'static str,
     }
 
     impl Account {
@@ -39,12 +47,24 @@ pub mod routes {
             let delete = "/api/v1/account/delete";
             let email_exists = "/api/v1/account/email/exists";
             let username_exists = "/api/v1/account/username/exists";
+            let update_username = "/api/v1/account/username/update";
             let update_email = "/api/v1/account/email/update";
+            let verify_email = "/api/v1/account/email/verify";
+            let resend_verification = "/api/v1/account/email/verify/resend";
+            let request_verification = "/api/v1/account/email/verify/request";
+            let invites = "/api/v1/account/invites";
+            let create_invite = "/api/v1/account/invites/create";
             Account {
                 delete,
                 email_exists,
                 update_email,
                 username_exists,
+                update_username,
+                verify_email,
+                resend_verification,
+                request_verification,
+                invites,
+                create_invite,
             }
         }
     }
@@ -63,5 +83,7 @@ pub struct AccountCheckResp {
 pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
     delete::services(cfg);
     email::services(cfg);
+    invite::services(cfg);
     username::services(cfg);
+    verify::services(cfg);
 }
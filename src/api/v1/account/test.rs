@@ -19,10 +19,11 @@ use actix_web::http::StatusCode;
 use actix_web::test;
 
 use super::email::*;
+use super::username::Username;
 use super::*;
-use crate::api::v1::auth::runners::Password;
+use crate::api::v1::auth::runners::{Login, Password};
 use crate::api::v1::ROUTES;
-use crate::app_state::AppState;
+use crate::errors::*;
 use crate::*;
 
 use crate::tests::*;
@@ -34,7 +35,7 @@ async fn uname_email_exists_works() {
     const EMAIL: &str = "testuserexists@a.com2";
 
     {
-        let data = AppState::new().await;
+        let data = app_state().await;
         delete_user(NAME, &data).await;
     }
 
@@ -101,7 +102,7 @@ async fn email_udpate_password_validation_del_userworks() {
     const EMAIL: &str = "testuser1@a.com2";
 
     {
-        let data = AppState::new().await;
+        let data = app_state().await;
         delete_user(NAME, &data).await;
     }
 
@@ -138,3 +139,210 @@ async fn email_udpate_password_validation_del_userworks() {
 
     assert_eq!(delete_user_resp.status(), StatusCode::OK);
 }
+
+#[actix_rt::test]
+async fn email_verification_works() {
+    const NAME: &str = "testuserverify";
+    const PASSWORD: &str = "longpassword2";
+    const EMAIL: &str = "testuserverify@a.com";
+    const NEW_EMAIL: &str = "testuserverify2@a.com";
+
+    {
+        let data = app_state().await;
+        delete_user(NAME, &data).await;
+    }
+
+    let (data, _, signin_resp) =
+        register_and_signin(NAME, Some(EMAIL.into()), PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+    let app = get_app!(data).await;
+
+    let mut login = Login {
+        login: EMAIL.into(),
+        password: PASSWORD.into(),
+    };
+
+    // unverified addresses can't be used for signing in
+    bad_post_req_test(
+        NAME,
+        PASSWORD,
+        ROUTES.auth.login,
+        &login,
+        ServiceError::EmailNotVerified,
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+
+    // mailer isn't configured in tests
+    let resend_resp = test::call_service(
+        &app,
+        post_request!(ROUTES.account.resend_verification)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resend_resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // resending without a session as well
+    let request = verify::VerificationRequest { login: NAME.into() };
+    let request_resp = test::call_service(
+        &app,
+        post_request!(&request, ROUTES.account.request_verification).to_request(),
+    )
+    .await;
+    assert_eq!(request_resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let invalid_token_resp = test::call_service(
+        &app,
+        get_req!(&format!("{}?token=invalid", ROUTES.account.verify_email)).to_request(),
+    )
+    .await;
+    assert_eq!(invalid_token_resp.status(), StatusCode::BAD_REQUEST);
+
+    verify_email(NAME, &data).await;
+    signin(EMAIL, PASSWORD).await;
+
+    // changing the address requires a new verification
+    let email_payload = Email {
+        email: NEW_EMAIL.into(),
+    };
+    let email_update_resp = test::call_service(
+        &app,
+        post_request!(&email_payload, ROUTES.account.update_email)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(email_update_resp.status(), StatusCode::OK);
+
+    login.login = NEW_EMAIL.into();
+    bad_post_req_test(
+        NAME,
+        PASSWORD,
+        ROUTES.auth.login,
+        &login,
+        ServiceError::EmailNotVerified,
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+
+    verify_email(NAME, &data).await;
+    signin(NEW_EMAIL, PASSWORD).await;
+}
+
+#[actix_rt::test]
+async fn request_verification_works() {
+    const NAME: &str = "testuserrequestverify";
+    const PASSWORD: &str = "longpassword2";
+    const EMAIL: &str = "testuserrequestverify@a.com";
+    const DIR: &str = "./data/test-mail-request-verification";
+    let _ = tokio::fs::remove_dir_all(DIR).await;
+
+    let mut config = settings();
+    config.smtp = Some(crate::config::Smtp {
+        transport: crate::config::MailTransport::File,
+        from: "Triox <noreply@localhost>".into(),
+        host: None,
+        port: None,
+        username: None,
+        password: None,
+        encryption: crate::config::SmtpEncryption::None,
+        directory: Some(DIR.into()),
+        queue_size: 10,
+        max_retries: 0,
+        retry_delay: 0,
+    });
+    let data = actix_web::web::Data::new(app_state_with(config).await);
+    delete_user(NAME, &data).await;
+    register(NAME, Some(EMAIL.into()), PASSWORD).await;
+
+    // unknown accounts look the same to the client
+    verify::request_verification_runner("nobody-requestverify", &data)
+        .await
+        .unwrap();
+    verify::request_verification_runner(NAME, &data)
+        .await
+        .unwrap();
+
+    // wait for the background task
+    let mut delivered = Vec::new();
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        if let Ok(mut dir) = tokio::fs::read_dir(DIR).await {
+            while let Some(entry) = dir.next_entry().await.unwrap() {
+                delivered.push(tokio::fs::read_to_string(entry.path()).await.unwrap());
+            }
+        }
+        if !delivered.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(delivered.len(), 1);
+    assert!(delivered[0].contains(EMAIL));
+
+    delete_user(NAME, &data).await;
+    let _ = tokio::fs::remove_dir_all(DIR).await;
+}
+
+#[actix_rt::test]
+async fn username_update_works() {
+    const NAME: &str = "testuserrename";
+    const NEW_NAME: &str = "testuserrenamed";
+    const TAKEN_NAME: &str = "testuserrenametaken";
+    const PASSWORD: &str = "longpassword2";
+
+    {
+        let data = app_state().await;
+        delete_user(NAME, &data).await;
+        delete_user(NEW_NAME, &data).await;
+        delete_user(TAKEN_NAME, &data).await;
+    }
+
+    register(TAKEN_NAME, None, PASSWORD).await;
+    let (data, _, signin_resp) = register_and_signin(NAME, None, PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+    let app = get_app!(data).await;
+    let id = user_id(NAME, &data).await;
+
+    let mut payload = Username {
+        username: TAKEN_NAME.into(),
+    };
+
+    let taken_resp = test::call_service(
+        &app,
+        post_request!(&payload, ROUTES.account.update_username)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(taken_resp.status(), StatusCode::BAD_REQUEST);
+    let txt: ErrorToResponse = test::read_body_json(taken_resp).await;
+    assert_eq!(txt.error, format!("{}", ServiceError::UsernameTaken));
+
+    payload.username = NEW_NAME.into();
+    let rename_resp = test::call_service(
+        &app,
+        post_request!(&payload, ROUTES.account.update_username)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(rename_resp.status(), StatusCode::OK);
+    assert_eq!(user_id(NEW_NAME, &data).await, id);
+
+    // storage and session are keyed by ID and unaffected by the rename
+    assert!(crate::apps::files::storage::user_path(id).exists());
+    let list_resp = test::call_service(
+        &app,
+        get_req!(&path(FILE_ROUTES.list, ""))
+            .cookie(cookies)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(list_resp.status(), StatusCode::OK);
+
+    signin(NEW_NAME, PASSWORD).await;
+
+    delete_user(NEW_NAME, &data).await;
+    delete_user(TAKEN_NAME, &data).await;
+}
@@ -14,35 +14,75 @@
 * You should have received a copy of the GNU Affero General Public License
 * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use actix_web::{web, HttpResponse, Responder};
+use actix_identity::Identity;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
 
 use super::{AccountCheckPayload, AccountCheckResp};
+use crate::audit::{Event, Record};
 use crate::errors::*;
+use crate::middleware::rate_limit::{Policy, RateLimit};
 use crate::AppData;
 
-#[my_codegen::post(path = "crate::V1_API_ROUTES.account.username_exists")]
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.account.username_exists",
+    wrap = "RateLimit::new(Policy::Account)"
+)]
 async fn username_exists(
     payload: web::Json<AccountCheckPayload>,
     data: AppData,
 ) -> ServiceResult<impl Responder> {
-    let res = sqlx::query!(
-        "SELECT EXISTS (SELECT 1 from triox_users WHERE name = $1)",
-        &payload.val,
-    )
-    .fetch_one(&data.db)
-    .await?;
+    let resp = AccountCheckResp {
+        exists: data.db.name_exists(&payload.val).await?,
+    };
 
-    let mut resp = AccountCheckResp { exists: false };
+    Ok(HttpResponse::Ok().json(resp))
+}
 
-    if let Some(x) = res.exists {
-        if x {
-            resp.exists = true;
-        }
-    }
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Username {
+    pub username: String,
+}
 
-    Ok(HttpResponse::Ok().json(resp))
+/// update username
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.account.update_username",
+    wrap = "crate::CheckLogin",
+    wrap = "RateLimit::new(Policy::Account)"
+)]
+async fn set_username(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<Username>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = update_username(user_id, &payload.username, &data).await;
+    Record::new(Event::UsernameUpdate)
+        .user(user_id)
+        .detail(&payload.username)
+        .save(&req, &data, &res)
+        .await;
+    res?;
+
+    Ok(HttpResponse::Ok())
+}
+
+async fn update_username(
+    user_id: i32,
+    username: &str,
+    data: &AppData,
+) -> ServiceResult<()> {
+    let username = data.creds.username(username)?;
+
+    // storage and session are keyed by the user ID and don't need to be updated
+    data.db.set_name(user_id, &username).await?;
+
+    Ok(())
 }
 
 pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
     cfg.service(username_exists);
+    cfg.service(set_username);
 }
@@ -0,0 +1,220 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::time::Instant;
+
+use actix_identity::Identity;
+use actix_web::http::header;
+use actix_web::{web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::api::v1::admin::STATUS_UNVERIFIED;
+use crate::errors::*;
+use crate::mailer::{Email, Template};
+use crate::middleware::rate_limit::{Key, Policy, RateLimit};
+use crate::tokens::{now, sign, unsign};
+use crate::AppData;
+
+/// Lifetime of verification links in seconds
+pub const VERIFICATION_LINK_LIFETIME: u64 = 60 * 60 * 24;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VerificationToken {
+    pub token: String,
+}
+
+/// Creates a signed token that proves ownership of `email` for the account `id`.
+/// The token expires after [VERIFICATION_LINK_LIFETIME] seconds.
+pub fn verification_token(secret: &str, id: i32, email: &str) -> String {
+    sign(
+        secret,
+        &format!("{}:{}:{}", id, now() + VERIFICATION_LINK_LIFETIME, email),
+    )
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VerificationRequest {
+    /// name or email address of the account
+    pub login: String,
+}
+
+/// Checks signature and expiry of a token and returns the account ID and email address.
+fn decode_token(secret: &str, token: &str) -> ServiceResult<(i32, String)> {
+    let payload = unsign(secret, token).ok_or(ServiceError::InvalidToken)?;
+    let mut parts = payload.splitn(3, ':');
+    let (id, expires, email) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(id), Some(expires), Some(email)) => (id, expires, email),
+        _ => return Err(ServiceError::InvalidToken),
+    };
+
+    let id: i32 = id.parse().map_err(|_| ServiceError::InvalidToken)?;
+    let expires: u64 = expires.parse().map_err(|_| ServiceError::InvalidToken)?;
+    if expires < now() {
+        return Err(ServiceError::InvalidToken);
+    }
+
+    Ok((id, email.to_owned()))
+}
+
+/// Sends a verification link to the email address of the user.
+/// Does nothing if the user has no email address or already verified it.
+pub async fn send_verification_email(user_id: i32, data: &AppData) -> ServiceResult<()> {
+    let mailer = data
+        .mailer
+        .as_ref()
+        .ok_or(ServiceError::MailerUnavailable)?;
+
+    let user = data.db.contact(user_id).await?;
+
+    let email = match user.email {
+        Some(email) if !user.email_verified => email,
+        _ => return Ok(()),
+    };
+
+    let settings = data.settings();
+    let link = format!(
+        "{}{}?token={}",
+        settings.base_url(),
+        crate::V1_API_ROUTES.account.verify_email,
+        verification_token(&settings.server.secret, user_id, &email)
+    );
+
+    mailer.send(Email {
+        to: email,
+        locale: user.locale,
+        template: Template::VerifyEmail,
+        vars: vec![("username", user.name), ("link", link)],
+    })
+}
+
+/// Target of the link in verification emails
+#[my_codegen::get(
+    path = "crate::V1_API_ROUTES.account.verify_email",
+    wrap = "RateLimit::new(Policy::Account)"
+)]
+async fn verify_email(
+    web::Query(payload): web::Query<VerificationToken>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let (id, email) = decode_token(&data.settings().server.secret, &payload.token)?;
+
+    // the address might have been changed after the link was sent
+    if !data.db.verify_email(id, &email, STATUS_UNVERIFIED).await? {
+        return Err(ServiceError::InvalidToken);
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header((header::LOCATION, crate::middleware::auth::SIGIN_PAGE))
+        .finish())
+}
+
+/// Send another verification link to the current email address
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.account.resend_verification",
+    wrap = "crate::CheckLogin",
+    wrap = "RateLimit::new(Policy::Account)"
+)]
+async fn resend_verification(
+    id: Identity,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
+    send_verification_email(user_id, &data).await?;
+    Ok(HttpResponse::Ok())
+}
+
+/// Sends another verification link to the account `login` refers to, for
+/// accounts that can't sign in before they verify their address.
+/// Succeeds whether the account exists or not.
+pub async fn request_verification_runner(
+    login: &str,
+    data: &AppData,
+) -> ServiceResult<()> {
+    if data.mailer.is_none() {
+        return Err(ServiceError::MailerUnavailable);
+    }
+
+    let user_id = match data.db.login_state(login).await? {
+        Some((user_id, _)) => user_id,
+        None => return Ok(()),
+    };
+
+    // requests are limited per address by the middleware, this keeps clients
+    // with many addresses from flooding the inbox of the user
+    if let Some(limiter) = data.limiters.get(Policy::Account) {
+        if !limiter.check(Key::User(user_id), Instant::now()).allowed {
+            log::debug!("Dropped verification request for user {}", user_id);
+            return Ok(());
+        }
+    }
+
+    send_verification_email(user_id, data).await
+}
+
+/// Send another verification link without a session
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.account.request_verification",
+    wrap = "RateLimit::new(Policy::Account)"
+)]
+async fn request_verification(
+    payload: web::Json<VerificationRequest>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    request_verification_runner(&payload.login, &data).await?;
+    Ok(HttpResponse::Ok())
+}
+
+pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
+    cfg.service(verify_email);
+    cfg.service(resend_verification);
+    cfg.service(request_verification);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_works() {
+        const EMAIL: &str = "token@example.com";
+        const SECRET: &str = "verification token secret";
+        let token = verification_token(SECRET, 7, EMAIL);
+        assert_eq!(decode_token(SECRET, &token), Ok((7, EMAIL.to_owned())));
+        assert_eq!(
+            decode_token("other secret", &token),
+            Err(ServiceError::InvalidToken)
+        );
+
+        // tampered payload
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            base64::encode_config(
+                format!("8:{}:{}", now() + 100, EMAIL),
+                base64::URL_SAFE_NO_PAD
+            ),
+            signature
+        );
+        assert_eq!(
+            decode_token(SECRET, &forged),
+            Err(ServiceError::InvalidToken)
+        );
+        assert_eq!(
+            decode_token(SECRET, "garbage"),
+            Err(ServiceError::InvalidToken)
+        );
+    }
+}
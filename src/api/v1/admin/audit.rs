@@ -0,0 +1,145 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use actix_web::http::header;
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::Event;
+use crate::errors::*;
+use crate::AppData;
+
+pub mod runners {
+    use super::*;
+
+    /// Maximum amount of entries returned by a single query
+    pub const MAX_LIMIT: i64 = 1000;
+
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    pub struct AuditQuery {
+        pub user_id: Option<i32>,
+        pub event: Option<Event>,
+        /// UNIX timestamp in seconds
+        pub since: Option<i64>,
+        /// UNIX timestamp in seconds, exclusive
+        pub until: Option<i64>,
+        pub limit: Option<i64>,
+        pub offset: Option<i64>,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct AuditEntry {
+        pub id: i64,
+        /// UNIX timestamp in seconds
+        pub timestamp: i64,
+        pub user_id: Option<i32>,
+        pub ip: Option<String>,
+        pub event: String,
+        pub path: Option<String>,
+        pub detail: Option<String>,
+        pub success: bool,
+        pub error: Option<String>,
+    }
+
+    /// entries matching the query, newest first
+    pub async fn list_entries_runner(
+        query: &AuditQuery,
+        data: &AppData,
+    ) -> ServiceResult<Vec<AuditEntry>> {
+        let limit = query.limit.unwrap_or(MAX_LIMIT).clamp(0, MAX_LIMIT);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        Ok(data.db.audit_entries(query, limit, offset).await?)
+    }
+
+    /// entries matching the query with an ID greater than `after`, oldest first.
+    /// `limit` and `offset` of the query are ignored.
+    pub async fn export_entries_runner(
+        query: &AuditQuery,
+        after: i64,
+        data: &AppData,
+    ) -> ServiceResult<Vec<AuditEntry>> {
+        Ok(data
+            .db
+            .export_audit_entries(query, after, MAX_LIMIT)
+            .await?)
+    }
+
+    /// one JSON object per line
+    pub fn json_lines(entries: &[AuditEntry]) -> ServiceResult<Bytes> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut buf, entry)
+                .map_err(|_| ServiceError::InternalServerError)?;
+            buf.push(b'\n');
+        }
+        Ok(buf.into())
+    }
+}
+
+use runners::*;
+
+/// query the audit log
+#[my_codegen::get(
+    path = "crate::V1_API_ROUTES.admin.audit",
+    wrap = "crate::RequireAdmin"
+)]
+async fn audit(
+    web::Query(query): web::Query<AuditQuery>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let entries = list_entries_runner(&query, &data).await?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// export the audit log as JSON lines, oldest entries first
+#[my_codegen::get(
+    path = "crate::V1_API_ROUTES.admin.audit_export",
+    wrap = "crate::RequireAdmin"
+)]
+async fn audit_export(
+    web::Query(query): web::Query<AuditQuery>,
+    data: AppData,
+) -> impl Responder {
+    // entries are fetched in batches while the response is sent
+    let body = futures::stream::unfold(Some(0), move |after| {
+        let query = query.clone();
+        let data = data.clone();
+        async move {
+            let entries = match export_entries_runner(&query, after?, &data).await {
+                Ok(entries) if entries.is_empty() => return None,
+                Ok(entries) => entries,
+                Err(e) => return Some((Err(e), None)),
+            };
+            let last = entries.last().map(|entry| entry.id);
+            Some((json_lines(&entries), last))
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .append_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"audit.jsonl\"",
+        ))
+        .streaming(body)
+}
+
+pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
+    cfg.service(audit);
+    cfg.service(audit_export);
+}
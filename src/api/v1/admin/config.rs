@@ -0,0 +1,57 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use actix_identity::Identity;
+use actix_web::{HttpRequest, HttpResponse, Responder};
+
+use crate::audit::{Event, Record};
+use crate::errors::*;
+use crate::AppData;
+
+/// reload the configuration files, same as sending SIGHUP to the server
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.reload_config",
+    wrap = "crate::RequireAdmin"
+)]
+async fn reload_config(
+    req: HttpRequest,
+    id: Identity,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let reload = data.reload_settings();
+    let res = if reload.problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ServiceError::InvalidConfiguration)
+    };
+    Record::new(Event::ConfigReload)
+        .user(admin_id)
+        .save(&req, &data, &res)
+        .await;
+
+    // the problems are returned instead of the error message
+    if res.is_err() {
+        return Ok(HttpResponse::UnprocessableEntity().json(reload));
+    }
+    Ok(HttpResponse::Ok().json(reload))
+}
+
+pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
+    cfg.service(reload_config);
+}
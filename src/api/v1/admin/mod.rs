@@ -0,0 +1,138 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+pub mod audit;
+pub mod config;
+#[cfg(test)]
+pub mod test;
+pub mod users;
+
+pub use users::runners;
+
+/// Value of `triox_users.status` for accounts that can't sign in
+pub const STATUS_LOCKED: &str = "locked";
+/// Value of `triox_users.status` for accounts that can't sign in
+/// until they verify their email address
+pub const STATUS_UNVERIFIED: &str = "unverified";
+/// Value of `triox_users.status` for locked accounts that still have to
+/// verify their email address once they are unlocked
+pub const STATUS_LOCKED_UNVERIFIED: &str = "locked_unverified";
+
+/// Returns true if `status` keeps the account from signing in until an admin unlocks it
+pub fn is_locked(status: Option<&str>) -> bool {
+    matches!(status, Some(STATUS_LOCKED | STATUS_LOCKED_UNVERIFIED))
+}
+
+/// Values of `triox_users.role`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn from_db(role: i16) -> Self {
+        match role {
+            1 => Role::Admin,
+            _ => Role::User,
+        }
+    }
+
+    pub fn to_db(self) -> i16 {
+        match self {
+            Role::User => 0,
+            Role::Admin => 1,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!(
+                "invalid role \"{}\", expected \"user\" or \"admin\"",
+                s
+            )),
+        }
+    }
+}
+
+pub mod routes {
+    pub struct Admin {
+        pub users: &'static str,
+        pub create_user: &'static str,
+        pub delete_user: &'static str,
+        pub set_role: &'static str,
+        pub lock_user: &'static str,
+        pub unlock_user: &'static str,
+        pub reset_password: &'static str,
+        pub set_quota: &'static str,
+        pub set_bandwidth: &'static str,
+        pub usage: &'static str,
+        pub audit: &'static str,
+        pub audit_export: &'static str,
+        pub reload_config: &'static str,
+    }
+
+    impl Admin {
+        pub const fn new() -> Admin {
+            let users = "/api/v1/admin/users";
+            let create_user = "/api/v1/admin/users/create";
+            let delete_user = "/api/v1/admin/users/delete";
+            let set_role = "/api/v1/admin/users/role";
+            let lock_user = "/api/v1/admin/users/lock";
+            let unlock_user = "/api/v1/admin/users/unlock";
+            let reset_password = "/api/v1/admin/users/password";
+            let set_quota = "/api/v1/admin/users/quota";
+            let set_bandwidth = "/api/v1/admin/users/bandwidth";
+            let usage = "/api/v1/admin/users/usage";
+            let audit = "/api/v1/admin/audit";
+            let audit_export = "/api/v1/admin/audit/export";
+            let reload_config = "/api/v1/admin/config/reload";
+            Admin {
+                users,
+                create_user,
+                delete_user,
+                set_role,
+                lock_user,
+                unlock_user,
+                reset_password,
+                set_quota,
+                set_bandwidth,
+                usage,
+                audit,
+                audit_export,
+                reload_config,
+            }
+        }
+    }
+}
+
+pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
+    audit::services(cfg);
+    config::services(cfg);
+    users::services(cfg);
+}
@@ -0,0 +1,442 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+
+use super::runners::*;
+use super::Role;
+use crate::api::v1::auth::runners::Login;
+use crate::api::v1::ROUTES;
+use crate::errors::*;
+use crate::*;
+
+use crate::tests::*;
+
+#[actix_rt::test]
+async fn admin_works() {
+    const ADMIN: &str = "testadmin";
+    const NAME: &str = "testadminuser";
+    const CREATED: &str = "testadmincreated";
+    const PASSWORD: &str = "longpassword2";
+    const NEW_PASSWORD: &str = "longpassword3";
+
+    {
+        let data = app_state().await;
+        delete_user(ADMIN, &data).await;
+        delete_user(NAME, &data).await;
+        delete_user(CREATED, &data).await;
+    }
+
+    register(NAME, None, PASSWORD).await;
+    let (data, _, signin_resp) = register_and_signin(ADMIN, None, PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+    let app = get_app!(data).await;
+    let id = user_id(NAME, &data).await;
+
+    // regular users can't use the admin API
+    let forbidden_resp = test::call_service(
+        &app,
+        get_req!(ROUTES.admin.users)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(forbidden_resp.status(), StatusCode::FORBIDDEN);
+    let txt: ErrorToResponse = test::read_body_json(forbidden_resp).await;
+    assert_eq!(txt.error, format!("{}", ServiceError::AdminRequired));
+
+    let admin_id = user_id(ADMIN, &data).await;
+    data.db.set_role(admin_id, Role::Admin).await.unwrap();
+
+    // search
+    let search_resp = test::call_service(
+        &app,
+        get_req!(&format!("{}?query={}", ROUTES.admin.users, NAME))
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(search_resp.status(), StatusCode::OK);
+    let users: Vec<User> = test::read_body_json(search_resp).await;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].id, id);
+    assert_eq!(users[0].role, Role::User);
+    assert!(!users[0].locked);
+
+    // lock
+    let lock_resp = test::call_service(
+        &app,
+        post_request!(&UserId { id }, ROUTES.admin.lock_user)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(lock_resp.status(), StatusCode::OK);
+
+    let login = Login {
+        login: NAME.into(),
+        password: PASSWORD.into(),
+    };
+    let locked_resp =
+        test::call_service(&app, post_request!(&login, ROUTES.auth.login).to_request())
+            .await;
+    assert_eq!(locked_resp.status(), StatusCode::FORBIDDEN);
+    let txt: ErrorToResponse = test::read_body_json(locked_resp).await;
+    assert_eq!(txt.error, format!("{}", ServiceError::AccountLocked));
+
+    // unlock
+    let unlock_resp = test::call_service(
+        &app,
+        post_request!(&UserId { id }, ROUTES.admin.unlock_user)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(unlock_resp.status(), StatusCode::OK);
+    signin(NAME, PASSWORD).await;
+
+    // reset password
+    let reset = ResetPassword {
+        id,
+        password: NEW_PASSWORD.into(),
+    };
+    let reset_resp = test::call_service(
+        &app,
+        post_request!(&reset, ROUTES.admin.reset_password)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(reset_resp.status(), StatusCode::OK);
+    signin(NAME, NEW_PASSWORD).await;
+
+    // quota and usage
+    let quota = Quota {
+        id,
+        quota: Some(1024),
+    };
+    let quota_resp = test::call_service(
+        &app,
+        post_request!(&quota, ROUTES.admin.set_quota)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(quota_resp.status(), StatusCode::OK);
+
+    let usage_resp = test::call_service(
+        &app,
+        get_req!(&format!("{}?id={}", ROUTES.admin.usage, id))
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(usage_resp.status(), StatusCode::OK);
+    let usage: Usage = test::read_body_json(usage_resp).await;
+    assert_eq!(usage.used, 0);
+    assert_eq!(usage.quota, Some(1024));
+
+    // bandwidth
+    let bandwidth = Bandwidth {
+        id,
+        bandwidth: Some(1024 * 1024),
+    };
+    let bandwidth_resp = test::call_service(
+        &app,
+        post_request!(&bandwidth, ROUTES.admin.set_bandwidth)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(bandwidth_resp.status(), StatusCode::OK);
+    let users = list_users_runner(
+        &UserQuery {
+            query: Some(NAME.into()),
+            limit: None,
+            offset: None,
+        },
+        &actix_web::web::Data::new(data.clone()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(users[0].bandwidth, Some(1024 * 1024));
+
+    // create
+    let create = CreateUser {
+        username: CREATED.into(),
+        password: PASSWORD.into(),
+        email: None,
+        role: None,
+    };
+    let create_resp = test::call_service(
+        &app,
+        post_request!(&create, ROUTES.admin.create_user)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(create_resp.status(), StatusCode::OK);
+    let created: UserId = test::read_body_json(create_resp).await;
+    assert_eq!(created.id, user_id(CREATED, &data).await);
+    signin(CREATED, PASSWORD).await;
+
+    // role
+    let set_role = SetRole {
+        id: created.id,
+        role: Role::Admin,
+    };
+    let role_resp = test::call_service(
+        &app,
+        post_request!(&set_role, ROUTES.admin.set_role)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(role_resp.status(), StatusCode::OK);
+    let app_data = actix_web::web::Data::new(data.clone());
+    assert!(is_admin(created.id, &app_data).await.unwrap());
+
+    // delete
+    let delete_resp = test::call_service(
+        &app,
+        post_request!(&created, ROUTES.admin.delete_user)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(delete_resp.status(), StatusCode::OK);
+    assert!(matches!(
+        user_id_runner(CREATED, &app_data).await,
+        Err(ServiceError::AccountNotFound)
+    ));
+
+    // reloading the configuration, the rate limits disabled by the tests
+    // are applied again
+    let reload_resp = test::call_service(
+        &app,
+        post_request!(ROUTES.admin.reload_config)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(reload_resp.status(), StatusCode::OK);
+    let reload: crate::config::Reload = test::read_body_json(reload_resp).await;
+    assert!(reload.problems.is_empty());
+    assert!(reload.restart_required.is_empty());
+
+    delete_user(NAME, &data).await;
+    delete_user(CREATED, &data).await;
+    delete_user(ADMIN, &data).await;
+}
+
+#[actix_rt::test]
+async fn audit_log_works() {
+    use super::audit::runners::{list_entries_runner, AuditEntry, AuditQuery};
+    use crate::audit::Event;
+
+    const ADMIN: &str = "testauditadmin";
+    const NAME: &str = "testaudituser";
+    const PASSWORD: &str = "longpassword2";
+
+    {
+        let data = app_state().await;
+        delete_user(ADMIN, &data).await;
+        delete_user(NAME, &data).await;
+    }
+
+    let (data, _, _) = register_and_signin(NAME, None, PASSWORD).await;
+    let (_, _, signin_resp) = register_and_signin(ADMIN, None, PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+    let app = get_app!(data).await;
+    let id = user_id(NAME, &data).await;
+
+    let admin_id = user_id(ADMIN, &data).await;
+    data.db.set_role(admin_id, Role::Admin).await.unwrap();
+
+    let login = Login {
+        login: NAME.into(),
+        password: "wrongpassword".into(),
+    };
+    let failed_resp =
+        test::call_service(&app, post_request!(&login, ROUTES.auth.login).to_request())
+            .await;
+    assert_eq!(failed_resp.status(), StatusCode::UNAUTHORIZED);
+
+    // failed attempts can't be attributed to a user
+    let query = AuditQuery {
+        event: Some(Event::Login),
+        ..AuditQuery::default()
+    };
+    let failed = list_entries_runner(&query, &actix_web::web::Data::new(data.clone()))
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|entry| entry.detail.as_deref() == Some(NAME) && !entry.success)
+        .unwrap();
+    assert_eq!(failed.user_id, None);
+    assert_eq!(
+        failed.error,
+        Some(format!("{}", ServiceError::InvalidCredentials))
+    );
+
+    // query
+    let audit_resp = test::call_service(
+        &app,
+        get_req!(&format!(
+            "{}?user_id={}&event=login",
+            ROUTES.admin.audit, id
+        ))
+        .cookie(cookies.clone())
+        .to_request(),
+    )
+    .await;
+    assert_eq!(audit_resp.status(), StatusCode::OK);
+    let entries: Vec<AuditEntry> = test::read_body_json(audit_resp).await;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].user_id, Some(id));
+    assert_eq!(entries[0].event, "login");
+    assert_eq!(entries[0].detail.as_deref(), Some(NAME));
+    assert!(entries[0].success);
+
+    // export
+    let export_resp = test::call_service(
+        &app,
+        get_req!(&format!("{}?user_id={}", ROUTES.admin.audit_export, id))
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(export_resp.status(), StatusCode::OK);
+    let body = test::read_body(export_resp).await;
+    let exported: Vec<AuditEntry> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(exported.iter().any(|entry| entry.event == "register"));
+    assert!(exported.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+    delete_user(NAME, &data).await;
+    delete_user(ADMIN, &data).await;
+}
+
+#[actix_rt::test]
+async fn locking_ends_sessions() {
+    const NAME: &str = "testlockedsession";
+    const PASSWORD: &str = "longpassword2";
+
+    {
+        let data = app_state().await;
+        delete_user(NAME, &data).await;
+    }
+
+    let (data, _, signin_resp) = register_and_signin(NAME, None, PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+    let app = get_app!(data).await;
+    let id = user_id(NAME, &data).await;
+    let data = actix_web::web::Data::new(data);
+
+    let invites = || {
+        get_req!(ROUTES.account.invites)
+            .cookie(cookies.clone())
+            .to_request()
+    };
+    let resp = test::call_service(&app, invites()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    set_locked_runner(id, true, &data).await.unwrap();
+    let resp = test::call_service(&app, invites()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let txt: ErrorToResponse = test::read_body_json(resp).await;
+    assert_eq!(txt.error, format!("{}", ServiceError::PermissionDenied));
+
+    set_locked_runner(id, false, &data).await.unwrap();
+    let resp = test::call_service(&app, invites()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    delete_user(NAME, &data).await;
+}
+
+#[actix_rt::test]
+async fn unlocking_keeps_unverified() {
+    const NAME: &str = "testlockedunverified";
+    const PASSWORD: &str = "longpassword2";
+
+    let data = actix_web::web::Data::new(app_state().await);
+    delete_user(NAME, &data).await;
+    register(NAME, None, PASSWORD).await;
+    let id = user_id(NAME, &data).await;
+    async fn status(data: &AppData, id: i32) -> Option<String> {
+        data.db.account(id).await.unwrap().unwrap().status
+    }
+
+    // unlocking an account that isn't locked doesn't skip the verification
+    data.db
+        .set_status(id, Some(super::STATUS_UNVERIFIED))
+        .await
+        .unwrap();
+    set_locked_runner(id, false, &data).await.unwrap();
+    assert_eq!(
+        status(&data, id).await.as_deref(),
+        Some(super::STATUS_UNVERIFIED)
+    );
+
+    set_locked_runner(id, true, &data).await.unwrap();
+    let login = Login {
+        login: NAME.into(),
+        password: PASSWORD.into(),
+    };
+    assert_eq!(
+        crate::api::v1::auth::runners::login_runner(login, &data).await,
+        Err(ServiceError::AccountLocked)
+    );
+    set_locked_runner(id, false, &data).await.unwrap();
+    assert_eq!(
+        status(&data, id).await.as_deref(),
+        Some(super::STATUS_UNVERIFIED)
+    );
+
+    // accounts without other statuses are unlocked completely
+    data.db.set_status(id, None).await.unwrap();
+    set_locked_runner(id, true, &data).await.unwrap();
+    set_locked_runner(id, false, &data).await.unwrap();
+    assert_eq!(status(&data, id).await, None);
+
+    delete_user(NAME, &data).await;
+}
+
+#[actix_rt::test]
+async fn created_users_get_their_role() {
+    const NAME: &str = "testadmincreatedrole";
+    const PASSWORD: &str = "longpassword2";
+
+    let data = actix_web::web::Data::new(app_state().await);
+    delete_user(NAME, &data).await;
+
+    let create = CreateUser {
+        username: NAME.into(),
+        password: PASSWORD.into(),
+        email: None,
+        role: Some(Role::Admin),
+    };
+    let id = create_user_runner(&create, &data).await.unwrap();
+    let account = data.db.account(id).await.unwrap().unwrap();
+    assert_eq!(account.role, Role::Admin);
+
+    delete_user(NAME, &data).await;
+}
@@ -0,0 +1,481 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use actix_identity::Identity;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{Event, Record};
+use crate::errors::*;
+use crate::AppData;
+
+pub mod runners {
+    use super::*;
+    use crate::api::v1::account::delete::remove_user;
+    use crate::api::v1::admin::{is_locked, Role};
+    use crate::api::v1::auth::runners::{register_account, Register};
+    use crate::apps::files::storage;
+
+    /// Maximum amount of users returned by a single search
+    pub const MAX_LIMIT: i64 = 100;
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct UserQuery {
+        /// matched against username and email, lists all users if missing
+        pub query: Option<String>,
+        pub limit: Option<i64>,
+        pub offset: Option<i64>,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct User {
+        pub id: i32,
+        pub name: String,
+        pub email: Option<String>,
+        pub email_verified: bool,
+        pub role: Role,
+        pub locked: bool,
+        /// temporarily locked after failed sign in attempts
+        pub login_blocked: bool,
+        pub quota: Option<i64>,
+        /// bandwidth limit override in bytes per second
+        pub bandwidth: Option<i64>,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct CreateUser {
+        pub username: String,
+        pub password: String,
+        pub email: Option<String>,
+        pub role: Option<Role>,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct UserId {
+        pub id: i32,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct SetRole {
+        pub id: i32,
+        pub role: Role,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct ResetPassword {
+        pub id: i32,
+        pub password: String,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Quota {
+        pub id: i32,
+        /// quota in bytes, `None` removes the quota
+        pub quota: Option<i64>,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Bandwidth {
+        pub id: i32,
+        /// bytes per second, `None` applies the configured limit
+        pub bandwidth: Option<i64>,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Usage {
+        pub id: i32,
+        /// used storage in bytes
+        pub used: u64,
+        pub quota: Option<i64>,
+    }
+
+    /// returns true if the user exists, is an admin and isn't locked
+    pub async fn is_admin(user_id: i32, data: &AppData) -> ServiceResult<bool> {
+        let account = data.db.account(user_id).await?;
+
+        Ok(account.map_or(false, |account| {
+            account.role == Role::Admin && !is_locked(account.status.as_deref())
+        }))
+    }
+
+    pub async fn list_users_runner(
+        query: &UserQuery,
+        data: &AppData,
+    ) -> ServiceResult<Vec<User>> {
+        // escape LIKE wildcards, the query is matched literally
+        let pattern = query.query.as_ref().map(|q| {
+            format!(
+                "%{}%",
+                q.replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            )
+        });
+        let limit = query.limit.unwrap_or(MAX_LIMIT).clamp(0, MAX_LIMIT);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        let users = data
+            .db
+            .users(pattern.as_deref(), limit, offset)
+            .await?
+            .into_iter()
+            .map(|rec| User {
+                id: rec.id,
+                name: rec.name,
+                email: rec.email,
+                email_verified: rec.email_verified,
+                role: rec.role,
+                locked: is_locked(rec.status.as_deref()),
+                login_blocked: rec.login_blocked,
+                quota: rec.quota,
+                bandwidth: rec.bandwidth,
+            })
+            .collect();
+
+        Ok(users)
+    }
+
+    /// creates an account, independent of the registration settings
+    pub async fn create_user_runner(
+        payload: &CreateUser,
+        data: &AppData,
+    ) -> ServiceResult<i32> {
+        let register = Register {
+            username: payload.username.clone(),
+            password: payload.password.clone(),
+            confirm_password: payload.password.clone(),
+            email: payload.email.clone(),
+            invite: None,
+        };
+        let role = payload.role.unwrap_or(Role::User);
+        register_account(&register, role, None, data).await
+    }
+
+    /// ID of the user with the given name
+    pub async fn user_id_runner(name: &str, data: &AppData) -> ServiceResult<i32> {
+        data.db
+            .user_id(name)
+            .await?
+            .ok_or(ServiceError::AccountNotFound)
+    }
+
+    /// deletes an account with all stored files
+    pub async fn delete_user_runner(user_id: i32, data: &AppData) -> ServiceResult<()> {
+        remove_user(user_id, data).await
+    }
+
+    pub async fn set_role_runner(
+        payload: &SetRole,
+        data: &AppData,
+    ) -> ServiceResult<()> {
+        if !data.db.set_role(payload.id, payload.role).await? {
+            return Err(ServiceError::AccountNotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn set_locked_runner(
+        user_id: i32,
+        locked: bool,
+        data: &AppData,
+    ) -> ServiceResult<()> {
+        let found = if locked {
+            data.db.lock_user(user_id).await?
+        } else {
+            data.db.unlock_user(user_id).await?
+        };
+        if !found {
+            return Err(ServiceError::AccountNotFound);
+        }
+        if !locked {
+            // also lift temporary lockouts after failed sign in attempts
+            crate::providers::lockout::reset(user_id, data).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn reset_password_runner(
+        payload: &ResetPassword,
+        data: &AppData,
+    ) -> ServiceResult<()> {
+        let hash = data.creds.password(&payload.password)?;
+        if !data.db.set_password(payload.id, &hash).await? {
+            return Err(ServiceError::AccountNotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn set_quota_runner(payload: &Quota, data: &AppData) -> ServiceResult<()> {
+        if matches!(payload.quota, Some(quota) if quota < 0) {
+            return Err(ServiceError::BadRequest);
+        }
+
+        if !data.db.set_quota(payload.id, payload.quota).await? {
+            return Err(ServiceError::AccountNotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn set_bandwidth_runner(
+        payload: &Bandwidth,
+        data: &AppData,
+    ) -> ServiceResult<()> {
+        if matches!(payload.bandwidth, Some(bandwidth) if bandwidth <= 0) {
+            return Err(ServiceError::BadRequest);
+        }
+
+        if !data.db.set_bandwidth(payload.id, payload.bandwidth).await? {
+            return Err(ServiceError::AccountNotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn usage_runner(user_id: i32, data: &AppData) -> ServiceResult<Usage> {
+        let limits = data
+            .db
+            .limits(user_id)
+            .await?
+            .ok_or(ServiceError::AccountNotFound)?;
+
+        Ok(Usage {
+            id: user_id,
+            used: storage::usage(user_id).await?,
+            quota: limits.quota,
+        })
+    }
+}
+
+use runners::*;
+
+/// list and search users
+#[my_codegen::get(
+    path = "crate::V1_API_ROUTES.admin.users",
+    wrap = "crate::RequireAdmin"
+)]
+async fn list_users(
+    web::Query(query): web::Query<UserQuery>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let users = list_users_runner(&query, &data).await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// create an account, also when registration is disabled
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.create_user",
+    wrap = "crate::RequireAdmin"
+)]
+async fn create_user(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<CreateUser>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = create_user_runner(&payload, &data).await;
+    Record::new(Event::UserCreate)
+        .user(admin_id)
+        .detail(&payload.username)
+        .save(&req, &data, &res)
+        .await;
+    let id = res?;
+    Ok(HttpResponse::Ok().json(UserId { id }))
+}
+
+/// delete an account with all stored files
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.delete_user",
+    wrap = "crate::RequireAdmin"
+)]
+async fn delete_user(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<UserId>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = delete_user_runner(payload.id, &data).await;
+    Record::new(Event::AccountDelete)
+        .user(admin_id)
+        .detail(format!("user {}", payload.id))
+        .save(&req, &data, &res)
+        .await;
+    res?;
+    Ok(HttpResponse::Ok())
+}
+
+/// promote a user to admin or demote an admin
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.set_role",
+    wrap = "crate::RequireAdmin"
+)]
+async fn set_role(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<SetRole>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = set_role_runner(&payload, &data).await;
+    Record::new(Event::RoleUpdate)
+        .user(admin_id)
+        .detail(format!("user {}", payload.id))
+        .save(&req, &data, &res)
+        .await;
+    res?;
+    Ok(HttpResponse::Ok())
+}
+
+/// prevent a user from signing in
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.lock_user",
+    wrap = "crate::RequireAdmin"
+)]
+async fn lock_user(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<UserId>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = set_locked_runner(payload.id, true, &data).await;
+    Record::new(Event::UserLock)
+        .user(admin_id)
+        .detail(format!("user {}", payload.id))
+        .save(&req, &data, &res)
+        .await;
+    res?;
+    Ok(HttpResponse::Ok())
+}
+
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.unlock_user",
+    wrap = "crate::RequireAdmin"
+)]
+async fn unlock_user(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<UserId>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = set_locked_runner(payload.id, false, &data).await;
+    Record::new(Event::UserUnlock)
+        .user(admin_id)
+        .detail(format!("user {}", payload.id))
+        .save(&req, &data, &res)
+        .await;
+    res?;
+    Ok(HttpResponse::Ok())
+}
+
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.reset_password",
+    wrap = "crate::RequireAdmin"
+)]
+async fn reset_password(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<ResetPassword>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = reset_password_runner(&payload, &data).await;
+    Record::new(Event::PasswordReset)
+        .user(admin_id)
+        .detail(format!("user {}", payload.id))
+        .save(&req, &data, &res)
+        .await;
+    res?;
+    Ok(HttpResponse::Ok())
+}
+
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.set_quota",
+    wrap = "crate::RequireAdmin"
+)]
+async fn set_quota(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<Quota>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = set_quota_runner(&payload, &data).await;
+    Record::new(Event::QuotaUpdate)
+        .user(admin_id)
+        .detail(format!("user {}", payload.id))
+        .save(&req, &data, &res)
+        .await;
+    res?;
+    Ok(HttpResponse::Ok())
+}
+
+/// override the bandwidth limit of a user
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.admin.set_bandwidth",
+    wrap = "crate::RequireAdmin"
+)]
+async fn set_bandwidth(
+    req: HttpRequest,
+    id: Identity,
+    payload: web::Json<Bandwidth>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let admin_id = crate::middleware::auth::get_user_id(&id)?;
+
+    let res = set_bandwidth_runner(&payload, &data).await;
+    Record::new(Event::BandwidthUpdate)
+        .user(admin_id)
+        .detail(format!("user {}", payload.id))
+        .save(&req, &data, &res)
+        .await;
+    res?;
+    Ok(HttpResponse::Ok())
+}
+
+/// storage usage and quota of a user
+#[my_codegen::get(
+    path = "crate::V1_API_ROUTES.admin.usage",
+    wrap = "crate::RequireAdmin"
+)]
+async fn usage(
+    web::Query(query): web::Query<UserId>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let usage = usage_runner(query.id, &data).await?;
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
+    cfg.service(list_users);
+    cfg.service(create_user);
+    cfg.service(delete_user);
+    cfg.service(set_role);
+    cfg.service(lock_user);
+    cfg.service(unlock_user);
+    cfg.service(reset_password);
+    cfg.service(set_quota);
+    cfg.service(set_bandwidth);
+    cfg.service(usage);
+}
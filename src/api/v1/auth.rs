@@ -17,11 +17,13 @@
 
 use actix_identity::Identity;
 use actix_web::http::header;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 
+use crate::audit::{Event, Record};
 use crate::errors::*;
-use crate::middleware::rate_limit::get_rate_limit_middleware;
+use crate::metrics::METRICS;
+use crate::middleware::rate_limit::{Policy, RateLimit};
 use crate::AppData;
 
 pub mod routes {
@@ -46,9 +48,13 @@ pub mod routes {
 }
 
 pub mod runners {
-    use std::borrow::Cow;
-
     use super::*;
+    use crate::api::v1::account::invite::runners::{
+        claim_invite, redeem_invite, release_invite,
+    };
+    use crate::api::v1::admin::{Role, STATUS_UNVERIFIED};
+    use crate::config::{Registration, Server};
+    use crate::db::{violates_unique, NewUser};
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct Register {
@@ -56,6 +62,8 @@ pub mod runners {
         pub password: String,
         pub confirm_password: String,
         pub email: Option<String>,
+        /// invite code, required unless registration is open
+        pub invite: Option<String>,
     }
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -71,69 +79,96 @@ pub mod runners {
         pub password: String,
     }
 
-    /// returns Ok(()) when everything checks out and the user is authenticated. Erros otherwise
-    pub async fn login_runner(payload: Login, data: &AppData) -> ServiceResult<String> {
-        use argon2_creds::Config;
-        use sqlx::Error::RowNotFound;
+    /// returns the ID of the user when everything checks out and the user is authenticated. Erros otherwise
+    pub async fn login_runner(payload: Login, data: &AppData) -> ServiceResult<i32> {
+        crate::providers::authenticate(&payload.login, &payload.password, data).await
+    }
 
-        let verify = |stored: &str, received: &str| {
-            if Config::verify(stored, received)? {
-                Ok(())
-            } else {
-                Err(ServiceError::InvalidCredentials)
-            }
+    /// Reason a registration is accepted under the registration policy
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Admission {
+        Open,
+        /// the invite code still needs to be checked
+        Invite,
+        /// the email address needs to be verified before signing in
+        Domain,
+    }
+
+    /// checks a registration against the registration policy of `server`
+    pub fn check_registration(
+        server: &Server,
+        payload: &Register,
+    ) -> ServiceResult<Admission> {
+        let allowed_domain = || {
+            payload
+                .email
+                .as_ref()
+                .and_then(|email| email.rsplit_once('@'))
+                .map_or(false, |(_, domain)| {
+                    server
+                        .registration_domains
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+                })
         };
 
-        if payload.login.contains('@') {
-            #[derive(Clone, Debug)]
-            struct EmailLogin {
-                name: String,
-                password: String,
-            }
+        match server.registration {
+            Registration::Open => Ok(Admission::Open),
+            Registration::Closed => Err(ServiceError::ClosedForRegistration),
+            _ if payload.invite.is_some() => Ok(Admission::Invite),
+            Registration::Allowlist if allowed_domain() => Ok(Admission::Domain),
+            _ => Err(ServiceError::ClosedForRegistration),
+        }
+    }
 
-            match sqlx::query_as!(
-                EmailLogin,
-                r#"SELECT name, password  FROM triox_users WHERE email = ($1)"#,
-                &payload.login,
-            )
-            .fetch_one(&data.db)
-            .await
-            {
-                Ok(s) => {
-                    verify(&s.password, &payload.password)?;
-                    Ok(s.name)
+    /// registers a user through the sign up API, enforcing the registration policy of `server`
+    pub async fn register_with_policy(
+        server: &Server,
+        payload: &Register,
+        data: &AppData,
+    ) -> ServiceResult<i32> {
+        match check_registration(server, payload)? {
+            Admission::Open => register_runner(payload, data).await,
+            Admission::Invite => {
+                let code = payload.invite.as_deref().unwrap_or_default();
+                claim_invite(code, data).await?;
+                match register_runner(payload, data).await {
+                    Ok(user_id) => {
+                        redeem_invite(code, user_id, data).await?;
+                        Ok(user_id)
+                    }
+                    Err(e) => {
+                        release_invite(code, data).await?;
+                        Err(e)
+                    }
                 }
-
-                Err(RowNotFound) => Err(ServiceError::AccountNotFound),
-                Err(_) => Err(ServiceError::InternalServerError),
             }
-        } else {
-            match sqlx::query_as!(
-                Password,
-                r#"SELECT password  FROM triox_users WHERE name = ($1)"#,
-                &payload.login,
-            )
-            .fetch_one(&data.db)
-            .await
-            {
-                Ok(s) => {
-                    verify(&s.password, &payload.password)?;
-                    Ok(payload.login)
-                }
-                Err(RowNotFound) => Err(ServiceError::AccountNotFound),
-                Err(_) => Err(ServiceError::InternalServerError),
+            // the status is written along with the account, so it can't
+            // sign in before the address is verified
+            Admission::Domain => {
+                register_account(payload, Role::User, Some(STATUS_UNVERIFIED), data)
+                    .await
             }
         }
     }
 
+    /// creates the account and its storage directory, returns the ID of the new user.
+    /// Doesn't check the registration policy.
     pub async fn register_runner(
         payload: &Register,
         data: &AppData,
-    ) -> ServiceResult<()> {
-        //  if !crate::SETTINGS.server.allow_registration {
-        //      return Err(ServiceError::ClosedForRegistration);
-        //  }
+    ) -> ServiceResult<i32> {
+        register_account(payload, Role::User, None, data).await
+    }
 
+    /// like [register_runner], with the role and `triox_users.status` written
+    /// along with the account
+    pub async fn register_account(
+        payload: &Register,
+        role: Role,
+        status: Option<&str>,
+        data: &AppData,
+    ) -> ServiceResult<i32> {
         if payload.password != payload.confirm_password {
             return Err(ServiceError::PasswordsDontMatch);
         }
@@ -144,49 +179,45 @@ pub mod runners {
             data.creds.email(email)?;
         }
 
-        let res = if let Some(email) = &payload.email {
-            sqlx::query!(
-                "INSERT INTO triox_users 
-        (name , password, email) VALUES ($1, $2, $3)",
-                &username,
-                &hash,
-                &email,
-            )
-            .execute(&data.db)
-            .await
-        } else {
-            sqlx::query!(
-                "INSERT INTO triox_users 
-        (name , password) VALUES ($1, $2)",
-                &username,
-                &hash,
-            )
-            .execute(&data.db)
-            .await
+        let user = NewUser {
+            name: &username,
+            password: &hash,
+            email: payload.email.as_deref(),
+            email_verified: false,
+            role,
+            provider: crate::providers::local::NAME,
+            status,
         };
 
-        if let Err(sqlx::Error::Database(err)) = res {
-            log::error!("{}", err);
-            if err.code() == Some(Cow::from("23505")) {
-                let msg = err.message();
-                if msg.contains("triox_users_name_key") {
-                    return Err(ServiceError::UsernameTaken);
-                } else if msg.contains("triox_users_email_key") {
-                    return Err(ServiceError::EmailTaken);
-                } else {
-                    return Err(ServiceError::InternalServerError);
-                }
-            } else {
-                return Err(sqlx::Error::Database(err).into());
+        let user_id = match data.db.create_user(&user).await {
+            Ok(id) => id,
+            Err(err) if violates_unique(&err, "triox_users", "name") => {
+                return Err(ServiceError::UsernameTaken);
+            }
+            Err(err) if violates_unique(&err, "triox_users", "email") => {
+                return Err(ServiceError::EmailTaken);
+            }
+            Err(err) => {
+                log::error!("{}", err);
+                return Err(err.into());
             }
         };
+
         // generate storage path for user
-        let path: std::path::PathBuf =
-            [".", "data", "users", &username, "files"].iter().collect();
+        let path = crate::apps::files::storage::user_path(user_id).join("files");
 
         std::fs::create_dir_all(path)?;
 
-        Ok(())
+        if payload.email.is_some() {
+            if let Err(e) =
+                crate::api::v1::account::verify::send_verification_email(user_id, data)
+                    .await
+            {
+                log::warn!("Unable to send verification email: {}", e);
+            }
+        }
+
+        Ok(user_id)
     }
 }
 
@@ -198,33 +229,64 @@ pub fn services(cfg: &mut web::ServiceConfig) {
 
 #[my_codegen::post(
     path = "crate::V1_API_ROUTES.auth.register",
-    wrap = "get_rate_limit_middleware()"
+    wrap = "RateLimit::new(Policy::Auth)"
 )]
 async fn register(
+    req: HttpRequest,
     payload: web::Json<runners::Register>,
     data: AppData,
 ) -> ServiceResult<impl Responder> {
-    runners::register_runner(&payload, &data).await?;
+    let settings = data.settings();
+    let res = runners::register_with_policy(&settings.server, &payload, &data).await;
+
+    let mut record = Record::new(Event::Register).detail(&payload.username);
+    if let Ok(user_id) = res {
+        record = record.user(user_id);
+    }
+    record.save(&req, &data, &res).await;
+
+    res?;
     Ok(HttpResponse::Ok())
 }
 
 #[my_codegen::post(
     path = "crate::V1_API_ROUTES.auth.login",
-    wrap = "get_rate_limit_middleware()"
+    wrap = "RateLimit::new(Policy::Auth)"
 )]
 async fn login(
+    req: HttpRequest,
     id: Identity,
     payload: web::Json<runners::Login>,
     data: AppData,
 ) -> ServiceResult<impl Responder> {
-    let username = runners::login_runner(payload.into_inner(), &data).await?;
-    id.remember(username);
+    let login = payload.login.clone();
+    let res = runners::login_runner(payload.into_inner(), &data).await;
+
+    // failed attempts are recorded with the login of the request
+    let mut record = Record::new(Event::Login).detail(login);
+    match res {
+        Ok(user_id) => record = record.user(user_id),
+        Err(_) => METRICS.login_failed(),
+    }
+    record.save(&req, &data, &res).await;
+
+    let user_id = res?;
+    id.remember(user_id.to_string());
     Ok(HttpResponse::Ok())
 }
 
-#[my_codegen::get(path = "crate::V1_API_ROUTES.auth.logout", wrap = "crate::CheckLogin")]
-async fn signout(id: Identity) -> impl Responder {
-    if id.identity().is_some() {
+#[my_codegen::post(
+    path = "crate::V1_API_ROUTES.auth.logout",
+    wrap = "crate::CheckLogin"
+)]
+async fn signout(req: HttpRequest, id: Identity, data: AppData) -> impl Responder {
+    if let Some(identity) = id.identity() {
+        let mut record = Record::new(Event::Logout);
+        if let Some(user_id) = crate::middleware::auth::parse_user_id(&identity) {
+            record = record.user(user_id);
+        }
+        let res: ServiceResult<()> = Ok(());
+        record.save(&req, &data, &res).await;
         id.forget();
     }
     HttpResponse::Found()
@@ -14,13 +14,20 @@
 * You should have received a copy of the GNU Affero General Public License
 * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use actix_web::http::StatusCode;
 use actix_web::{web, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
-use sqlx::Connection;
+use tokio::fs;
+use tokio::sync::Mutex;
 use tokio::time::timeout;
 
+use crate::apps::files::storage;
+use crate::migrate;
 use crate::AppData;
 use crate::{GIT_COMMIT_HASH, VERSION};
 
@@ -34,6 +41,8 @@ pub mod routes {
     pub struct Meta {
         pub build_details: &'static str,
         pub health: &'static str,
+        pub ready: &'static str,
+        pub live: &'static str,
     }
 
     impl Meta {
@@ -41,6 +50,8 @@ pub mod routes {
             Self {
                 build_details: "/api/v1/meta/build",
                 health: "/api/v1/meta/health",
+                ready: "/api/v1/meta/ready",
+                live: "/api/v1/meta/live",
             }
         }
     }
@@ -57,33 +68,233 @@ async fn build_details() -> impl Responder {
     HttpResponse::Ok().json(BUILD_DETAILS)
 }
 
+/// Overall state of the health checks
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// An optional component like the mailer is unavailable
+    Degraded,
+    /// A required component is unavailable, the server isn't ready
+    Unavailable,
+}
+
+/// Result of a single check
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Check {
+    pub healthy: bool,
+    /// Failures of optional checks don't affect readiness
+    pub required: bool,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 /// Health check return datatype
 pub struct Health {
-    db: bool,
+    pub status: Status,
+    pub db: bool,
+    pub checks: BTreeMap<String, Check>,
 }
 
-/// checks all components of the system
-#[my_codegen::get(path = "crate::V1_API_ROUTES.meta.health")]
-async fn health(data: AppData) -> impl Responder {
-    let mut db = false;
-
-    if let Ok(mut con) = data.db.acquire().await {
-        if timeout(Duration::from_millis(100), con.ping())
-            .await
-            .is_ok()
-        {
-            db = true;
+#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Liveness check return datatype
+pub struct Liveness {
+    pub status: Status,
+}
+
+/// Time a report is reused, the probes are unauthenticated and
+/// shouldn't let anyone put load on the server
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Recent reports of the health checks
+#[derive(Default)]
+pub struct HealthCache {
+    full: Mutex<Option<(Instant, Health)>>,
+    ready: Mutex<Option<(Instant, Health)>>,
+}
+
+impl HealthCache {
+    /// Runs the checks unless a recent report exists, concurrent requests
+    /// wait for the same run. `mailer` includes the optional mailer check.
+    pub async fn get(&self, data: &AppData, mailer: bool) -> Health {
+        let slot = if mailer { &self.full } else { &self.ready };
+        let mut slot = slot.lock().await;
+        match &*slot {
+            Some((checked, health)) if checked.elapsed() < CACHE_TTL => health.clone(),
+            _ => {
+                let health = check_health(data, mailer).await;
+                *slot = Some((Instant::now(), health.clone()));
+                health
+            }
+        }
+    }
+}
+
+/// runs a check with the configured timeout
+async fn check<F>(limit: Duration, required: bool, fut: F) -> Check
+where
+    F: Future<Output = Result<(), String>>,
+{
+    let start = Instant::now();
+    let res = match timeout(limit, fut).await {
+        Ok(res) => res,
+        Err(_) => Err("timed out".into()),
+    };
+
+    Check {
+        healthy: res.is_ok(),
+        required,
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        error: res.err(),
+    }
+}
+
+async fn check_db(data: &AppData) -> Result<(), String> {
+    data.db.ping().await.map_err(|e| e.to_string())
+}
+
+/// all migrations of this version have to be applied
+async fn check_migrations(data: &AppData) -> Result<(), String> {
+    let status = migrate::status(&*data.db)
+        .await
+        .map_err(|e| e.to_string())?;
+    if let Some(version) = status.dirty {
+        Err(format!("migration {} is partially applied", version))
+    } else if !status.pending.is_empty() {
+        Err(format!("{} pending migrations", status.pending.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// creates and removes a file in the storage directory
+async fn check_storage() -> Result<(), String> {
+    let dir = storage::users_path();
+    fs::create_dir_all(&dir).await.map_err(|e| e.to_string())?;
+
+    let file = dir.join(format!(".health-{}", rand::random::<u64>()));
+    fs::write(&file, b"ok").await.map_err(|e| e.to_string())?;
+    fs::remove_file(&file).await.map_err(|e| e.to_string())
+}
+
+async fn check_disk(min_free_space: u64) -> Result<(), String> {
+    let dir = storage::users_path();
+    fs::create_dir_all(&dir).await.map_err(|e| e.to_string())?;
+
+    let free = free_space(&dir).map_err(|e| e.to_string())?;
+    if free >= min_free_space {
+        Ok(())
+    } else {
+        Err(format!("{} bytes free, {} required", free, min_free_space))
+    }
+}
+
+/// space in bytes available to unprivileged users
+#[cfg(unix)]
+// field types of statvfs differ between platforms
+#[allow(clippy::unnecessary_cast)]
+fn free_space(path: &Path) -> std::io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "free space can't be determined on this platform",
+    ))
+}
+
+/// runs all checks concurrently, `mailer` includes the optional mailer check
+pub async fn check_health(data: &AppData, mailer: bool) -> Health {
+    let settings = data.settings();
+    let limit = Duration::from_millis(settings.health.timeout);
+    let mailer = async {
+        match &data.mailer {
+            Some(smtp) if mailer => {
+                Some(check(limit, false, smtp.test_connection()).await)
+            }
+            _ => None,
         }
     };
+    let (db, migrations, storage, disk, mailer) = futures::join!(
+        check(limit, true, check_db(data)),
+        check(limit, true, check_migrations(data)),
+        check(limit, true, check_storage()),
+        check(limit, true, check_disk(settings.health.min_free_space)),
+        mailer,
+    );
+
+    let mut checks = BTreeMap::new();
+    if let Some(mailer) = mailer {
+        checks.insert("mailer".into(), mailer);
+    }
+
+    let db_healthy = db.healthy;
+    checks.insert("database".into(), db);
+    checks.insert("migrations".into(), migrations);
+    checks.insert("storage".into(), storage);
+    checks.insert("disk".into(), disk);
+
+    let status = if checks.values().any(|c| c.required && !c.healthy) {
+        Status::Unavailable
+    } else if checks.values().any(|c| !c.healthy) {
+        Status::Degraded
+    } else {
+        Status::Ok
+    };
 
-    let resp = Health { db };
-    HttpResponse::Ok().json(&resp)
+    Health {
+        status,
+        db: db_healthy,
+        checks,
+    }
+}
+
+async fn health_response(data: &AppData, mailer: bool) -> HttpResponse {
+    let health = data.health.get(data, mailer).await;
+    let status = match health.status {
+        Status::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Status::Ok | Status::Degraded => StatusCode::OK,
+    };
+    HttpResponse::build(status).json(&health)
+}
+
+/// checks all components of the system, responds with 503 if a
+/// required component is unavailable
+#[my_codegen::get(path = "crate::V1_API_ROUTES.meta.health")]
+async fn health(data: AppData) -> impl Responder {
+    health_response(&data, true).await
+}
+
+/// readiness probe, same as [health] without the optional mailer check
+#[my_codegen::get(path = "crate::V1_API_ROUTES.meta.ready")]
+async fn ready(data: AppData) -> impl Responder {
+    health_response(&data, false).await
+}
+
+/// liveness probe, succeeds as long as the server handles requests
+#[my_codegen::get(path = "crate::V1_API_ROUTES.meta.live")]
+async fn live() -> impl Responder {
+    HttpResponse::Ok().json(Liveness { status: Status::Ok })
 }
 
 pub fn services(cfg: &mut web::ServiceConfig) {
     cfg.service(build_details);
     cfg.service(health);
+    cfg.service(ready);
+    cfg.service(live);
 }
 
 #[cfg(test)]
@@ -110,8 +321,7 @@ mod tests {
 
     #[actix_rt::test]
     async fn health_works() {
-        println!("{}", V1_API_ROUTES.meta.health);
-        let data = crate::app_state::AppState::new().await;
+        let data = crate::tests::app_state().await;
         let app = get_app!(data).await;
 
         let resp = test::call_service(
@@ -125,5 +335,55 @@ mod tests {
 
         let health_resp: Health = test::read_body_json(resp).await;
         assert!(health_resp.db);
+        assert_eq!(health_resp.status, Status::Ok);
+        for name in ["database", "migrations", "storage", "disk"] {
+            let check = &health_resp.checks[name];
+            assert!(check.healthy, "{}: {:?}", name, check.error);
+            assert!(check.required);
+        }
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(V1_API_ROUTES.meta.ready)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(V1_API_ROUTES.meta.live)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let live_resp: Liveness = test::read_body_json(resp).await;
+        assert_eq!(live_resp.status, Status::Ok);
+    }
+
+    #[actix_rt::test]
+    async fn health_cache_works() {
+        let data = crate::tests::app_state().await;
+        let data = actix_web::web::Data::new(data);
+
+        // reports are reused for a while
+        let first = data.health.get(&data, true).await;
+        let second = data.health.get(&data, true).await;
+        assert_eq!(
+            first.checks["database"].latency_ms,
+            second.checks["database"].latency_ms
+        );
+
+        // readiness doesn't depend on the reports with the mailer
+        let ready = data.health.get(&data, false).await;
+        assert!(!ready.checks.contains_key("mailer"));
+    }
+
+    #[test]
+    fn free_space_works() {
+        assert!(free_space(Path::new(".")).unwrap() > 0);
+        assert!(free_space(Path::new("./does-not-exist")).is_err());
     }
 }
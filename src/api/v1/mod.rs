@@ -15,23 +15,28 @@
 * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-// TODO setup governor
 pub mod account;
+pub mod admin;
 pub mod auth;
 pub mod meta;
+pub mod oidc;
 #[cfg(test)]
 mod tests;
 
 use account::routes::Account;
+use admin::routes::Admin;
 use auth::routes::Auth;
 use meta::routes::Meta;
+use oidc::routes::Oidc;
 
 pub const ROUTES: Routes = Routes::new();
 
 pub struct Routes {
     pub auth: Auth,
     pub account: Account,
+    pub admin: Admin,
     pub meta: Meta,
+    pub oidc: Oidc,
 }
 
 impl Routes {
@@ -39,7 +44,9 @@ impl Routes {
         Routes {
             auth: Auth::new(),
             account: Account::new(),
+            admin: Admin::new(),
             meta: Meta::new(),
+            oidc: Oidc::new(),
         }
     }
 }
@@ -47,5 +54,7 @@ impl Routes {
 pub fn services(cfg: &mut actix_web::web::ServiceConfig) {
     auth::services(cfg);
     account::services(cfg);
+    admin::services(cfg);
     meta::services(cfg);
+    oidc::services(cfg);
 }
@@ -0,0 +1,284 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use actix_identity::Identity;
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{Event, Record};
+use crate::errors::*;
+use crate::providers::oidc::{PendingLogin, LOGIN_LIFETIME};
+use crate::AppData;
+
+/// Cookie holding the [PendingLogin] during the sign in at the identity provider
+pub const LOGIN_COOKIE: &str = "triox-oidc";
+
+pub mod routes {
+    pub struct Oidc {
+        pub providers: &'static str,
+        pub login: &'static str,
+        pub callback: &'static str,
+    }
+
+    impl Oidc {
+        pub const fn new() -> Oidc {
+            let providers = "/api/v1/oidc/providers";
+            let login = "/api/v1/oidc/login";
+            let callback = "/api/v1/oidc/callback";
+            Oidc {
+                providers,
+                login,
+                callback,
+            }
+        }
+    }
+}
+
+pub mod runners {
+    use super::*;
+    use crate::api::v1::admin::Role;
+    use crate::config::{AppConfig, Oidc};
+    use crate::providers::oidc::{self, Claims};
+    use crate::providers::{check_status, create_account};
+
+    /// Value of `triox_users.provider` for accounts created through OpenID Connect
+    pub const NAME: &str = "oidc";
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Provider {
+        pub name: String,
+        pub display_name: String,
+        /// starts the sign in
+        pub login: String,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Callback {
+        pub code: Option<String>,
+        pub state: Option<String>,
+        pub error: Option<String>,
+    }
+
+    pub fn provider<'a>(settings: &'a AppConfig, name: &str) -> ServiceResult<&'a Oidc> {
+        settings
+            .oidc
+            .iter()
+            .find(|provider| provider.name == name)
+            .ok_or(ServiceError::BadRequest)
+    }
+
+    pub fn redirect_uri(settings: &AppConfig) -> String {
+        format!(
+            "{}{}",
+            settings.base_url(),
+            crate::V1_API_ROUTES.oidc.callback
+        )
+    }
+
+    /// returns the URL of the identity provider the user is sent to
+    pub async fn start_login(
+        config: &Oidc,
+        redirect_uri: &str,
+    ) -> ServiceResult<(String, PendingLogin)> {
+        let discovery = oidc::discover(config).await?;
+        let login = PendingLogin::new(&config.name);
+        let url = oidc::authorization_url(config, &discovery, &login, redirect_uri)?;
+        Ok((url, login))
+    }
+
+    /// returns the ID of the user that signed in at the identity provider
+    pub async fn finish_login(
+        config: &Oidc,
+        login: &PendingLogin,
+        callback: &Callback,
+        redirect_uri: &str,
+        data: &AppData,
+    ) -> ServiceResult<i32> {
+        if let Some(error) = &callback.error {
+            log::info!("Sign in at {} failed: {}", config.name, error);
+            return Err(ServiceError::InvalidCredentials);
+        }
+
+        let (code, state) = match (&callback.code, &callback.state) {
+            (Some(code), Some(state)) => (code, state),
+            _ => return Err(ServiceError::BadRequest),
+        };
+        if login.provider != config.name || state != &login.state {
+            return Err(ServiceError::InvalidToken);
+        }
+
+        let discovery = oidc::discover(config).await?;
+        let claims =
+            oidc::exchange_code(config, &discovery, login, code, redirect_uri).await?;
+        let user_id = find_or_create_user(config, &claims, data).await?;
+
+        let account = data
+            .db
+            .account(user_id)
+            .await?
+            .ok_or(ServiceError::AccountNotFound)?;
+        check_status(account.status.as_deref())?;
+
+        Ok(user_id)
+    }
+
+    async fn find_or_create_user(
+        config: &Oidc,
+        claims: &Claims,
+        data: &AppData,
+    ) -> ServiceResult<i32> {
+        let subject = claims.subject().ok_or(ServiceError::InvalidToken)?;
+
+        if let Some(user_id) = data.db.oidc_user(&config.name, subject).await? {
+            return Ok(user_id);
+        }
+
+        let email = claims.verified_email();
+
+        // link accounts with the same verified email address
+        let existing = match email {
+            Some(email) => data.db.user_id_by_verified_email(email).await?,
+            None => None,
+        };
+
+        let user_id = match existing {
+            Some(id) => id,
+            None if config.provision => {
+                let claim = claims
+                    .get(&config.username_claim)
+                    .or_else(|| email.and_then(|email| email.split('@').next()))
+                    .ok_or(ServiceError::InvalidToken)?;
+                // the name is used in storage paths, so it has to pass the
+                // same checks as names of local accounts
+                let username = data.creds.username(claim).map_err(|e| {
+                    log::warn!("{} user {} has no valid username", config.name, claim);
+                    ServiceError::from(e)
+                })?;
+                if data.db.name_exists(&username).await? {
+                    log::warn!(
+                        "{} user {} has the same name as an existing account",
+                        config.name,
+                        username
+                    );
+                    return Err(ServiceError::UsernameTaken);
+                }
+                create_account(&username, email, Role::User, NAME, data).await?
+            }
+            None => return Err(ServiceError::AccountNotFound),
+        };
+
+        data.db
+            .link_oidc_user(&config.name, subject, user_id)
+            .await?;
+
+        Ok(user_id)
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(providers);
+    cfg.service(login);
+    cfg.service(callback);
+}
+
+/// identity providers for the sign in page
+#[my_codegen::get(path = "crate::V1_API_ROUTES.oidc.providers")]
+async fn providers(data: AppData) -> impl Responder {
+    let providers: Vec<runners::Provider> = data
+        .settings()
+        .oidc
+        .iter()
+        .map(|provider| runners::Provider {
+            name: provider.name.clone(),
+            display_name: provider.display_name.clone(),
+            login: format!(
+                "{}?provider={}",
+                crate::V1_API_ROUTES.oidc.login,
+                provider.name
+            ),
+        })
+        .collect();
+    HttpResponse::Ok().json(providers)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LoginQuery {
+    pub provider: String,
+}
+
+/// redirects to the identity provider
+#[my_codegen::get(path = "crate::V1_API_ROUTES.oidc.login")]
+async fn login(
+    web::Query(query): web::Query<LoginQuery>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let settings = data.settings();
+    let config = runners::provider(&settings, &query.provider)?;
+    let (url, login) =
+        runners::start_login(config, &runners::redirect_uri(&settings)).await?;
+
+    // Lax, the callback is a cross-site navigation
+    let cookie = Cookie::build(LOGIN_COOKIE, login.encode(&settings.server.secret))
+        .path(crate::V1_API_ROUTES.oidc.callback)
+        .http_only(true)
+        .secure(settings.https())
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(LOGIN_LIFETIME as i64))
+        .finish();
+
+    Ok(HttpResponse::Found()
+        .cookie(cookie)
+        .append_header((header::LOCATION, url))
+        .finish())
+}
+
+/// target of the redirect from the identity provider
+#[my_codegen::get(path = "crate::V1_API_ROUTES.oidc.callback")]
+async fn callback(
+    req: HttpRequest,
+    id: Identity,
+    web::Query(query): web::Query<runners::Callback>,
+    data: AppData,
+) -> ServiceResult<impl Responder> {
+    let cookie = req.cookie(LOGIN_COOKIE).ok_or(ServiceError::InvalidToken)?;
+    let settings = data.settings();
+    let login = PendingLogin::decode(&settings.server.secret, cookie.value())?;
+    let config = runners::provider(&settings, &login.provider)?;
+
+    let redirect_uri = runners::redirect_uri(&settings);
+    let res = runners::finish_login(config, &login, &query, &redirect_uri, &data).await;
+
+    let mut record =
+        Record::new(Event::Login).detail(format!("oidc:{}", login.provider));
+    if let Ok(user_id) = res {
+        record = record.user(user_id);
+    }
+    record.save(&req, &data, &res).await;
+
+    let user_id = res?;
+    id.remember(user_id.to_string());
+
+    let mut removal = Cookie::named(LOGIN_COOKIE);
+    removal.set_path(crate::V1_API_ROUTES.oidc.callback);
+    let mut res = HttpResponse::Found()
+        .append_header((header::LOCATION, "/"))
+        .finish();
+    res.add_removal_cookie(&removal)
+        .map_err(|_| ServiceError::InternalServerError)?;
+    Ok(res)
+}
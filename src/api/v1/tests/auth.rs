@@ -18,9 +18,12 @@
 use actix_web::http::{header, StatusCode};
 use actix_web::test;
 
-use crate::api::v1::auth::runners::{Login, Register};
+use crate::api::v1::account::invite::Invite;
+use crate::api::v1::admin::runners::{list_users_runner, set_locked_runner, UserQuery};
+use crate::api::v1::admin::Role;
+use crate::api::v1::auth::runners::*;
 use crate::api::v1::ROUTES;
-use crate::app_state::AppState as Data;
+use crate::config::Registration;
 use crate::errors::*;
 use crate::*;
 
@@ -28,7 +31,7 @@ use crate::tests::*;
 
 #[actix_rt::test]
 async fn auth_works() {
-    let data = Data::new().await;
+    let data = app_state().await;
     const NAME: &str = "testuser";
     const PASSWORD: &str = "longpassword";
     const EMAIL: &str = "testuser1@a.com";
@@ -43,6 +46,7 @@ async fn auth_works() {
         password: PASSWORD.into(),
         confirm_password: PASSWORD.into(),
         email: None,
+        invite: None,
     };
     let resp =
         test::call_service(&app, post_request!(&msg, ROUTES.auth.register).to_request())
@@ -56,7 +60,23 @@ async fn auth_works() {
         register_and_signin(NAME, Some(EMAIL.into()), PASSWORD).await;
     let cookies = get_cookie!(signin_resp);
 
-    // Sign in with email
+    // Sign in with unverified email
+    let creds = Login {
+        login: EMAIL.into(),
+        password: PASSWORD.into(),
+    };
+    bad_post_req_test(
+        NAME,
+        PASSWORD,
+        ROUTES.auth.login,
+        &creds,
+        ServiceError::EmailNotVerified,
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+
+    // Sign in with verified email
+    verify_email(NAME, &data).await;
     signin(EMAIL, PASSWORD).await;
 
     // 2. check if duplicate username is allowed
@@ -65,6 +85,7 @@ async fn auth_works() {
         password: PASSWORD.into(),
         confirm_password: PASSWORD.into(),
         email: Some(EMAIL.into()),
+        invite: None,
     };
     bad_post_req_test(
         NAME,
@@ -108,7 +129,7 @@ async fn auth_works() {
     // 5. signout
     let signout_resp = test::call_service(
         &app,
-        test::TestRequest::get()
+        test::TestRequest::post()
             .uri(ROUTES.auth.logout)
             .cookie(cookies)
             .to_request(),
@@ -128,7 +149,7 @@ async fn serverside_password_validation_works() {
     const NAME: &str = "testuser542";
     const PASSWORD: &str = "longpassword2";
 
-    let data = Data::new().await;
+    let data = app_state().await;
     delete_user(NAME, &data).await;
 
     let app = get_app!(data).await;
@@ -140,6 +161,7 @@ async fn serverside_password_validation_works() {
         password: PASSWORD.into(),
         confirm_password: NAME.into(),
         email: None,
+        invite: None,
     };
     let resp = test::call_service(
         &app,
@@ -150,3 +172,231 @@ async fn serverside_password_validation_works() {
     let txt: ErrorToResponse = test::read_body_json(resp).await;
     assert_eq!(txt.error, format!("{}", ServiceError::PasswordsDontMatch));
 }
+
+#[test]
+fn registration_policy_works() {
+    let mut server = settings().server;
+    server.registration_domains = vec!["example.com".into()];
+
+    let mut msg = Register {
+        username: "testuserpolicy".into(),
+        password: "longpassword".into(),
+        confirm_password: "longpassword".into(),
+        email: Some("testuserpolicy@Example.com".into()),
+        invite: None,
+    };
+
+    server.registration = Registration::Open;
+    assert_eq!(check_registration(&server, &msg), Ok(Admission::Open));
+
+    server.registration = Registration::Closed;
+    assert_eq!(
+        check_registration(&server, &msg),
+        Err(ServiceError::ClosedForRegistration)
+    );
+
+    server.registration = Registration::Allowlist;
+    assert_eq!(check_registration(&server, &msg), Ok(Admission::Domain));
+
+    server.registration = Registration::Invite;
+    assert_eq!(
+        check_registration(&server, &msg),
+        Err(ServiceError::ClosedForRegistration)
+    );
+
+    msg.invite = Some("code".into());
+    assert_eq!(check_registration(&server, &msg), Ok(Admission::Invite));
+
+    server.registration = Registration::Closed;
+    assert_eq!(
+        check_registration(&server, &msg),
+        Err(ServiceError::ClosedForRegistration)
+    );
+
+    msg.invite = None;
+    msg.email = Some("testuserpolicy@example.org".into());
+    server.registration = Registration::Allowlist;
+    assert_eq!(
+        check_registration(&server, &msg),
+        Err(ServiceError::ClosedForRegistration)
+    );
+}
+
+#[actix_rt::test]
+async fn invite_registration_works() {
+    const NAME: &str = "testuserinviter";
+    const INVITED: &str = "testuserinvited";
+    const PASSWORD: &str = "longpassword";
+
+    let data = app_state().await;
+    delete_user(NAME, &data).await;
+    delete_user(INVITED, &data).await;
+
+    let (_, _, signin_resp) = register_and_signin(NAME, None, PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+    let app = get_app!(data).await;
+
+    // regular users have no invites by default
+    bad_post_req_test(
+        NAME,
+        PASSWORD,
+        ROUTES.account.create_invite,
+        &(),
+        ServiceError::InviteLimitReached,
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+
+    let id = user_id(NAME, &data).await;
+    data.db.set_role(id, Role::Admin).await.unwrap();
+
+    let resp = test::call_service(
+        &app,
+        post_request!(ROUTES.account.create_invite)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let invite: Invite = test::read_body_json(resp).await;
+
+    let mut server = settings().server;
+    server.registration = Registration::Invite;
+    let data = actix_web::web::Data::new(data);
+
+    let mut msg = Register {
+        username: INVITED.into(),
+        password: PASSWORD.into(),
+        confirm_password: PASSWORD.into(),
+        email: None,
+        invite: Some("invalid".into()),
+    };
+    assert_eq!(
+        register_with_policy(&server, &msg, &data).await,
+        Err(ServiceError::InvalidInvite)
+    );
+
+    // failed registrations don't use up the invite
+    msg.invite = Some(invite.code.clone());
+    msg.confirm_password = NAME.into();
+    assert_eq!(
+        register_with_policy(&server, &msg, &data).await,
+        Err(ServiceError::PasswordsDontMatch)
+    );
+
+    msg.confirm_password = PASSWORD.into();
+    let id = register_with_policy(&server, &msg, &data).await.unwrap();
+    assert_eq!(id, user_id(INVITED, &data).await);
+    signin(INVITED, PASSWORD).await;
+
+    // invites can only be used once
+    msg.username = NAME.into();
+    assert_eq!(
+        register_with_policy(&server, &msg, &data).await,
+        Err(ServiceError::InvalidInvite)
+    );
+
+    let resp = test::call_service(
+        &app,
+        get_req!(ROUTES.account.invites)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let invites: Vec<Invite> = test::read_body_json(resp).await;
+    assert_eq!(invites.len(), 1);
+    assert_eq!(invites[0].code, invite.code);
+    assert!(invites[0].used);
+
+    delete_user(INVITED, &data).await;
+    delete_user(NAME, &data).await;
+}
+
+#[actix_rt::test]
+async fn parallel_login_attempts_are_limited() {
+    const NAME: &str = "testuserlockoutrace";
+    const PASSWORD: &str = "longpassword";
+
+    let mut config = settings();
+    config.lockout.max_failures = 2;
+    let data = actix_web::web::Data::new(app_state_with(config).await);
+    delete_user(NAME, &data).await;
+    register(NAME, None, PASSWORD).await;
+
+    let login = Login {
+        login: NAME.into(),
+        password: NAME.into(),
+    };
+    let attempts = (0..6).map(|_| login_runner(login.clone(), &data));
+    let results = futures::future::join_all(attempts).await;
+
+    let failed = results
+        .iter()
+        .filter(|res| **res == Err(ServiceError::InvalidCredentials))
+        .count();
+    assert!((1..=2).contains(&failed));
+    assert!(results
+        .iter()
+        .all(|res| *res == Err(ServiceError::InvalidCredentials)
+            || *res == Err(ServiceError::TooManyLoginAttempts)));
+
+    delete_user(NAME, &data).await;
+}
+
+#[actix_rt::test]
+async fn account_lockout_works() {
+    const NAME: &str = "testuserlockout";
+    const PASSWORD: &str = "longpassword";
+
+    let data = actix_web::web::Data::new(app_state().await);
+    delete_user(NAME, &data).await;
+    register(NAME, None, PASSWORD).await;
+    let id = user_id(NAME, &data).await;
+
+    let login = |password: &str| Login {
+        login: NAME.into(),
+        password: password.into(),
+    };
+
+    // every failure blocks the account for a while, even for the right password
+    assert_eq!(
+        login_runner(login(NAME), &data).await,
+        Err(ServiceError::InvalidCredentials)
+    );
+    assert_eq!(
+        login_runner(login(PASSWORD), &data).await,
+        Err(ServiceError::TooManyLoginAttempts)
+    );
+
+    // too many failures lock the account
+    let mut config = settings();
+    config.lockout.max_failures = 2;
+    let strict = actix_web::web::Data::new(app_state_with(config).await);
+    data.db.block_login(id, 0, false).await.unwrap();
+    assert_eq!(
+        login_runner(login(NAME), &strict).await,
+        Err(ServiceError::InvalidCredentials)
+    );
+    let query = UserQuery {
+        query: Some(NAME.into()),
+        limit: None,
+        offset: None,
+    };
+    let users = list_users_runner(&query, &strict).await.unwrap();
+    assert!(users[0].login_blocked);
+    assert_eq!(
+        login_runner(login(PASSWORD), &strict).await,
+        Err(ServiceError::TooManyLoginAttempts)
+    );
+    // the counter starts over once the account is locked
+    data.db.block_login(id, 0, false).await.unwrap();
+    assert_eq!(data.db.add_failed_login(NAME).await.unwrap(), Some((id, 1)));
+    data.db.remove_failed_login(id).await.unwrap();
+
+    // admins can lift the lock
+    set_locked_runner(id, false, &data).await.unwrap();
+    assert_eq!(login_runner(login(PASSWORD), &data).await, Ok(id));
+
+    delete_user(NAME, &data).await;
+}
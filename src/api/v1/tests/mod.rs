@@ -16,4 +16,5 @@
 */
 
 mod auth;
+mod oidc;
 mod protected;
@@ -0,0 +1,275 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::api::v1::oidc::runners::*;
+use crate::config::Oidc;
+use crate::errors::*;
+use crate::providers::oidc::PendingLogin;
+use crate::tokens::now;
+
+use crate::tests::*;
+
+const CLIENT_ID: &str = "triox";
+const REDIRECT_URI: &str = "http://localhost/api/v1/oidc/callback";
+
+/// identity provider that issues a prepared ID token
+struct MockIssuer {
+    issuer: String,
+    challenge: String,
+    claims: Value,
+    key: PKey<Private>,
+    /// sign tokens with a key that isn't published
+    forge: bool,
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+async fn discovery(issuer: web::Data<Mutex<MockIssuer>>) -> HttpResponse {
+    let issuer = &issuer.lock().unwrap().issuer;
+    HttpResponse::Ok().json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+    }))
+}
+
+async fn jwks(issuer: web::Data<Mutex<MockIssuer>>) -> HttpResponse {
+    let rsa = issuer.lock().unwrap().key.rsa().unwrap();
+    HttpResponse::Ok().json(json!({
+        "keys": [{
+            "kty": "RSA",
+            "kid": "mock",
+            "use": "sig",
+            "n": b64(&rsa.n().to_vec()),
+            "e": b64(&rsa.e().to_vec()),
+        }]
+    }))
+}
+
+async fn token(
+    issuer: web::Data<Mutex<MockIssuer>>,
+    form: web::Form<HashMap<String, String>>,
+) -> HttpResponse {
+    let issuer = issuer.lock().unwrap();
+
+    // PKCE
+    let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+    let challenge = base64::encode_config(
+        Sha256::digest(verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+    if challenge != issuer.challenge || form.get("client_id").unwrap() != CLIENT_ID {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let header = json!({"alg": "RS256", "kid": "mock"}).to_string();
+    let claims = issuer.claims.to_string();
+    let input = format!("{}.{}", b64(header.as_bytes()), b64(claims.as_bytes()));
+    let key = if issuer.forge {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    } else {
+        issuer.key.clone()
+    };
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+    signer.update(input.as_bytes()).unwrap();
+    let id_token = format!("{}.{}", input, b64(&signer.sign_to_vec().unwrap()));
+    HttpResponse::Ok().json(json!({ "id_token": id_token, "token_type": "Bearer" }))
+}
+
+fn start_issuer() -> (web::Data<Mutex<MockIssuer>>, Oidc) {
+    let state = web::Data::new(Mutex::new(MockIssuer {
+        issuer: String::new(),
+        challenge: String::new(),
+        claims: Value::Null,
+        key: PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+        forge: false,
+    }));
+
+    let app_state = state.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .route(
+                "/.well-known/openid-configuration",
+                web::get().to(discovery),
+            )
+            .route("/token", web::post().to(token))
+            .route("/jwks", web::get().to(jwks))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+
+    let issuer = format!("http://{}", server.addrs()[0]);
+    state.lock().unwrap().issuer = issuer.clone();
+    actix_rt::spawn(server.run());
+
+    let config = Oidc {
+        name: "mock".into(),
+        display_name: "Mock".into(),
+        issuer,
+        client_id: CLIENT_ID.into(),
+        client_secret: None,
+        scopes: vec!["openid".into(), "email".into()],
+        username_claim: "preferred_username".into(),
+        provision: true,
+    };
+    (state, config)
+}
+
+/// starts a sign in and prepares the ID token the issuer will return
+async fn start(
+    issuer: &web::Data<Mutex<MockIssuer>>,
+    config: &Oidc,
+    claims: Value,
+) -> PendingLogin {
+    let (url, login) = start_login(config, REDIRECT_URI).await.unwrap();
+
+    let url = Url::parse(&url).unwrap();
+    let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    assert_eq!(query["code_challenge_method"], "S256");
+    assert_eq!(query["state"], login.state);
+    assert_eq!(query["redirect_uri"], REDIRECT_URI);
+
+    let mut issuer = issuer.lock().unwrap();
+    let mut claims = claims;
+    claims["iss"] = json!(issuer.issuer);
+    claims["aud"] = json!(CLIENT_ID);
+    claims["exp"] = json!(now() + 60);
+    claims["nonce"] = json!(query["nonce"]);
+    issuer.challenge = query["code_challenge"].clone();
+    issuer.claims = claims;
+    login
+}
+
+fn callback(login: &PendingLogin) -> Callback {
+    Callback {
+        code: Some("code".into()),
+        state: Some(login.state.clone()),
+        error: None,
+    }
+}
+
+#[actix_rt::test]
+async fn oidc_login_works() {
+    const NAME: &str = "testoidcuser";
+    const LINKED: &str = "testoidclinked";
+    const LINKED_EMAIL: &str = "testoidclinked@example.com";
+    const PASSWORD: &str = "longpassword";
+
+    let data = web::Data::new(app_state().await);
+    delete_user(NAME, &data).await;
+    delete_user(LINKED, &data).await;
+
+    let (issuer, config) = start_issuer();
+
+    // just-in-time provisioning
+    let claims = json!({"sub": "subject1", "preferred_username": NAME});
+    let login = start(&issuer, &config, claims.clone()).await;
+
+    let mut wrong_state = callback(&login);
+    wrong_state.state = Some("wrong".into());
+    assert_eq!(
+        finish_login(&config, &login, &wrong_state, REDIRECT_URI, &data).await,
+        Err(ServiceError::InvalidToken)
+    );
+
+    let id = finish_login(&config, &login, &callback(&login), REDIRECT_URI, &data)
+        .await
+        .unwrap();
+    assert_eq!(id, user_id(NAME, &data).await);
+    assert!(crate::apps::files::storage::user_path(id).exists());
+
+    // the identity stays linked
+    let login = start(&issuer, &config, claims.clone()).await;
+    assert_eq!(
+        finish_login(&config, &login, &callback(&login), REDIRECT_URI, &data).await,
+        Ok(id)
+    );
+
+    // tokens for another sign in are rejected
+    let login = start(&issuer, &config, claims).await;
+    let mut other = login.clone();
+    other.nonce = "other".into();
+    assert_eq!(
+        finish_login(&config, &other, &callback(&login), REDIRECT_URI, &data).await,
+        Err(ServiceError::InvalidToken)
+    );
+
+    // tokens need a signature of the provider
+    let login = start(&issuer, &config, json!({"sub": "subject1"})).await;
+    issuer.lock().unwrap().forge = true;
+    assert_eq!(
+        finish_login(&config, &login, &callback(&login), REDIRECT_URI, &data).await,
+        Err(ServiceError::InvalidToken)
+    );
+    issuer.lock().unwrap().forge = false;
+
+    // names of new accounts are validated like at registration
+    let login = start(
+        &issuer,
+        &config,
+        json!({"sub": "subject3", "preferred_username": "test oidc user"}),
+    )
+    .await;
+    assert!(matches!(
+        finish_login(&config, &login, &callback(&login), REDIRECT_URI, &data).await,
+        Err(ServiceError::CredentialError(_))
+    ));
+    let login = start(
+        &issuer,
+        &config,
+        json!({"sub": "subject4", "preferred_username": NAME}),
+    )
+    .await;
+    assert_eq!(
+        finish_login(&config, &login, &callback(&login), REDIRECT_URI, &data).await,
+        Err(ServiceError::UsernameTaken)
+    );
+
+    // existing accounts are linked by their verified email address
+    register(LINKED, Some(LINKED_EMAIL.into()), PASSWORD).await;
+    verify_email(LINKED, &data).await;
+    let claims = json!({
+        "sub": "subject2",
+        "preferred_username": "someoneelse",
+        "email": LINKED_EMAIL,
+        "email_verified": true,
+    });
+    let login = start(&issuer, &config, claims).await;
+    assert_eq!(
+        finish_login(&config, &login, &callback(&login), REDIRECT_URI, &data).await,
+        Ok(user_id(LINKED, &data).await)
+    );
+
+    delete_user(NAME, &data).await;
+    delete_user(LINKED, &data).await;
+}
@@ -15,10 +15,9 @@
 * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use actix_web::http::StatusCode;
+use actix_web::http::{header, StatusCode};
 use actix_web::test;
 
-use crate::app_state::AppState as Data;
 use crate::*;
 
 use crate::tests::*;
@@ -30,10 +29,8 @@ async fn protected_routes_work() {
 
     let _post_protected_urls = ["/api/v1/account/email/", "/api/v1/account/delete"];
 
-    let get_protected_urls = ["/logout"];
-
     {
-        let data = Data::new().await;
+        let data = app_state().await;
         delete_user(NAME, &data).await;
     }
 
@@ -41,25 +38,23 @@ async fn protected_routes_work() {
     let cookies = get_cookie!(signin_resp);
     let app = get_app!(data).await;
 
-    for url in get_protected_urls.iter() {
-        let resp =
-            test::call_service(&app, test::TestRequest::get().uri(url).to_request())
-                .await;
-        assert_eq!(resp.status(), StatusCode::FOUND);
-
-        let authenticated_resp = test::call_service(
-            &app,
-            test::TestRequest::get()
-                .uri(url)
-                .cookie(cookies.clone())
-                .to_request(),
-        )
-        .await;
-
-        if url == &V1_API_ROUTES.auth.logout {
-            assert_eq!(authenticated_resp.status(), StatusCode::FOUND);
-        } else {
-            assert_eq!(authenticated_resp.status(), StatusCode::OK);
-        }
-    }
+    // signing out changes the session, so it's a POST behind the CSRF check
+    let url = V1_API_ROUTES.auth.logout;
+    let resp =
+        test::call_service(&app, test::TestRequest::post().uri(url).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+
+    let authenticated_resp = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri(url)
+            .cookie(cookies)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(authenticated_resp.status(), StatusCode::FOUND);
+    assert_eq!(
+        authenticated_resp.headers().get(header::LOCATION).unwrap(),
+        crate::middleware::auth::SIGIN_PAGE
+    );
 }
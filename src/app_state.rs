@@ -1,21 +1,39 @@
 use std::sync::Arc;
 
-use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
-
-use crate::SETTINGS;
+use crate::api::v1::meta::HealthCache;
+use crate::apps::files::throttle::Buckets;
+use crate::config::{AppConfig, LiveConfig, Reload};
+use crate::db::Database;
+use crate::mailer::Mailer;
+use crate::middleware::rate_limit::Limiters;
+use crate::providers::AuthProvider;
+use crate::tls::Certificates;
 
 /// Storing the state of the application
 /// Can be accessed using the AppData extractor.
 #[derive(Clone)]
 pub struct AppState {
     pub creds: argon2_creds::Config,
-    // sqlx
-    pub db: PgPool,
+    /// Postgres or SQLite, depending on `[database]`
+    pub db: Arc<dyn Database>,
+    /// Queue for outgoing emails, `None` if `[smtp]` isn't configured
+    pub mailer: Option<Mailer>,
+    /// Settings that can be reloaded while running, see [AppState::settings]
+    pub live_settings: Arc<LiveConfig>,
+    /// Authentication providers in the order they are tried
+    pub providers: Arc<Vec<Box<dyn AuthProvider>>>,
+    pub limiters: Arc<Limiters>,
+    /// Bandwidth limits of file transfers
+    pub bandwidth: Arc<Buckets>,
+    /// Certificate of the HTTPS server and pending ACME challenges
+    pub tls: Arc<Certificates>,
+    /// Recent results of the health checks
+    pub health: Arc<HealthCache>,
 }
 
 impl AppState {
-    pub async fn new() -> Arc<Self> {
+    /// `config_dir` is read again when the settings are reloaded
+    pub async fn new(config_dir: &str, settings: AppConfig) -> Arc<Self> {
         let creds = argon2_creds::ConfigBuilder::default()
             .username_case_mapped(true)
             .profanity(true)
@@ -33,13 +51,76 @@ impl AppState {
             log::info!("Initialized credential manager");
         });
 
-        let db = PgPoolOptions::new()
-            .max_connections(SETTINGS.database.pool)
-            .connect(&SETTINGS.database.url())
+        let db = crate::db::connect(&settings.database)
             .await
             .expect("Unable to form database pool");
+
+        let mailer = settings
+            .smtp
+            .as_ref()
+            .map(|smtp| Mailer::new(smtp).expect("Unable to initialize mailer"));
+
+        let providers = Arc::new(crate::providers::configured(&settings));
+        let limiters = Arc::new(Limiters::new(&settings));
+        let bandwidth = Arc::new(Buckets::new(&settings));
+        let live_settings = Arc::new(LiveConfig::new(config_dir, settings));
+
         #[cfg(not(debug_assertions))]
         init.join().unwrap();
-        Arc::new(AppState { creds, db })
+        Arc::new(AppState {
+            creds,
+            db,
+            mailer,
+            live_settings,
+            providers,
+            limiters,
+            bandwidth,
+            tls: Arc::new(Certificates::default()),
+            health: Arc::new(HealthCache::default()),
+        })
+    }
+
+    /// Current configuration
+    pub fn settings(&self) -> Arc<AppConfig> {
+        self.live_settings.get()
+    }
+
+    /// Reloads the configuration from disk and rebuilds the rate limiters
+    /// and bandwidth limits from it. The TLS certificate is loaded again
+    /// from its files.
+    pub fn reload_settings(&self) -> Reload {
+        let (settings, mut reload) = self.live_settings.reload();
+        if !reload.problems.is_empty() {
+            for problem in reload.problems.iter() {
+                log::error!("Configuration not reloaded: {}", problem);
+            }
+            return reload;
+        }
+
+        self.limiters.configure(&settings);
+        self.bandwidth.configure(&settings);
+        if settings.tls.enabled {
+            match self.tls.load(&settings.tls) {
+                Ok(true) => reload.applied.push("tls.certificate".into()),
+                Ok(false) => (),
+                Err(e) => log::error!("TLS certificate not reloaded: {}", e),
+            }
+        }
+
+        if reload.applied.is_empty() {
+            log::info!("Configuration reloaded without changes");
+        } else {
+            log::info!(
+                "Configuration reloaded, applied changes to {}",
+                reload.applied.join(", ")
+            );
+        }
+        if !reload.restart_required.is_empty() {
+            log::warn!(
+                "Changes to {} take effect after a restart",
+                reload.restart_required.join(", ")
+            );
+        }
+        reload
     }
 }
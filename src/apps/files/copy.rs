@@ -1,22 +1,48 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
+use crate::audit::{Event, Record};
 use crate::errors::*;
+use crate::AppData;
 
 /// Service for deleting files or directories
 #[my_codegen::post(path = "crate::FILE_ROUTES.copy", wrap = "crate::CheckLogin")]
 pub async fn copy(
+    req: HttpRequest,
     id: actix_identity::Identity,
     payload: web::Json<super::SourceAndDest>,
+    data: AppData,
 ) -> ServiceResult<HttpResponse> {
-    super::read_only_guard()?;
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
 
-    let username = id.identity().unwrap();
+    let res = copy_path(user_id, &payload, &data).await;
+    Record::new(Event::Copy)
+        .user(user_id)
+        .path(&payload.from)
+        .detail(&payload.to)
+        .save(&req, &data, &res)
+        .await;
+    res
+}
+
+async fn copy_path(
+    user_id: i32,
+    payload: &super::SourceAndDest,
+    data: &AppData,
+) -> ServiceResult<HttpResponse> {
+    super::read_only_guard(data)?;
 
-    let source_path = super::resolve_path(&username, &payload.from)?;
-    let destination_path = super::resolve_path(&username, &payload.to)?;
+    let source_path = super::resolve_path(user_id, &payload.from)?;
+    let destination_path = super::resolve_path(user_id, &payload.to)?;
 
     let metadata = tokio::fs::metadata(&source_path).await?;
 
+    if let Some(remaining) = super::remaining_quota(user_id, data).await? {
+        // the metadata of directories doesn't include their contents
+        if super::storage::size(&source_path).await? > remaining {
+            return Err(ServiceError::QuotaExceeded);
+        }
+    }
+
     tokio::fs::copy(&source_path, &destination_path).await?;
 
     if metadata.is_dir() {
@@ -1,18 +1,36 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
+use crate::audit::{Event, Record};
 use crate::errors::*;
+use crate::AppData;
 
 /// Service for creating directories
-#[my_codegen::get(path = "crate::FILE_ROUTES.create_dir", wrap = "crate::CheckLogin")]
+#[my_codegen::post(path = "crate::FILE_ROUTES.create_dir", wrap = "crate::CheckLogin")]
 pub async fn create_dir(
-    web::Query(query_path): web::Query<super::QueryPath>,
+    req: HttpRequest,
+    web::Json(query_path): web::Json<super::QueryPath>,
     id: actix_identity::Identity,
+    data: AppData,
 ) -> ServiceResult<HttpResponse> {
-    super::read_only_guard()?;
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
 
-    let username = id.identity().unwrap();
+    let res = create(user_id, &query_path.path, &data).await;
+    Record::new(Event::CreateDir)
+        .user(user_id)
+        .path(&query_path.path)
+        .save(&req, &data, &res)
+        .await;
+    res
+}
+
+async fn create(
+    user_id: i32,
+    path: &str,
+    data: &AppData,
+) -> ServiceResult<HttpResponse> {
+    super::read_only_guard(data)?;
 
-    let full_path = super::resolve_path(&username, &query_path.path)?;
+    let full_path = super::resolve_path(user_id, path)?;
 
     tokio::fs::create_dir_all(&full_path).await?;
 
@@ -1,15 +1,34 @@
 use actix_files::NamedFile;
-use actix_web::web;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
+use super::throttle::Throttle;
 use crate::errors::*;
+use crate::metrics::METRICS;
+use crate::middleware::rate_limit::{Policy, RateLimit};
+use crate::AppData;
 
 /// Service for downloading files via an API
-#[my_codegen::get(path = "crate::FILE_ROUTES.get", wrap = "crate::CheckLogin")]
+#[my_codegen::get(
+    path = "crate::FILE_ROUTES.get",
+    wrap = "crate::CheckLogin",
+    wrap = "RateLimit::new(Policy::Downloads)"
+)]
 pub async fn get(
+    req: HttpRequest,
     id: actix_identity::Identity,
     web::Query(query_path): web::Query<super::QueryPath>,
-) -> ServiceResult<NamedFile> {
-    let username = id.identity().unwrap();
-    let full_path = super::resolve_path(&username, &query_path.path)?;
-    Ok(NamedFile::open(&full_path)?)
+    data: AppData,
+) -> ServiceResult<HttpResponse> {
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
+    let full_path = super::resolve_path(user_id, &query_path.path)?;
+    let file = NamedFile::open(&full_path)?;
+
+    let throttle = Throttle::for_user(user_id, &data).await?;
+    let res = file.respond_to(&req);
+    // size of the requested range for partial downloads
+    if let BodySize::Sized(size) = res.body().size() {
+        METRICS.add_downloaded(size);
+    }
+    Ok(throttle.response(res))
 }
@@ -36,9 +36,9 @@ pub async fn list(
     id: actix_identity::Identity,
     web::Query(query_path): web::Query<QueryPath>,
 ) -> ServiceResult<HttpResponse> {
-    let username = id.identity().unwrap();
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
 
-    let full_path = super::resolve_path(&username, &query_path.path)?;
+    let full_path = super::resolve_path(user_id, &query_path.path)?;
 
     let dir = ReadDirStream::new(fs::read_dir(&full_path).await?);
 
@@ -2,6 +2,7 @@ use actix_web::web;
 use serde::{Deserialize, Serialize};
 
 use crate::errors::*;
+use crate::AppData;
 
 /// Copy files and directories
 pub mod copy;
@@ -16,13 +17,17 @@ pub mod list;
 pub mod mv;
 /// Delete files and directories
 pub mod remove;
+/// Storage layout of user data
+pub mod storage;
+/// Bandwidth limits of file transfers
+pub mod throttle;
 /// Upload files to the server
 pub mod upload;
 
 pub const FILE_ROUTES: routes::Files = routes::Files::new();
 
 /// Shared struct for extracting paths
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct QueryPath {
     pub path: String,
 }
@@ -35,20 +40,44 @@ pub struct SourceAndDest {
 }
 
 /// Helper function to translate paths from requests into absolute path
-fn resolve_path(username: &str, query_path: &str) -> ServiceResult<std::path::PathBuf> {
+fn resolve_path(user_id: i32, query_path: &str) -> ServiceResult<std::path::PathBuf> {
     if query_path.contains("..") {
         Err(ServiceError::PermissionDenied)
     } else {
         Ok(std::path::PathBuf::from(format!(
             "data/users/{}/files/{}",
-            username, query_path
+            user_id, query_path
         )))
     }
 }
 
+/// Helper function to get the storage quota of a user in bytes,
+/// `None` if the user has no quota
+async fn quota(user_id: i32, data: &AppData) -> ServiceResult<Option<u64>> {
+    let limits = data
+        .db
+        .limits(user_id)
+        .await?
+        .ok_or(ServiceError::AccountNotFound)?;
+
+    Ok(limits.quota.map(|quota| quota.max(0) as u64))
+}
+
+/// Helper function to get the remaining storage quota of a user in bytes,
+/// `None` if the user has no quota
+async fn remaining_quota(user_id: i32, data: &AppData) -> ServiceResult<Option<u64>> {
+    match quota(user_id, data).await? {
+        Some(quota) => {
+            let used = storage::usage(user_id).await?;
+            Ok(Some(quota.saturating_sub(used)))
+        }
+        None => Ok(None),
+    }
+}
+
 /// Helper function to
-fn read_only_guard() -> ServiceResult<()> {
-    if crate::SETTINGS.files.read_only {
+fn read_only_guard(data: &AppData) -> ServiceResult<()> {
+    if data.settings().files.read_only {
         Err(ServiceError::FSReadOnly)
     } else {
         Ok(())
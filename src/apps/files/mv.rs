@@ -1,19 +1,38 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
+use crate::audit::{Event, Record};
 use crate::errors::*;
+use crate::AppData;
 
 /// Service for deleting files or directories
 #[my_codegen::post(path = "crate::FILE_ROUTES.mv", wrap = "crate::CheckLogin")]
 pub async fn mv(
+    req: HttpRequest,
     id: actix_identity::Identity,
     params: web::Json<super::SourceAndDest>,
+    data: AppData,
 ) -> ServiceResult<HttpResponse> {
-    super::read_only_guard()?;
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
 
-    let username = id.identity().unwrap();
+    let res = move_path(user_id, &params, &data).await;
+    Record::new(Event::Move)
+        .user(user_id)
+        .path(&params.from)
+        .detail(&params.to)
+        .save(&req, &data, &res)
+        .await;
+    res
+}
+
+async fn move_path(
+    user_id: i32,
+    params: &super::SourceAndDest,
+    data: &AppData,
+) -> ServiceResult<HttpResponse> {
+    super::read_only_guard(data)?;
 
-    let source_path = super::resolve_path(&username, &params.from)?;
-    let destination_path = super::resolve_path(&username, &params.to)?;
+    let source_path = super::resolve_path(user_id, &params.from)?;
+    let destination_path = super::resolve_path(user_id, &params.to)?;
 
     let metadata = tokio::fs::metadata(&source_path).await?;
 
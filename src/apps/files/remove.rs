@@ -1,18 +1,36 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
+use crate::audit::{Event, Record};
 use crate::errors::*;
+use crate::AppData;
 
 /// Service for deleting files or directories
-#[my_codegen::get(path = "crate::FILE_ROUTES.remove", wrap = "crate::CheckLogin")]
+#[my_codegen::delete(path = "crate::FILE_ROUTES.remove", wrap = "crate::CheckLogin")]
 pub async fn remove(
+    req: HttpRequest,
     id: actix_identity::Identity,
     web::Query(query_path): web::Query<super::QueryPath>,
+    data: AppData,
 ) -> ServiceResult<HttpResponse> {
-    super::read_only_guard()?;
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
 
-    let username = id.identity().unwrap();
+    let res = remove_path(user_id, &query_path.path, &data).await;
+    Record::new(Event::Remove)
+        .user(user_id)
+        .path(&query_path.path)
+        .save(&req, &data, &res)
+        .await;
+    res
+}
+
+async fn remove_path(
+    user_id: i32,
+    path: &str,
+    data: &AppData,
+) -> ServiceResult<HttpResponse> {
+    super::read_only_guard(data)?;
 
-    let full_path = super::resolve_path(&username, &query_path.path)?;
+    let full_path = super::resolve_path(user_id, path)?;
 
     let metadata = tokio::fs::metadata(&full_path).await?;
 
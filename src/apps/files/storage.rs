@@ -0,0 +1,108 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::db::Database;
+
+/// Directory that contains the storage directories of all users.
+pub fn users_path() -> PathBuf {
+    [".", "data", "users"].iter().collect()
+}
+
+/// Storage directory of a user.
+///
+/// Directories are named after the immutable `triox_users.id`,
+/// so renaming a user doesn't affect the storage.
+pub fn user_path(user_id: i32) -> PathBuf {
+    users_path().join(user_id.to_string())
+}
+
+/// Unfinished uploads of a user. They count against the quota, but aren't
+/// listed with the files.
+pub fn uploads_path(user_id: i32) -> PathBuf {
+    user_path(user_id).join("uploads")
+}
+
+/// Moves storage directories from the old layout (`data/users/{name}`)
+/// to the ID based layout (`data/users/{id}`).
+///
+/// Usernames that equal the ID of an existing user are ambiguous and skipped,
+/// so running the migration more than once is harmless.
+/// Returns the number of migrated directories.
+pub async fn migrate_to_id_layout(db: &dyn Database) -> std::io::Result<usize> {
+    let users = db
+        .user_ids()
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+    let ids: HashSet<String> = users.iter().map(|(id, _)| id.to_string()).collect();
+    let users_dir = users_path();
+
+    let mut migrated = 0;
+    for (id, name) in users.iter() {
+        let old_path = users_dir.join(name);
+        if !old_path.is_dir() {
+            continue;
+        }
+
+        if ids.contains(name) {
+            log::warn!(
+                "Skipping {:?}: the name of user {} equals a user ID, move it manually",
+                old_path,
+                id
+            );
+            continue;
+        }
+
+        let new_path = user_path(*id);
+        if new_path.exists() {
+            log::error!("Skipping {:?}: {:?} already exists", old_path, new_path);
+            continue;
+        }
+
+        tokio::fs::rename(&old_path, &new_path).await?;
+        log::info!("Moved {:?} to {:?}", old_path, new_path);
+        migrated += 1;
+    }
+
+    Ok(migrated)
+}
+
+/// Total size of all files stored by a user in bytes.
+pub async fn usage(user_id: i32) -> std::io::Result<u64> {
+    size(&user_path(user_id)).await
+}
+
+/// Size of a file, or the total size of all files in a directory in bytes.
+pub async fn size(path: &Path) -> std::io::Result<u64> {
+    let metadata = tokio::fs::metadata(path).await?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut total = 0;
+    let mut dirs = vec![path.to_path_buf()];
+
+    // files of parallel uploads may disappear while they are counted
+    let gone = |e: &std::io::Error| e.kind() == std::io::ErrorKind::NotFound;
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if gone(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(e) if gone(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                total += metadata.len();
+            }
+        }
+    }
+
+    Ok(total)
+}
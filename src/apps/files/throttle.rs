@@ -0,0 +1,204 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BodySize, BodyStream, BoxBody, MessageBody, SizedStream};
+use actix_web::HttpResponse;
+use dashmap::DashMap;
+use futures::{stream, StreamExt};
+
+use crate::config::{AppConfig, BandwidthLimit};
+use crate::errors::*;
+use crate::AppData;
+
+/// Buckets of a server, shared by all transfers
+#[derive(Default)]
+pub struct Buckets {
+    /// bucket of `bandwidth.global`
+    global: RwLock<Option<Arc<Bucket>>>,
+    users: DashMap<i32, Arc<Bucket>>,
+}
+
+impl Buckets {
+    pub fn new(settings: &AppConfig) -> Self {
+        let buckets = Buckets::default();
+        buckets.configure(settings);
+        buckets
+    }
+
+    /// Applies `bandwidth.global`, the bucket is kept if the limit didn't change.
+    /// Buckets of users are replaced when their next transfer starts.
+    pub fn configure(&self, settings: &AppConfig) {
+        let mut global = self.global.write().unwrap();
+        let unchanged =
+            global.as_ref().map(|bucket| bucket.limit) == settings.bandwidth.global;
+        if !unchanged {
+            *global = settings
+                .bandwidth
+                .global
+                .map(|limit| Arc::new(Bucket::new(limit)));
+        }
+    }
+}
+
+/// Token bucket measured in bytes
+///
+/// Transfers may take more bytes than available, following transfers
+/// wait until the debt is paid off.
+pub struct Bucket {
+    limit: BandwidthLimit,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Bucket {
+            limit,
+            state: Mutex::new((limit.burst as f64, Instant::now())),
+        }
+    }
+
+    /// Takes `bytes` from the bucket, returns how long the transfer has to wait
+    pub fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let rate = self.limit.rate as f64;
+        let mut state = self.state.lock().unwrap();
+        let (tokens, updated) = *state;
+
+        let refilled =
+            tokens + now.saturating_duration_since(updated).as_secs_f64() * rate;
+        let tokens = refilled.min(self.limit.burst as f64) - bytes as f64;
+        *state = (tokens, now.max(updated));
+
+        if tokens < 0.0 {
+            Duration::from_secs_f64(-tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Bandwidth limits of a single transfer
+#[derive(Clone, Default)]
+pub struct Throttle {
+    buckets: Vec<Arc<Bucket>>,
+}
+
+impl Throttle {
+    /// Applies the global limit and the limit of the user, which is either
+    /// `bandwidth.user` or the override set by an admin
+    pub async fn for_user(user_id: i32, data: &AppData) -> ServiceResult<Self> {
+        let limits = data
+            .db
+            .limits(user_id)
+            .await?
+            .ok_or(ServiceError::AccountNotFound)?;
+
+        let default = data.settings().bandwidth.user;
+        let limit = match limits.bandwidth {
+            Some(rate) => Some(BandwidthLimit {
+                rate: rate.max(1) as u64,
+                burst: default.map_or(rate.max(1) as u64, |limit| limit.burst),
+            }),
+            None => default,
+        };
+
+        let shared = &data.bandwidth;
+        let mut buckets: Vec<Arc<Bucket>> =
+            shared.global.read().unwrap().iter().cloned().collect();
+        match limit {
+            Some(limit) => {
+                let mut bucket = shared
+                    .users
+                    .entry(user_id)
+                    .or_insert_with(|| Arc::new(Bucket::new(limit)));
+                // the limit was changed by an admin
+                if bucket.limit != limit {
+                    *bucket = Arc::new(Bucket::new(limit));
+                }
+                buckets.push(bucket.clone());
+            }
+            None => {
+                shared.users.remove(&user_id);
+            }
+        }
+
+        Ok(Throttle { buckets })
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Waits until `bytes` may be transferred
+    pub async fn consume(&self, bytes: usize) {
+        let now = Instant::now();
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.reserve(bytes, now))
+            .max()
+            .unwrap_or(Duration::ZERO);
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Throttles the body of a response, keeps the content length
+    pub fn response(self, res: HttpResponse) -> HttpResponse {
+        if self.is_unlimited() {
+            return res;
+        }
+
+        res.map_body(|_, body| {
+            let size = body.size();
+            let chunks = stream::unfold(body, |mut body| async move {
+                let chunk = futures::future::poll_fn(|cx| {
+                    std::pin::Pin::new(&mut body).poll_next(cx)
+                })
+                .await?;
+                Some((chunk, body))
+            })
+            .then(move |chunk| {
+                let throttle = self.clone();
+                async move {
+                    if let Ok(bytes) = &chunk {
+                        throttle.consume(bytes.len()).await;
+                    }
+                    chunk
+                }
+            });
+
+            match size {
+                BodySize::Sized(size) => BoxBody::new(SizedStream::new(size, chunks)),
+                _ => BoxBody::new(BodyStream::new(chunks)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_works() {
+        let bucket = Bucket::new(BandwidthLimit {
+            rate: 1000,
+            burst: 2000,
+        });
+        let now = Instant::now();
+
+        // burst
+        assert_eq!(bucket.reserve(2000, now), Duration::ZERO);
+        // debt is paid off at the configured rate
+        assert_eq!(bucket.reserve(500, now), Duration::from_millis(500));
+        assert_eq!(
+            bucket.reserve(500, now + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+        // the bucket doesn't refill beyond the burst size
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.reserve(2000, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000, later), Duration::from_secs(1));
+    }
+}
@@ -1,13 +1,21 @@
-use actix_multipart::Multipart;
+use std::path::Path;
+
+use actix_multipart::{Field, Multipart};
 use futures::{StreamExt, TryStreamExt};
 //use std::future::Future;
 
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
+use super::storage;
+use super::throttle::Throttle;
+use crate::audit::{Event, Record};
 use crate::errors::*;
+use crate::metrics::METRICS;
+use crate::middleware::rate_limit::{Policy, RateLimit};
+use crate::AppData;
 
 #[derive(serde::Serialize)]
 struct Response {
@@ -17,17 +25,49 @@ struct Response {
 }
 
 /// Service for listing files
-#[my_codegen::post(path = "crate::FILE_ROUTES.upload", wrap = "crate::CheckLogin")]
+#[my_codegen::post(
+    path = "crate::FILE_ROUTES.upload",
+    wrap = "crate::CheckLogin",
+    wrap = "RateLimit::new(Policy::Uploads)"
+)]
 pub async fn upload(
+    req: HttpRequest,
     id: actix_identity::Identity,
     web::Query(query_path): web::Query<super::QueryPath>,
-    mut payload: Multipart,
+    payload: Multipart,
+    data: AppData,
 ) -> ServiceResult<impl Responder> {
-    super::read_only_guard()?;
+    let user_id = crate::middleware::auth::get_user_id(&id)?;
 
-    let username = id.identity().unwrap();
+    let mut filenames = Vec::new();
+    let res =
+        upload_files(user_id, &query_path.path, payload, &mut filenames, &data).await;
+    Record::new(Event::Upload)
+        .user(user_id)
+        .path(&query_path.path)
+        .detail(filenames.join(", "))
+        .save(&req, &data, &res)
+        .await;
+    res
+}
+
+/// Writes the files of a multipart upload, collects their names in `filenames`
+async fn upload_files(
+    user_id: i32,
+    path: &str,
+    mut payload: Multipart,
+    filenames: &mut Vec<String>,
+    data: &AppData,
+) -> ServiceResult<HttpResponse> {
+    super::read_only_guard(data)?;
 
-    let base_path = super::resolve_path(&username, &query_path.path)?;
+    let base_path = super::resolve_path(user_id, path)?;
+
+    let quota = super::quota(user_id, data).await?;
+    let throttle = Throttle::for_user(user_id, data).await?;
+
+    let uploads_path = storage::uploads_path(user_id);
+    fs::create_dir_all(&uploads_path).await?;
 
     loop {
         match payload.try_next().await {
@@ -40,14 +80,31 @@ pub async fn upload(
 
                     let mut file_path = base_path.clone();
                     file_path.push(filename);
-                    println!("uploading file: {} at {:?}", filename, file_path);
+                    filenames.push(filename.to_owned());
 
-                    let mut file = fs::File::create(file_path).await?;
+                    // an overwritten file frees its space once the upload is complete
+                    let replaced = match fs::metadata(&file_path).await {
+                        Ok(metadata) if metadata.is_file() => metadata.len(),
+                        _ => 0,
+                    };
 
-                    // Field in turn is stream of *Bytes* object
-                    while let Some(chunk) = field.next().await {
-                        file.write_all(&chunk?).await?;
+                    // files are moved into place when they are complete, so
+                    // failed uploads don't leave partial or truncated files behind
+                    let upload_path =
+                        uploads_path.join(format!("{:016x}", rand::random::<u64>()));
+                    let res = write_file(
+                        user_id,
+                        &mut field,
+                        &upload_path,
+                        quota.map(|quota| quota + replaced),
+                        &throttle,
+                    )
+                    .await;
+                    if let Err(e) = res {
+                        let _ = fs::remove_file(&upload_path).await;
+                        return Err(e);
                     }
+                    fs::rename(&upload_path, &file_path).await?;
                 } else {
                     return Err(ServiceError::BadRequest);
                 }
@@ -60,3 +117,44 @@ pub async fn upload(
         }
     }
 }
+
+/// Writes a single file to `upload_path`. The user may store up to `quota`
+/// bytes, counting the file being written.
+async fn write_file(
+    user_id: i32,
+    field: &mut Field,
+    upload_path: &Path,
+    quota: Option<u64>,
+    throttle: &Throttle,
+) -> ServiceResult<()> {
+    let mut remaining = match quota {
+        Some(quota) => Some(quota.saturating_sub(storage::usage(user_id).await?)),
+        None => None,
+    };
+
+    let mut file = fs::File::create(upload_path).await?;
+
+    // Field in turn is stream of *Bytes* object
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        if let Some(remaining) = remaining.as_mut() {
+            if chunk.len() as u64 > *remaining {
+                return Err(ServiceError::QuotaExceeded);
+            }
+            *remaining -= chunk.len() as u64;
+        }
+        throttle.consume(chunk.len()).await;
+        METRICS.add_uploaded(chunk.len() as u64);
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    // parallel uploads of the user count against the same quota
+    if let Some(quota) = quota {
+        if storage::usage(user_id).await? > quota {
+            return Err(ServiceError::QuotaExceeded);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,320 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Files of the web UI, bundled into the binary by `build.rs`.
+//!
+//! Files in `assets.override_dir` are served instead of the bundled ones,
+//! e.g. for theming.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use actix_files::NamedFile;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+use sha2::{Digest, Sha256};
+
+use crate::config::Assets;
+use crate::AppData;
+
+/// File of `static/` with compressed variants that are smaller
+pub struct Asset {
+    /// Path relative to `static/` with `/` as separator
+    pub path: &'static str,
+    pub content: &'static [u8],
+    pub gzip: Option<&'static [u8]>,
+    pub brotli: Option<&'static [u8]>,
+}
+
+pub static ASSETS: &[Asset] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+lazy_static::lazy_static! {
+    /// Bundled files with their ETag by path
+    static ref BUNDLE: HashMap<&'static str, (&'static Asset, String)> = ASSETS
+        .iter()
+        .map(|asset| {
+            let digest = Sha256::digest(asset.content);
+            let etag = format!(
+                "\"{}\"",
+                base64::encode_config(&digest[..16], base64::URL_SAFE_NO_PAD)
+            );
+            (asset.path, (asset, etag))
+        })
+        .collect();
+}
+
+/// Serves `static/{path}`
+async fn asset(req: HttpRequest, data: AppData) -> actix_web::Result<HttpResponse> {
+    let path = req.match_info().query("path").to_owned();
+    file(&req, &data, &path)
+}
+
+/// Response with the file at `path` relative to `static/`
+pub fn file(
+    req: &HttpRequest,
+    data: &AppData,
+    path: &str,
+) -> actix_web::Result<HttpResponse> {
+    let settings = data.settings();
+    let cache_control = cache_control(&settings.assets, path);
+
+    if let Some(file) = settings
+        .assets
+        .override_dir
+        .as_deref()
+        .and_then(|dir| override_path(dir, path))
+        .filter(|file| file.is_file())
+    {
+        let mut res = NamedFile::open(file)?.into_response(req);
+        res.headers_mut()
+            .insert(header::CACHE_CONTROL, cache_control);
+        return Ok(res);
+    }
+
+    let (asset, etag) = match BUNDLE.get(path) {
+        Some(asset) => asset,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let modified = none_match(req, etag);
+    let mut res = if modified {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::NotModified()
+    };
+    res.insert_header((header::ETAG, etag.as_str()))
+        .insert_header((header::CACHE_CONTROL, cache_control));
+    if asset.gzip.is_some() || asset.brotli.is_some() {
+        res.insert_header((header::VARY, "accept-encoding"));
+    }
+    if !modified {
+        return Ok(res.finish());
+    }
+
+    res.insert_header((header::CONTENT_TYPE, content_type(path)));
+    let body = match (asset.brotli, asset.gzip) {
+        (Some(brotli), _) if accepts(req, "br") => {
+            res.insert_header((header::CONTENT_ENCODING, "br"));
+            brotli
+        }
+        (_, Some(gzip)) if accepts(req, "gzip") => {
+            res.insert_header((header::CONTENT_ENCODING, "gzip"));
+            gzip
+        }
+        _ => asset.content,
+    };
+    Ok(res.body(body))
+}
+
+/// Path of the file in the override directory, hidden files and paths
+/// leaving the directory are rejected
+fn override_path(dir: &str, path: &str) -> Option<PathBuf> {
+    let mut file = PathBuf::from(dir);
+    for segment in path.split('/') {
+        if segment.is_empty() || segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+        file.push(segment);
+    }
+    Some(file)
+}
+
+/// Pages are revalidated on every visit, so they always load the scripts
+/// and styles of the running version after `max_age` passed
+fn cache_control(config: &Assets, path: &str) -> HeaderValue {
+    if path.ends_with(".html") {
+        HeaderValue::from_static("no-cache")
+    } else {
+        HeaderValue::from_str(&format!("public, max-age={}", config.max_age)).unwrap()
+    }
+}
+
+fn content_type(path: &str) -> String {
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+    let guess = actix_files::file_extension_to_mime(extension);
+    if guess.type_() == mime::TEXT || guess.subtype() == mime::JAVASCRIPT {
+        format!("{}; charset=utf-8", guess)
+    } else {
+        guess.to_string()
+    }
+}
+
+/// Returns false if the `If-None-Match` header contains `etag`
+fn none_match(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .all(|tag| tag != etag && tag != "*")
+}
+
+/// Returns true if the `Accept-Encoding` header allows `coding`
+fn accepts(req: &HttpRequest, coding: &str) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            // "br;q=0" rejects the coding
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            name.eq_ignore_ascii_case(coding) && quality > 0.0
+        })
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/static/{path:.*}")
+            .route(web::get().to(asset))
+            .route(web::head().to(asset)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn assets_work() {
+        let dir =
+            std::env::temp_dir().join(format!("triox-assets-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(dir.join("CSS")).unwrap();
+        std::fs::write(dir.join("CSS/main.css"), "body { color: red; }").unwrap();
+        std::fs::write(dir.join(".secret"), "secret").unwrap();
+
+        let mut settings = crate::tests::settings();
+        settings.assets.override_dir = Some(dir.to_str().unwrap().to_owned());
+        settings.assets.max_age = 60;
+        let data = crate::tests::app_state_with(settings).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(data.clone()))
+                .configure(services),
+        )
+        .await;
+
+        let get = |path: &str| test::TestRequest::get().uri(path);
+
+        let res = test::call_service(&app, get("/static/JS/main.js").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // older versions of mime_guess use application/javascript
+        assert!(res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .ends_with("/javascript; charset=utf-8"));
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=60"
+        );
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        let body = test::read_body(res).await;
+        assert_eq!(body, include_bytes!("../static/JS/main.js")[..]);
+
+        let res = test::call_service(
+            &app,
+            get("/static/JS/main.js")
+                .insert_header((header::ACCEPT_ENCODING, "gzip, deflate, br"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "accept-encoding");
+
+        let res = test::call_service(
+            &app,
+            get("/static/JS/main.js")
+                .insert_header((header::ACCEPT_ENCODING, "br;q=0, gzip"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        let body = test::read_body(res).await;
+        assert_eq!(body, BUNDLE["JS/main.js"].0.gzip.unwrap());
+
+        let res = test::call_service(
+            &app,
+            get("/static/JS/main.js")
+                .insert_header((header::IF_NONE_MATCH, etag.clone()))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), etag);
+        assert!(test::read_body(res).await.is_empty());
+
+        let res = test::call_service(&app, get("/static/index.html").to_request()).await;
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "no-cache"
+        );
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+
+        // files of the override directory take precedence
+        let res =
+            test::call_service(&app, get("/static/CSS/main.css").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=60"
+        );
+        let body = test::read_body(res).await;
+        assert_eq!(body, "body { color: red; }");
+
+        for path in [
+            "/static/.secret",
+            "/static/CSS/../.secret",
+            "/static/%2e%2e/Cargo.toml",
+            "/static/missing.js",
+        ] {
+            let res = test::call_service(&app, get(path).to_request()).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn accepts_works() {
+        let req = |value: &str| {
+            test::TestRequest::default()
+                .insert_header((header::ACCEPT_ENCODING, value))
+                .to_http_request()
+        };
+        assert!(accepts(&req("gzip, br"), "br"));
+        assert!(accepts(&req("GZIP;q=0.5"), "gzip"));
+        assert!(!accepts(&req("gzip, br;q=0"), "br"));
+        assert!(!accepts(&req("identity"), "gzip"));
+        assert!(!accepts(
+            &test::TestRequest::default().to_http_request(),
+            "gzip"
+        ));
+    }
+}
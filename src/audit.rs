@@ -0,0 +1,163 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+
+use crate::db::NewAuditEntry;
+use crate::errors::*;
+use crate::middleware::rate_limit::request_ip;
+use crate::AppData;
+
+/// Values of `triox_audit_log.event`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Login,
+    Logout,
+    Register,
+    AccountDelete,
+    UsernameUpdate,
+    EmailUpdate,
+    UserCreate,
+    UserLock,
+    UserUnlock,
+    PasswordReset,
+    RoleUpdate,
+    QuotaUpdate,
+    BandwidthUpdate,
+    ConfigReload,
+    Upload,
+    CreateDir,
+    Copy,
+    Move,
+    Remove,
+}
+
+impl Event {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Event::Login => "login",
+            Event::Logout => "logout",
+            Event::Register => "register",
+            Event::AccountDelete => "account_delete",
+            Event::UsernameUpdate => "username_update",
+            Event::EmailUpdate => "email_update",
+            Event::UserCreate => "user_create",
+            Event::UserLock => "user_lock",
+            Event::UserUnlock => "user_unlock",
+            Event::PasswordReset => "password_reset",
+            Event::RoleUpdate => "role_update",
+            Event::QuotaUpdate => "quota_update",
+            Event::BandwidthUpdate => "bandwidth_update",
+            Event::ConfigReload => "config_reload",
+            Event::Upload => "upload",
+            Event::CreateDir => "create_dir",
+            Event::Copy => "copy",
+            Event::Move => "move",
+            Event::Remove => "remove",
+        }
+    }
+}
+
+/// Entry of the audit log, written after the operation finished
+///
+/// ```ignore
+/// let res = remove(user_id, &path).await;
+/// Record::new(Event::Remove)
+///     .user(user_id)
+///     .path(&path)
+///     .save(&req, &data, &res)
+///     .await;
+/// ```
+pub struct Record {
+    event: Event,
+    user_id: Option<i32>,
+    path: Option<String>,
+    detail: Option<String>,
+}
+
+impl Record {
+    pub fn new(event: Event) -> Self {
+        Record {
+            event,
+            user_id: None,
+            path: None,
+            detail: None,
+        }
+    }
+
+    pub fn user(mut self, user_id: i32) -> Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Stores the entry with the outcome of the operation. Failures are only
+    /// logged, the audit log doesn't affect the response.
+    pub async fn save<T>(
+        self,
+        req: &HttpRequest,
+        data: &AppData,
+        result: &ServiceResult<T>,
+    ) {
+        let trusted = data.limiters.trusted_proxies();
+        let ip = request_ip(req.peer_addr(), req.headers(), trusted).to_string();
+        self.insert(Some(ip), data, result).await;
+    }
+
+    /// Stores the entry of an operation from the command line, without
+    /// client address.
+    pub async fn save_local<T>(self, data: &AppData, result: &ServiceResult<T>) {
+        self.insert(None, data, result).await;
+    }
+
+    async fn insert<T>(
+        self,
+        ip: Option<String>,
+        data: &AppData,
+        result: &ServiceResult<T>,
+    ) {
+        let error = result.as_ref().err().map(|e| e.to_string());
+
+        let entry = NewAuditEntry {
+            user_id: self.user_id,
+            ip: ip.as_deref(),
+            event: self.event.as_str(),
+            path: self.path.as_deref(),
+            detail: self.detail.as_deref(),
+            error: error.as_deref(),
+        };
+        let res = data.db.add_audit_entry(&entry).await;
+
+        if let Err(e) = res {
+            log::error!(
+                "Unable to write {} event to audit log: {}",
+                self.event.as_str(),
+                e
+            );
+        }
+    }
+}
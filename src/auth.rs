@@ -1,19 +1,20 @@
-use actix_web::HttpResponse;
-use actix_web::Responder;
+use actix_web::{HttpRequest, HttpResponse};
 
-const SIGN_IN: &str = include_str!("../static/sign_in.html");
-const SIGN_UP: &str = include_str!("../static/sign_up.html");
+use crate::assets;
+use crate::AppData;
 
 /// Give user sign in page.
-pub async fn sign_in_page() -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(SIGN_IN)
+pub async fn sign_in_page(
+    req: HttpRequest,
+    data: AppData,
+) -> actix_web::Result<HttpResponse> {
+    assets::file(&req, &data, "sign_in.html")
 }
 
 /// Give user sign up page.
-pub async fn sign_up_page() -> impl Responder {
-    HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(SIGN_UP)
+pub async fn sign_up_page(
+    req: HttpRequest,
+    data: AppData,
+) -> actix_web::Result<HttpResponse> {
+    assets::file(&req, &data, "sign_up.html")
 }
@@ -1,8 +1,51 @@
 use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg};
 
+use crate::config::LogFormat;
+
+/// Administrative tasks that run instead of the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    UserCreate {
+        username: String,
+        email: Option<String>,
+        /// read from stdin if missing
+        password: Option<String>,
+        admin: bool,
+    },
+    UserDelete {
+        username: String,
+    },
+    UserList {
+        query: Option<String>,
+    },
+    UserSetRole {
+        username: String,
+        role: String,
+    },
+    UserResetPassword {
+        username: String,
+        /// read from stdin if missing
+        password: Option<String>,
+    },
+    MigrateStatus,
+    MigrateUp {
+        /// only list pending migrations
+        dry_run: bool,
+    },
+    CheckConfig,
+    StorageUsage {
+        /// all users if missing
+        username: Option<String>,
+    },
+}
+
 pub struct Options {
     pub config_dir: String,
     pub log_level: String,
+    /// overrides `log.format` of the configuration
+    pub log_format: Option<LogFormat>,
+    pub migrate_storage: bool,
+    pub command: Option<Command>,
 }
 
 impl Default for Options {
@@ -10,6 +53,9 @@ impl Default for Options {
         Options {
             config_dir: String::from("config"),
             log_level: String::from("info"),
+            log_format: None,
+            migrate_storage: false,
+            command: None,
         }
     }
 }
@@ -35,12 +81,148 @@ impl Options {
                     .takes_value(true)
                     .help("Set default log level."),
             )
+            .arg(
+                Arg::with_name("log-format")
+                    .long("log-format")
+                    .takes_value(true)
+                    .possible_values(["text", "json"])
+                    .help("Log output format, overrides the configuration."),
+            )
+            .arg(
+                Arg::with_name("migrate-storage")
+                    .long("migrate-storage")
+                    .help("Move user storage from data/users/{name} to data/users/{id} and exit."),
+            )
+            .subcommand(
+                App::new("user")
+                    .about("Manage accounts.")
+                    .subcommand_required(true)
+                    .arg_required_else_help(true)
+                    .subcommand(
+                        App::new("create")
+                            .about("Create an account, also when registration is disabled.")
+                            .arg(Arg::with_name("username").required(true))
+                            .arg(Arg::with_name("email").long("email").takes_value(true))
+                            .arg(password_arg())
+                            .arg(
+                                Arg::with_name("admin")
+                                    .long("admin")
+                                    .help("Create an admin account."),
+                            ),
+                    )
+                    .subcommand(
+                        App::new("delete")
+                            .about("Delete an account with all stored files.")
+                            .arg(Arg::with_name("username").required(true)),
+                    )
+                    .subcommand(
+                        App::new("list")
+                            .about("List accounts.")
+                            .arg(
+                                Arg::with_name("query")
+                                    .help("Only list accounts whose name or email contain the query."),
+                            ),
+                    )
+                    .subcommand(
+                        App::new("set-role")
+                            .about("Promote a user to admin or demote an admin.")
+                            .arg(Arg::with_name("username").required(true))
+                            .arg(
+                                Arg::with_name("role")
+                                    .required(true)
+                                    .possible_values(["user", "admin"]),
+                            ),
+                    )
+                    .subcommand(
+                        App::new("reset-password")
+                            .about("Set a new password.")
+                            .arg(Arg::with_name("username").required(true))
+                            .arg(password_arg()),
+                    ),
+            )
+            .subcommand(
+                App::new("migrate")
+                    .about("Manage database migrations, applies pending migrations by default.")
+                    .subcommand(
+                        App::new("status").about("List applied and pending migrations."),
+                    )
+                    .subcommand(
+                        App::new("up").about("Apply pending migrations.").arg(
+                            Arg::with_name("dry-run")
+                                .long("dry-run")
+                                .help("Only list pending migrations."),
+                        ),
+                    ),
+            )
+            .subcommand(
+                App::new("check-config").about("Check the configuration and exit."),
+            )
+            .subcommand(
+                App::new("storage")
+                    .about("Inspect user storage.")
+                    .subcommand_required(true)
+                    .arg_required_else_help(true)
+                    .subcommand(
+                        App::new("usage")
+                            .about("Show used storage and quota of users.")
+                            .arg(Arg::with_name("username").help("Defaults to all users.")),
+                    ),
+            )
             .get_matches();
 
+        options.migrate_storage = matches.is_present("migrate-storage");
+
+        let value =
+            |args: &clap::ArgMatches, name: &str| args.value_of(name).map(str::to_owned);
+        options.command = match matches.subcommand() {
+            Some(("user", user)) => match user.subcommand() {
+                Some(("create", args)) => Some(Command::UserCreate {
+                    username: value(args, "username").unwrap(),
+                    email: value(args, "email"),
+                    password: value(args, "password"),
+                    admin: args.is_present("admin"),
+                }),
+                Some(("delete", args)) => Some(Command::UserDelete {
+                    username: value(args, "username").unwrap(),
+                }),
+                Some(("list", args)) => Some(Command::UserList {
+                    query: value(args, "query"),
+                }),
+                Some(("set-role", args)) => Some(Command::UserSetRole {
+                    username: value(args, "username").unwrap(),
+                    role: value(args, "role").unwrap(),
+                }),
+                Some(("reset-password", args)) => Some(Command::UserResetPassword {
+                    username: value(args, "username").unwrap(),
+                    password: value(args, "password"),
+                }),
+                _ => None,
+            },
+            Some(("migrate", migrate)) => match migrate.subcommand() {
+                Some(("status", _)) => Some(Command::MigrateStatus),
+                Some(("up", args)) => Some(Command::MigrateUp {
+                    dry_run: args.is_present("dry-run"),
+                }),
+                _ => Some(Command::MigrateUp { dry_run: false }),
+            },
+            Some(("check-config", _)) => Some(Command::CheckConfig),
+            Some(("storage", storage)) => match storage.subcommand() {
+                Some(("usage", args)) => Some(Command::StorageUsage {
+                    username: value(args, "username"),
+                }),
+                _ => None,
+            },
+            _ => None,
+        };
+
         if let Some(config_dir) = matches.value_of("config-dir") {
             options.config_dir = config_dir.to_owned();
         }
 
+        if let Some(log_format) = matches.value_of("log-format") {
+            options.log_format = log_format.parse().ok();
+        }
+
         if let Some(log_level) = matches.value_of("default-log-level") {
             match log_level {
                 "warn" | "trace" | "debug" | "error" | "info" => {
@@ -55,3 +237,10 @@ impl Options {
         options
     }
 }
+
+fn password_arg() -> Arg<'static> {
+    Arg::with_name("password")
+        .long("password")
+        .takes_value(true)
+        .help("Read from stdin if missing, passing it as argument makes it visible to other users.")
+}
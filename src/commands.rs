@@ -0,0 +1,263 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Administrative commands of the `triox` binary.
+//!
+//! User commands use the same runners as the admin API and are recorded
+//! in the audit log without client address.
+
+use std::io::BufRead;
+
+use crate::api::v1::admin::runners::*;
+use crate::api::v1::admin::Role;
+use crate::audit::{Event, Record};
+use crate::cli::Command;
+use crate::config::AppConfig;
+use crate::errors::*;
+use crate::migrate;
+use crate::AppData;
+
+/// Loads and validates the configuration without connecting to the database.
+pub fn check_config(config_dir: &str) -> bool {
+    match AppConfig::load(config_dir) {
+        Ok(_) => {
+            println!("Configuration in {} is valid", config_dir);
+            true
+        }
+        Err(problems) => {
+            print_problems(config_dir, &problems);
+            false
+        }
+    }
+}
+
+pub fn print_problems(config_dir: &str, problems: &[String]) {
+    eprintln!(
+        "Found {} problems in the configuration in {}:",
+        problems.len(),
+        config_dir
+    );
+    for problem in problems {
+        eprintln!("  - {}", problem);
+    }
+}
+
+/// Runs a command that needs the database, returns false if it failed.
+pub async fn run(command: Command, data: &AppData) -> bool {
+    match execute(command, data).await {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            false
+        }
+    }
+}
+
+async fn execute(command: Command, data: &AppData) -> ServiceResult<()> {
+    match command {
+        Command::UserCreate {
+            username,
+            email,
+            password,
+            admin,
+        } => {
+            let payload = CreateUser {
+                password: password_or_stdin(password)?,
+                username,
+                email,
+                role: admin.then_some(Role::Admin),
+            };
+            let res = create_user_runner(&payload, data).await;
+            Record::new(Event::UserCreate)
+                .detail(&payload.username)
+                .save_local(data, &res)
+                .await;
+            println!("Created user {} with ID {}", payload.username, res?);
+        }
+        Command::UserDelete { username } => {
+            let id = user_id_runner(&username, data).await?;
+            let res = delete_user_runner(id, data).await;
+            Record::new(Event::AccountDelete)
+                .detail(format!("user {}", id))
+                .save_local(data, &res)
+                .await;
+            res?;
+            println!("Deleted user {}", username);
+        }
+        Command::UserList { query } => {
+            let mut query = UserQuery {
+                query,
+                limit: Some(MAX_LIMIT),
+                offset: Some(0),
+            };
+            println!(
+                "{:>8}  {:<24}  {:<32}  {:<5}  {}",
+                "ID", "NAME", "EMAIL", "ROLE", "STATUS"
+            );
+            loop {
+                let users = list_users_runner(&query, data).await?;
+                for user in users.iter() {
+                    println!(
+                        "{:>8}  {:<24}  {:<32}  {:<5}  {}",
+                        user.id,
+                        user.name,
+                        user.email.as_deref().unwrap_or("-"),
+                        role_name(user.role),
+                        if user.locked { "locked" } else { "active" }
+                    );
+                }
+                if (users.len() as i64) < MAX_LIMIT {
+                    break;
+                }
+                query.offset = query.offset.map(|offset| offset + MAX_LIMIT);
+            }
+        }
+        Command::UserSetRole { username, role } => {
+            let payload = SetRole {
+                id: user_id_runner(&username, data).await?,
+                role: role.parse().map_err(|_| ServiceError::BadRequest)?,
+            };
+            let res = set_role_runner(&payload, data).await;
+            Record::new(Event::RoleUpdate)
+                .detail(format!("user {}", payload.id))
+                .save_local(data, &res)
+                .await;
+            res?;
+            println!("{} is now {}", username, role_name(payload.role));
+        }
+        Command::UserResetPassword { username, password } => {
+            let payload = ResetPassword {
+                id: user_id_runner(&username, data).await?,
+                password: password_or_stdin(password)?,
+            };
+            let res = reset_password_runner(&payload, data).await;
+            Record::new(Event::PasswordReset)
+                .detail(format!("user {}", payload.id))
+                .save_local(data, &res)
+                .await;
+            res?;
+            println!("Password of {} changed", username);
+        }
+        Command::MigrateStatus => {
+            let status = migrate::status(&*data.db).await.map_err(migrate_error)?;
+            for migration in status.applied.iter() {
+                println!(
+                    "{:>16}  applied  {}",
+                    migration.version, migration.description
+                );
+            }
+            for migration in status.pending.iter() {
+                println!(
+                    "{:>16}  pending  {}",
+                    migration.version, migration.description
+                );
+            }
+            if let Some(version) = status.dirty {
+                eprintln!(
+                    "Migration {} is partially applied and needs to be repaired manually",
+                    version
+                );
+                return Err(ServiceError::InternalServerError);
+            }
+        }
+        Command::MigrateUp { dry_run } => {
+            let migrations = migrate::up(&*data.db, dry_run)
+                .await
+                .map_err(migrate_error)?;
+            let action = if dry_run { "Would apply" } else { "Applied" };
+            for migration in migrations.iter() {
+                println!(
+                    "{} migration {} {}",
+                    action, migration.version, migration.description
+                );
+            }
+            if migrations.is_empty() {
+                println!("Database is up to date");
+            }
+        }
+        Command::CheckConfig => {
+            unreachable!("handled before connecting to the database")
+        }
+        Command::StorageUsage { username } => {
+            let ids = match username {
+                Some(username) => {
+                    vec![(user_id_runner(&username, data).await?, username)]
+                }
+                None => {
+                    let mut query = UserQuery {
+                        query: None,
+                        limit: Some(MAX_LIMIT),
+                        offset: Some(0),
+                    };
+                    let mut ids = Vec::new();
+                    loop {
+                        let users = list_users_runner(&query, data).await?;
+                        let done = (users.len() as i64) < MAX_LIMIT;
+                        ids.extend(users.into_iter().map(|user| (user.id, user.name)));
+                        if done {
+                            break;
+                        }
+                        query.offset = query.offset.map(|offset| offset + MAX_LIMIT);
+                    }
+                    ids
+                }
+            };
+
+            println!(
+                "{:>8}  {:<24}  {:>16}  {:>16}",
+                "ID", "NAME", "USED", "QUOTA"
+            );
+            for (id, name) in ids {
+                let usage = usage_runner(id, data).await?;
+                println!(
+                    "{:>8}  {:<24}  {:>16}  {:>16}",
+                    id,
+                    name,
+                    usage.used,
+                    usage.quota.map_or("-".into(), |quota| quota.to_string())
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// prints the details, [ServiceError] has no variant for migrations
+fn migrate_error(e: sqlx::migrate::MigrateError) -> ServiceError {
+    eprintln!("Migration failed: {}", e);
+    ServiceError::InternalServerError
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::User => "user",
+        Role::Admin => "admin",
+    }
+}
+
+/// Passwords given as argument are visible in the process list,
+/// reading them from stdin allows piping them from a password manager.
+fn password_or_stdin(password: Option<String>) -> ServiceResult<String> {
+    if let Some(password) = password {
+        return Ok(password);
+    }
+
+    eprint!("Password: ");
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
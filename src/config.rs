@@ -5,62 +5,442 @@
 //! The values are then converted into an `AppConfig` struct that allows faster access
 //! and also enforces the type system.
 
-use serde::Deserialize;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use actix_web::http::header::HeaderValue;
+use serde::{Deserialize, Deserializer, Serialize};
 
 use config::{Config, Environment, File};
-/// Stores a database type (currently only MySQL).
-#[derive(Debug, Clone, Deserialize)]
+/// Stores a database type, see [Database::server_type].
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub enum DbServerType {
-    Mysql,
+    Postgres,
+    /// Embedded database in the file `database.name`
+    Sqlite,
 }
 
 /// Configurations for the http server.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Server {
     pub host: String,
     pub ip: String,
     pub port: u32,
     pub workers: usize,
-    pub registration: bool,
+    pub registration: Registration,
+    /// Email domains that may sign up in [Registration::Allowlist] mode
+    #[serde(default)]
+    pub registration_domains: Vec<String>,
+    /// Amount of invite codes a regular user may create, admins are unlimited
+    #[serde(default)]
+    pub invites_per_user: u32,
     pub secret: String,
     pub domain: String,
+    /// Deprecated, replaced by `rate_limit.auth`
     pub rate_limit_period: Option<u64>,
     pub rate_limit_burst_size: Option<u32>,
+    /// URL under which users reach Triox, used for links in emails
+    pub public_url: Option<String>,
+}
+
+/// Who may create accounts through the sign up API.
+/// Admins can always create accounts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Registration {
+    /// Everyone
+    Open,
+    /// Nobody
+    Closed,
+    /// Only users with a valid invite code
+    Invite,
+    /// Users with an email address from `registration_domains` or an invite code
+    Allowlist,
+}
+
+impl FromStr for Registration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "open" | "true" => Ok(Registration::Open),
+            "closed" | "false" => Ok(Registration::Closed),
+            "invite" => Ok(Registration::Invite),
+            "allowlist" => Ok(Registration::Allowlist),
+            _ => Err(format!(
+                "invalid registration mode \"{}\", expected one of \
+                \"open\", \"closed\", \"invite\" or \"allowlist\"",
+                s
+            )),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+// older configurations use a boolean
+impl<'de> Deserialize<'de> for Registration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Bool(bool),
+            Mode(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Bool(true) => Ok(Registration::Open),
+            Repr::Bool(false) => Ok(Registration::Closed),
+            Repr::Mode(mode) => mode.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Files {
     pub read_only: bool,
 }
 
 /// Configurations for the database connector.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Database {
-    // TODO changed to string, need to use the correct Pool type
-    // while creating database connection
+    /// "postgres" or "sqlite"
     pub db: String,
     pub user: String,
     pub password: String,
     pub host: String,
+    pub port: Option<u16>,
     pub name: String,
     pub pool: u32,
+    /// Apply pending migrations on startup
+    #[serde(default = "Database::default_auto_migrate")]
+    pub auto_migrate: bool,
 }
 
 /// Configurations for tls.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Tls {
     pub enabled: bool,
     pub certificate_path: Option<String>,
     pub key_path: Option<String>,
+    /// Obtain and renew the certificate automatically
+    pub acme: Option<Acme>,
+    /// Plain HTTP address that redirects to HTTPS
+    pub redirect_listen: Option<String>,
+    /// Send the Strict-Transport-Security header
+    pub hsts: Option<Hsts>,
+}
+
+/// Configurations of the Strict-Transport-Security header.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Hsts {
+    /// Time in seconds browsers only connect with HTTPS
+    #[serde(default = "Hsts::default_max_age")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    /// Allow adding the domain to the preload lists of browsers
+    #[serde(default)]
+    pub preload: bool,
+}
+
+/// Challenge the ACME CA uses to check control over a domain.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum AcmeChallenge {
+    /// HTTP request to port 80, answered by a separate listener
+    #[serde(rename = "http-01")]
+    Http01,
+    /// TLS handshake with the server
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+/// Configurations for obtaining certificates from an ACME CA like Let's Encrypt.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Acme {
+    /// Directory URL of the CA
+    #[serde(default = "Acme::default_directory")]
+    pub directory: String,
+    /// Contact address for notices of the CA
+    pub email: Option<String>,
+    /// Domains of the certificate, defaults to `server.domain`
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default = "Acme::default_challenge")]
+    pub challenge: AcmeChallenge,
+    /// Address answering HTTP-01 challenges, the CA connects to port 80
+    #[serde(default = "Acme::default_http_listen")]
+    pub http_listen: String,
+    /// Key of the ACME account, created if it doesn't exist
+    #[serde(default = "Acme::default_account_key_path")]
+    pub account_key_path: String,
+    /// Renew the certificate if it expires within this many days
+    #[serde(default = "Acme::default_renew_before")]
+    pub renew_before: u32,
+    /// Additional trusted root certificate for connections to the CA,
+    /// e.g. of a Pebble test server
+    pub ca_certificate: Option<String>,
+}
+
+/// Transport used for delivering outgoing emails.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Deliver messages to an SMTP server.
+    Smtp,
+    /// Write messages as `.eml` files to a directory (development and testing).
+    File,
+}
+
+/// Encryption used for the connection to the SMTP server.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpEncryption {
+    None,
+    Starttls,
+    Tls,
+}
+
+/// Configurations for outgoing emails.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Smtp {
+    pub transport: MailTransport,
+    /// Sender address, e.g. `Triox <noreply@example.com>`
+    pub from: String,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "Smtp::default_encryption")]
+    pub encryption: SmtpEncryption,
+    /// Target directory of the file transport.
+    pub directory: Option<String>,
+    #[serde(default = "Smtp::default_queue_size")]
+    pub queue_size: usize,
+    #[serde(default = "Smtp::default_max_retries")]
+    pub max_retries: u32,
+    /// Delay in milliseconds before the first retry, doubled on every further attempt.
+    #[serde(default = "Smtp::default_retry_delay")]
+    pub retry_delay: u64,
+}
+
+/// Configurations for authenticating users against an LDAP directory.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Ldap {
+    /// e.g. `ldap://localhost:389` or `ldaps://ldap.example.com`
+    pub url: String,
+    #[serde(default)]
+    pub starttls: bool,
+    /// Connection timeout in seconds
+    #[serde(default = "Ldap::default_timeout")]
+    pub timeout: u64,
+    /// Service account used for searching users, binds anonymously if missing
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub base_dn: String,
+    /// Search filter, `{login}` is replaced by the escaped login
+    #[serde(default = "Ldap::default_filter")]
+    pub filter: String,
+    /// Attribute used as Triox username
+    #[serde(default = "Ldap::default_username_attribute")]
+    pub username_attribute: String,
+    #[serde(default = "Ldap::default_email_attribute")]
+    pub email_attribute: String,
+    /// Attribute listing the groups of a user
+    #[serde(default = "Ldap::default_group_attribute")]
+    pub group_attribute: String,
+    /// Members of these groups (DNs) become admins
+    #[serde(default)]
+    pub admin_groups: Vec<String>,
+}
+
+/// Configurations of an OpenID Connect identity provider.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Oidc {
+    /// Identifier used in URLs
+    pub name: String,
+    /// Label of the sign in button
+    pub display_name: String,
+    /// Issuer URL, the provider configuration is discovered from
+    /// `{issuer}/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    #[serde(default = "Oidc::default_scopes")]
+    pub scopes: Vec<String>,
+    /// Claim used as username for new accounts
+    #[serde(default = "Oidc::default_username_claim")]
+    pub username_claim: String,
+    /// Create accounts for unknown users on their first sign in
+    #[serde(default = "Oidc::default_provision")]
+    pub provision: bool,
+}
+
+/// Configurations for slowing down password guessing on single accounts.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Lockout {
+    /// Failed sign in attempts before the account gets locked
+    #[serde(default = "Lockout::default_max_failures")]
+    pub max_failures: u32,
+    /// Delay in seconds after the first failed attempt, doubled on every further failure
+    #[serde(default = "Lockout::default_base_delay")]
+    pub base_delay: u64,
+    /// Upper limit for the delay in seconds
+    #[serde(default = "Lockout::default_max_delay")]
+    pub max_delay: u64,
+    /// Time in seconds an account stays locked after `max_failures` attempts
+    #[serde(default = "Lockout::default_duration")]
+    pub duration: u64,
+}
+
+/// Output format of log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "invalid log format \"{}\", expected \"text\" or \"json\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Configurations for logging.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Log {
+    #[serde(default = "Log::default_format")]
+    pub format: LogFormat,
+}
+
+/// Configurations for the Prometheus metrics endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Metrics {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Required as bearer token if set
+    pub token: Option<String>,
+    /// Serve `/metrics` on a separate address (e.g. `127.0.0.1:9100`)
+    /// instead of the main server
+    pub listen: Option<String>,
+}
+
+/// Configurations for the readiness checks.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Health {
+    /// Minimum free space of the storage directory in bytes
+    #[serde(default = "Health::default_min_free_space")]
+    pub min_free_space: u64,
+    /// Time in milliseconds after a single check fails
+    #[serde(default = "Health::default_timeout")]
+    pub timeout: u64,
+}
+
+/// Configurations for the files of the web UI, which are bundled into the binary.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Assets {
+    /// Files in this directory are served instead of the bundled ones
+    pub override_dir: Option<String>,
+    /// Time in seconds browsers use scripts, styles and images without
+    /// asking for changes. Pages are always revalidated.
+    #[serde(default = "Assets::default_max_age")]
+    pub max_age: u64,
+}
+
+/// Headers that restrict what browsers allow pages of Triox to do.
+/// Empty values aren't sent.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SecurityHeaders {
+    #[serde(default = "SecurityHeaders::default_content_security_policy")]
+    pub content_security_policy: String,
+    #[serde(default = "SecurityHeaders::default_frame_options")]
+    pub frame_options: String,
+    #[serde(default = "SecurityHeaders::default_referrer_policy")]
+    pub referrer_policy: String,
+}
+
+/// Token bucket parameters of a rate limit policy.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RateLimitPolicy {
+    /// Time in milliseconds before one request is replenished
+    pub period: u64,
+    /// Maximum amount of requests that are allowed in a short interval
+    pub burst: u32,
+}
+
+/// Configurations for limiting requests per user, or per client address
+/// for anonymous requests. Groups without a policy aren't limited.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RateLimit {
+    /// Addresses or networks (CIDR notation) of reverse proxies whose
+    /// `X-Forwarded-For` header is used for determining the client address
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Sign in and sign up, falls back to `server.rate_limit_period`
+    /// and `server.rate_limit_burst_size`
+    pub auth: Option<RateLimitPolicy>,
+    pub uploads: Option<RateLimitPolicy>,
+    pub downloads: Option<RateLimitPolicy>,
+    /// Account API
+    pub account: Option<RateLimitPolicy>,
+}
+
+/// Throughput limit of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct BandwidthLimit {
+    /// Bytes per second
+    pub rate: u64,
+    /// Bytes that may be transferred at once before the rate applies
+    pub burst: u64,
+}
+
+/// Configurations for limiting the bandwidth of file uploads and downloads.
+/// Admins can override the per user rate of single accounts.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Bandwidth {
+    /// Limit of all transfers of a single user
+    pub user: Option<BandwidthLimit>,
+    /// Limit of all transfers of the server
+    pub global: Option<BandwidthLimit>,
 }
 
 /// Collection of all partial configurations.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct AppConfig {
     pub server: Server,
     pub files: Files,
     pub database: Database,
     pub tls: Tls,
+    pub smtp: Option<Smtp>,
+    pub ldap: Option<Ldap>,
+    #[serde(default)]
+    pub oidc: Vec<Oidc>,
+    #[serde(default)]
+    pub lockout: Lockout,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub bandwidth: Bandwidth,
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub health: Health,
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+    #[serde(default)]
+    pub assets: Assets,
 }
 
 impl AppConfig {
@@ -75,20 +455,14 @@ impl AppConfig {
         use std::path::PathBuf;
 
         let config = Config::builder()
-            .set_default("server.workers", "1")
-            .unwrap()
-            .set_default("server.url", "127.0.0.1")
-            .unwrap()
-            .set_default("server.listen", "127.0.0.1")
-            .unwrap()
-            .set_default("server.port", "8080")
-            .unwrap()
-            .set_default("server.registration", "false")
-            .unwrap()
-            .set_default("files.read_only", "true")
-            .unwrap()
-            .set_default("tls.enabled", "false")
-            .unwrap();
+            .set_default("server.workers", "1")?
+            .set_default("server.url", "127.0.0.1")?
+            .set_default("server.listen", "127.0.0.1")?
+            .set_default("server.port", "8080")?
+            .set_default("server.registration", "closed")?
+            .set_default("server.invites_per_user", "0")?
+            .set_default("files.read_only", "true")?
+            .set_default("tls.enabled", "false")?;
 
         let default: PathBuf = [dir, "default"].iter().collect();
         let default_path = default.to_str().unwrap_or("config/default.toml");
@@ -101,23 +475,383 @@ impl AppConfig {
         let config = config.add_source(Environment::with_prefix("TRIOX").separator("_"));
 
         let config = if let Ok(val) = env::var("PORT") {
-            config.set_override("server.port", val).unwrap()
+            config.set_override("server.port", val)?
         } else {
             config
         };
 
-        let config = config.build().unwrap();
+        config.build()?.try_deserialize()
+    }
+
+    /// Loads and validates the configuration, returns all problems at once.
+    pub fn load(dir: &str) -> Result<Self, Vec<String>> {
+        let config = Self::new(dir).map_err(|e| vec![e.to_string()])?;
+        let problems = config.validate();
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Checks values that can't be expressed by types, every problem
+    /// starts with the name of the setting.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        check(
+            self.server.secret.len() >= 32,
+            "server.secret: has to be at least 32 bytes long".into(),
+        );
+        check(
+            self.server.port <= u16::MAX as u32,
+            format!("server.port: {} is not a valid port", self.server.port),
+        );
+        check(
+            self.server.listen_address().to_socket_addrs().is_ok(),
+            format!(
+                "server.ip: can't listen on \"{}\"",
+                self.server.listen_address()
+            ),
+        );
+        if let Some(url) = &self.server.public_url {
+            check(
+                url::Url::parse(url).is_ok(),
+                format!("server.public_url: \"{}\" is not a valid URL", url),
+            );
+        }
+
+        // DATABASE_URL takes precedence over the other database settings
+        if std::env::var("DATABASE_URL").is_ok() {
+            check(
+                self.database.server_type().is_some(),
+                "DATABASE_URL: only postgres:// and sqlite:// URLs are supported".into(),
+            );
+        } else {
+            check(
+                self.database.server_type().is_some(),
+                format!(
+                    "database.db: \"{}\" is not supported, use \"postgres\" or \"sqlite\"",
+                    self.database.db
+                ),
+            );
+        }
+        check(
+            self.database.pool > 0,
+            "database.pool: has to be at least 1".into(),
+        );
+
+        if self.tls.enabled {
+            for (name, path) in [
+                ("tls.certificate_path", &self.tls.certificate_path),
+                ("tls.key_path", &self.tls.key_path),
+            ] {
+                match path {
+                    // ACME creates the files
+                    Some(_) if self.tls.acme.is_some() => (),
+                    Some(path) => check(
+                        Path::new(path).is_file(),
+                        format!("{}: file \"{}\" doesn't exist", name, path),
+                    ),
+                    None => {
+                        check(false, format!("{}: required if TLS is enabled", name))
+                    }
+                }
+            }
+        }
+
+        if let Some(listen) = &self.tls.redirect_listen {
+            check(
+                self.tls.enabled,
+                "tls.redirect_listen: requires tls.enabled = true".into(),
+            );
+            check(
+                listen.to_socket_addrs().is_ok(),
+                format!("tls.redirect_listen: can't listen on \"{}\"", listen),
+            );
+        }
+        if let Some(hsts) = &self.tls.hsts {
+            // requirements of https://hstspreload.org
+            check(
+                !hsts.preload || (hsts.include_subdomains && hsts.max_age >= 31536000),
+                "tls.hsts: preload requires include_subdomains and a max_age of at least one year".into(),
+            );
+        }
+        if let Some(acme) = &self.tls.acme {
+            check(
+                self.tls.enabled,
+                "tls.acme: requires tls.enabled = true".into(),
+            );
+            check(
+                url::Url::parse(&acme.directory).is_ok(),
+                format!(
+                    "tls.acme.directory: \"{}\" is not a valid URL",
+                    acme.directory
+                ),
+            );
+            if let Some(email) = &acme.email {
+                check(
+                    email.parse::<lettre::Address>().is_ok(),
+                    format!("tls.acme.email: \"{}\" is not a valid address", email),
+                );
+            }
+            if acme.challenge == AcmeChallenge::Http01 {
+                check(
+                    acme.http_listen.to_socket_addrs().is_ok(),
+                    format!(
+                        "tls.acme.http_listen: can't listen on \"{}\"",
+                        acme.http_listen
+                    ),
+                );
+            }
+            if let Some(path) = &acme.ca_certificate {
+                check(
+                    Path::new(path).is_file(),
+                    format!("tls.acme.ca_certificate: file \"{}\" doesn't exist", path),
+                );
+            }
+        }
+
+        // allowlist accounts can only be activated through a verification link
+        check(
+            self.server.registration != Registration::Allowlist || self.smtp.is_some(),
+            "server.registration: \"allowlist\" requires [smtp] for verification links"
+                .into(),
+        );
+        if let Some(smtp) = &self.smtp {
+            check(
+                smtp.from.parse::<lettre::message::Mailbox>().is_ok(),
+                format!("smtp.from: \"{}\" is not a valid address", smtp.from),
+            );
+            match smtp.transport {
+                MailTransport::Smtp => check(
+                    smtp.host.is_some(),
+                    "smtp.host: required for the smtp transport".into(),
+                ),
+                MailTransport::File => check(
+                    smtp.directory.is_some(),
+                    "smtp.directory: required for the file transport".into(),
+                ),
+            }
+        }
+
+        for oidc in self.oidc.iter() {
+            check(
+                url::Url::parse(&oidc.issuer).is_ok(),
+                format!(
+                    "oidc.issuer: \"{}\" of provider \"{}\" is not a valid URL",
+                    oidc.issuer, oidc.name
+                ),
+            );
+        }
+
+        for proxy in self.rate_limit.trusted_proxies.iter() {
+            check(
+                proxy
+                    .parse::<crate::middleware::rate_limit::TrustedProxy>()
+                    .is_ok(),
+                format!(
+                    "rate_limit.trusted_proxies: \"{}\" is not an address or network",
+                    proxy
+                ),
+            );
+        }
+        for (name, policy) in [
+            ("rate_limit.auth", self.rate_limit.auth),
+            ("rate_limit.uploads", self.rate_limit.uploads),
+            ("rate_limit.downloads", self.rate_limit.downloads),
+            ("rate_limit.account", self.rate_limit.account),
+        ] {
+            if let Some(policy) = policy {
+                check(
+                    policy.period > 0 && policy.burst > 0,
+                    format!("{}: period and burst have to be at least 1", name),
+                );
+            }
+        }
+
+        for (name, limit) in [
+            ("bandwidth.user", self.bandwidth.user),
+            ("bandwidth.global", self.bandwidth.global),
+        ] {
+            if let Some(limit) = limit {
+                check(
+                    limit.rate > 0 && limit.burst > 0,
+                    format!("{}: rate and burst have to be at least 1", name),
+                );
+            }
+        }
 
-        if config
-            .get::<String>("server.secret")
-            .expect("Please set a secret in configuration file")
-            .len()
-            < 32
-        {
-            panic!("Please set a secret that's at least 32 bytes long");
+        for (name, value) in [
+            (
+                "security_headers.content_security_policy",
+                &self.security_headers.content_security_policy,
+            ),
+            (
+                "security_headers.frame_options",
+                &self.security_headers.frame_options,
+            ),
+            (
+                "security_headers.referrer_policy",
+                &self.security_headers.referrer_policy,
+            ),
+        ] {
+            check(
+                HeaderValue::from_str(value).is_ok(),
+                format!("{}: \"{}\" is not a valid header value", name, value),
+            );
         }
 
-        config.try_deserialize()
+        if let Some(dir) = &self.assets.override_dir {
+            check(
+                Path::new(dir).is_dir(),
+                format!("assets.override_dir: directory \"{}\" doesn't exist", dir),
+            );
+        }
+
+        if let Some(listen) = &self.metrics.listen {
+            check(
+                listen.to_socket_addrs().is_ok(),
+                format!("metrics.listen: can't listen on \"{}\"", listen),
+            );
+        }
+
+        problems
+    }
+}
+
+/// Outcome of [LiveConfig::reload].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Reload {
+    /// Changed settings that were applied
+    pub applied: Vec<String>,
+    /// Changed settings that only take effect after a restart
+    pub restart_required: Vec<String>,
+    /// Problems of the new configuration, nothing is applied if there are any
+    pub problems: Vec<String>,
+}
+
+/// Configuration that can be replaced while the server is running.
+///
+/// Only settings that are read for every request are replaced, all other
+/// settings keep their value until the server is restarted.
+pub struct LiveConfig {
+    dir: String,
+    current: RwLock<Arc<AppConfig>>,
+}
+
+impl LiveConfig {
+    pub fn new(dir: &str, config: AppConfig) -> Self {
+        LiveConfig {
+            dir: dir.to_owned(),
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<AppConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Loads the configuration directory again and applies the changed live
+    /// settings. Invalid configurations are rejected and the current one is kept.
+    pub fn reload(&self) -> (Arc<AppConfig>, Reload) {
+        let mut current = self.current.write().unwrap();
+        let new = match AppConfig::load(&self.dir) {
+            Ok(new) => new,
+            Err(problems) => {
+                return (
+                    current.clone(),
+                    Reload {
+                        problems,
+                        ..Reload::default()
+                    },
+                )
+            }
+        };
+
+        let mut merged = AppConfig::clone(&current);
+        let mut reload = Reload::default();
+        let mut apply = |name: &str, changed: bool| {
+            if changed {
+                reload.applied.push(name.to_owned());
+            }
+        };
+
+        apply(
+            "server.registration",
+            merged.server.registration != new.server.registration,
+        );
+        merged.server.registration = new.server.registration;
+        apply(
+            "server.registration_domains",
+            merged.server.registration_domains != new.server.registration_domains,
+        );
+        merged.server.registration_domains = new.server.registration_domains.clone();
+        apply(
+            "server.invites_per_user",
+            merged.server.invites_per_user != new.server.invites_per_user,
+        );
+        merged.server.invites_per_user = new.server.invites_per_user;
+        apply(
+            "server.rate_limit_period",
+            merged.server.rate_limit_period != new.server.rate_limit_period
+                || merged.server.rate_limit_burst_size
+                    != new.server.rate_limit_burst_size,
+        );
+        merged.server.rate_limit_period = new.server.rate_limit_period;
+        merged.server.rate_limit_burst_size = new.server.rate_limit_burst_size;
+        apply("files", merged.files != new.files);
+        merged.files = new.files.clone();
+        apply("lockout", merged.lockout != new.lockout);
+        merged.lockout = new.lockout.clone();
+        apply(
+            "rate_limit",
+            merged.rate_limit.auth != new.rate_limit.auth
+                || merged.rate_limit.uploads != new.rate_limit.uploads
+                || merged.rate_limit.downloads != new.rate_limit.downloads
+                || merged.rate_limit.account != new.rate_limit.account,
+        );
+        merged.rate_limit.auth = new.rate_limit.auth;
+        merged.rate_limit.uploads = new.rate_limit.uploads;
+        merged.rate_limit.downloads = new.rate_limit.downloads;
+        merged.rate_limit.account = new.rate_limit.account;
+        apply("bandwidth", merged.bandwidth != new.bandwidth);
+        merged.bandwidth = new.bandwidth.clone();
+        apply("health", merged.health != new.health);
+        merged.health = new.health.clone();
+        apply("assets", merged.assets != new.assets);
+        merged.assets = new.assets.clone();
+
+        for (name, changed) in [
+            ("server", merged.server != new.server),
+            ("database", merged.database != new.database),
+            ("tls", merged.tls != new.tls),
+            ("smtp", merged.smtp != new.smtp),
+            ("ldap", merged.ldap != new.ldap),
+            ("oidc", merged.oidc != new.oidc),
+            (
+                "rate_limit.trusted_proxies",
+                merged.rate_limit != new.rate_limit,
+            ),
+            ("log", merged.log != new.log),
+            ("metrics", merged.metrics != new.metrics),
+            (
+                "security_headers",
+                merged.security_headers != new.security_headers,
+            ),
+        ] {
+            if changed {
+                reload.restart_required.push(name.to_owned());
+            }
+        }
+
+        *current = Arc::new(merged);
+        (current.clone(), reload)
     }
 }
 
@@ -128,23 +862,429 @@ impl Server {
     }
 }
 
+impl AppConfig {
+    /// Base URL for links pointing to this server, without trailing slash.
+    ///
+    /// Uses `server.public_url` if set and otherwise derives it from
+    /// the domain, port and TLS settings.
+    pub fn base_url(&self) -> String {
+        if let Some(url) = &self.server.public_url {
+            return url.trim_end_matches('/').to_owned();
+        }
+
+        let scheme = if self.tls.enabled { "https" } else { "http" };
+        match (self.tls.enabled, self.server.port) {
+            (true, 443) | (false, 80) => format!("{}://{}", scheme, self.server.domain),
+            (_, port) => format!("{}://{}:{}", scheme, self.server.domain, port),
+        }
+    }
+
+    /// Returns true if clients reach the server with HTTPS, either directly
+    /// or through a reverse proxy at an https:// `server.public_url`
+    pub fn https(&self) -> bool {
+        self.base_url().starts_with("https://")
+    }
+
+    /// Domains the certificate is issued for by ACME
+    pub fn acme_domains(&self) -> Vec<String> {
+        match &self.tls.acme {
+            Some(acme) if !acme.domains.is_empty() => acme.domains.clone(),
+            Some(_) => vec![self.server.domain.clone()],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Hsts {
+    fn default_max_age() -> u64 {
+        // two years, as required for preloading
+        2 * 365 * 24 * 60 * 60
+    }
+
+    /// Value of the Strict-Transport-Security header
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+impl Acme {
+    fn default_directory() -> String {
+        "https://acme-v02.api.letsencrypt.org/directory".into()
+    }
+
+    fn default_challenge() -> AcmeChallenge {
+        AcmeChallenge::TlsAlpn01
+    }
+
+    fn default_http_listen() -> String {
+        "0.0.0.0:80".into()
+    }
+
+    fn default_account_key_path() -> String {
+        "tls/account.pem".into()
+    }
+
+    fn default_renew_before() -> u32 {
+        30
+    }
+}
+
+impl Smtp {
+    fn default_encryption() -> SmtpEncryption {
+        SmtpEncryption::Starttls
+    }
+
+    fn default_queue_size() -> usize {
+        100
+    }
+
+    fn default_max_retries() -> u32 {
+        5
+    }
+
+    fn default_retry_delay() -> u64 {
+        2000
+    }
+}
+
+impl Ldap {
+    fn default_timeout() -> u64 {
+        5
+    }
+
+    fn default_filter() -> String {
+        "(uid={login})".into()
+    }
+
+    fn default_username_attribute() -> String {
+        "uid".into()
+    }
+
+    fn default_email_attribute() -> String {
+        "mail".into()
+    }
+
+    fn default_group_attribute() -> String {
+        "memberOf".into()
+    }
+}
+
+impl Oidc {
+    fn default_scopes() -> Vec<String> {
+        vec!["openid".into(), "email".into(), "profile".into()]
+    }
+
+    fn default_username_claim() -> String {
+        "preferred_username".into()
+    }
+
+    fn default_provision() -> bool {
+        true
+    }
+}
+
+impl Lockout {
+    fn default_max_failures() -> u32 {
+        10
+    }
+
+    fn default_base_delay() -> u64 {
+        1
+    }
+
+    fn default_max_delay() -> u64 {
+        60
+    }
+
+    fn default_duration() -> u64 {
+        15 * 60
+    }
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            max_failures: Self::default_max_failures(),
+            base_delay: Self::default_base_delay(),
+            max_delay: Self::default_max_delay(),
+            duration: Self::default_duration(),
+        }
+    }
+}
+
+impl Log {
+    fn default_format() -> LogFormat {
+        LogFormat::Text
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            format: Self::default_format(),
+        }
+    }
+}
+
+impl Health {
+    fn default_min_free_space() -> u64 {
+        1024 * 1024 * 1024
+    }
+
+    fn default_timeout() -> u64 {
+        1000
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            min_free_space: Self::default_min_free_space(),
+            timeout: Self::default_timeout(),
+        }
+    }
+}
+
+impl Assets {
+    fn default_max_age() -> u64 {
+        // one hour
+        60 * 60
+    }
+}
+
+impl Default for Assets {
+    fn default() -> Self {
+        Self {
+            override_dir: None,
+            max_age: Self::default_max_age(),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    fn default_content_security_policy() -> String {
+        // the bundled pages load the theme from a CDN, their scripts are
+        // all in static/JS
+        [
+            "default-src 'self'",
+            "script-src 'self'",
+            "style-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net",
+            "font-src 'self' https://cdn.jsdelivr.net",
+            "img-src 'self' data: blob:",
+            "object-src 'none'",
+            "base-uri 'self'",
+            "form-action 'self'",
+            "frame-ancestors 'none'",
+        ]
+        .join("; ")
+    }
+
+    fn default_frame_options() -> String {
+        "DENY".into()
+    }
+
+    fn default_referrer_policy() -> String {
+        "same-origin".into()
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            content_security_policy: Self::default_content_security_policy(),
+            frame_options: Self::default_frame_options(),
+            referrer_policy: Self::default_referrer_policy(),
+        }
+    }
+}
+
 impl Database {
+    fn default_auto_migrate() -> bool {
+        true
+    }
+
+    /// Database the url refers to, `None` if it isn't supported
+    pub fn server_type(&self) -> Option<DbServerType> {
+        match self.url().split(':').next() {
+            Some("postgres" | "postgresql") => Some(DbServerType::Postgres),
+            Some("sqlite") => Some(DbServerType::Sqlite),
+            _ => None,
+        }
+    }
+
     /// Builds database url from config parameters.
     pub fn url(&self) -> String {
         if let Ok(val) = std::env::var("DATABASE_URL") {
             val
+        } else if self.db == "sqlite" {
+            // `name` is the path of the database file
+            format!("sqlite://{}", self.name)
         } else {
+            let host = match self.port {
+                Some(port) => format!("{}:{}", self.host, port),
+                None => self.host.clone(),
+            };
             format!(
                 "{}://{}:{}@{}/{}",
-                self.db,
-                //            match self.server_type {
-                //                DbServerType::Mysql => "mysql",
-                //            },
-                self.user,
-                self.password,
-                self.host,
-                self.name
+                self.db, self.user, self.password, host, self.name
             )
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_works() {
+        let mut config = AppConfig::new("config").unwrap();
+        config.server.secret = "a".repeat(32);
+        config.database.pool = 4;
+        config.tls.enabled = false;
+        config.metrics.listen = None;
+        assert_eq!(config.validate(), Vec::<String>::new());
+
+        config.server.secret = "short".into();
+        config.server.port = 70000;
+        config.database.pool = 0;
+        config.tls = Tls {
+            enabled: true,
+            certificate_path: Some("does-not-exist.pem".into()),
+            key_path: None,
+            acme: None,
+            redirect_listen: Some("nowhere".into()),
+            hsts: Some(Hsts {
+                max_age: 60,
+                include_subdomains: false,
+                preload: true,
+            }),
+        };
+        config.rate_limit.trusted_proxies = vec!["10.0.0.0/33".into()];
+        config.metrics.listen = Some("localhost".into());
+        config.security_headers.referrer_policy = "no\nreferrer".into();
+        config.assets.override_dir = Some("/nonexistent/triox/theme".into());
+        config.server.registration = Registration::Allowlist;
+        config.smtp = None;
+
+        let problems = config.validate();
+        for setting in [
+            "server.secret",
+            "server.port",
+            "server.registration",
+            "database.pool",
+            "tls.certificate_path",
+            "tls.key_path",
+            "tls.redirect_listen",
+            "tls.hsts",
+            "rate_limit.trusted_proxies",
+            "metrics.listen",
+            "security_headers.referrer_policy",
+            "assets.override_dir",
+        ] {
+            assert!(
+                problems.iter().any(|p| p.starts_with(setting)),
+                "no problem with {} in {:?}",
+                setting,
+                problems
+            );
+        }
+
+        // ACME creates the certificate files
+        config.tls.acme = Some(Acme {
+            directory: "not a url".into(),
+            email: Some("not an address".into()),
+            domains: Vec::new(),
+            challenge: AcmeChallenge::Http01,
+            http_listen: "nowhere".into(),
+            account_key_path: "tls/account.pem".into(),
+            renew_before: 30,
+            ca_certificate: Some("does-not-exist.pem".into()),
+        });
+        let problems = config.validate();
+        assert!(!problems
+            .iter()
+            .any(|p| p.starts_with("tls.certificate_path")));
+        for setting in [
+            "tls.key_path",
+            "tls.acme.directory",
+            "tls.acme.email",
+            "tls.acme.http_listen",
+            "tls.acme.ca_certificate",
+        ] {
+            assert!(
+                problems.iter().any(|p| p.starts_with(setting)),
+                "no problem with {} in {:?}",
+                setting,
+                problems
+            );
+        }
+        assert_eq!(config.acme_domains(), vec![config.server.domain.clone()]);
+
+        let hsts = Hsts {
+            max_age: 60,
+            include_subdomains: true,
+            preload: true,
+        };
+        assert_eq!(
+            hsts.header_value(),
+            "max-age=60; includeSubDomains; preload"
+        );
+    }
+
+    #[test]
+    fn reload_works() {
+        let dir =
+            std::env::temp_dir().join(format!("triox-config-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy("config/default.toml", dir.join("default.toml")).unwrap();
+        let local = std::fs::read_to_string("config/local.toml")
+            .unwrap()
+            .replace(
+                "# secret = \"\"",
+                &format!("secret = \"{}\"", "s".repeat(32)),
+            );
+        let write_local = |changes: &[(&str, &str)]| {
+            let mut content = local.clone();
+            for (from, to) in changes {
+                content = content.replacen(from, to, 1);
+            }
+            std::fs::write(dir.join("local.toml"), content).unwrap();
+        };
+        write_local(&[]);
+
+        let dir_name = dir.to_str().unwrap();
+        let live = LiveConfig::new(dir_name, AppConfig::load(dir_name).unwrap());
+        let (_, reload) = live.reload();
+        assert!(reload.applied.is_empty());
+        assert!(reload.restart_required.is_empty());
+        assert!(reload.problems.is_empty());
+
+        // live settings are applied, others are kept until a restart
+        let port = live.get().server.port;
+        write_local(&[
+            ("read_only = false", "read_only = true"),
+            ("port = 8080", "port = 8081"),
+        ]);
+        let (settings, reload) = live.reload();
+        assert_eq!(reload.applied, vec!["files".to_owned()]);
+        assert_eq!(reload.restart_required, vec!["server".to_owned()]);
+        assert!(settings.files.read_only);
+        assert_eq!(settings.server.port, port);
+
+        // invalid configurations are rejected
+        write_local(&[("read_only = false", "read_only = \"maybe\"")]);
+        let (settings, reload) = live.reload();
+        assert!(!reload.problems.is_empty());
+        assert!(settings.files.read_only);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
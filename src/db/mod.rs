@@ -0,0 +1,302 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Data access layer.
+//!
+//! All queries go through the [Database] trait, which is implemented for
+//! Postgres and SQLite. Both backends have the same schema, their migrations
+//! in `migrations/postgres` and `migrations/sqlite` share versions and
+//! descriptions. Timestamps are passed as UNIX timestamps in seconds.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use sqlx::migrate::{MigrateError, Migrator};
+
+use crate::api::v1::account::invite::Invite;
+use crate::api::v1::admin::audit::runners::{AuditEntry, AuditQuery};
+use crate::api::v1::admin::Role;
+use crate::config::{self, DbServerType};
+use crate::migrate::{Migration, Status};
+
+pub mod postgres;
+pub mod sqlite;
+
+pub type DbResult<T> = Result<T, sqlx::Error>;
+
+/// Account to be created
+#[derive(Clone, Debug)]
+pub struct NewUser<'a> {
+    pub name: &'a str,
+    /// password hash
+    pub password: &'a str,
+    pub email: Option<&'a str>,
+    pub email_verified: bool,
+    pub role: Role,
+    pub provider: &'a str,
+    /// value of `triox_users.status`, e.g. for accounts that have to verify
+    /// their email address before they can sign in
+    pub status: Option<&'a str>,
+}
+
+/// Role and state of an account
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    pub id: i32,
+    pub role: Role,
+    pub status: Option<String>,
+    pub provider: String,
+}
+
+/// Everything needed to check a local password
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub id: i32,
+    /// password hash
+    pub password: String,
+    pub email_verified: bool,
+    pub status: Option<String>,
+    pub provider: String,
+}
+
+/// Details needed for emails to a user
+#[derive(Clone, Debug)]
+pub struct Contact {
+    pub name: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub locale: Option<String>,
+}
+
+/// Account as shown to admins
+#[derive(Clone, Debug)]
+pub struct UserRecord {
+    pub id: i32,
+    pub name: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub role: Role,
+    pub status: Option<String>,
+    pub quota: Option<i64>,
+    pub bandwidth: Option<i64>,
+    /// temporarily blocked after failed sign in attempts
+    pub login_blocked: bool,
+}
+
+/// Storage and bandwidth limits of a user, `None` if there is no override
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub quota: Option<i64>,
+    pub bandwidth: Option<i64>,
+}
+
+/// Entry for the audit log
+#[derive(Clone, Debug)]
+pub struct NewAuditEntry<'a> {
+    pub user_id: Option<i32>,
+    pub ip: Option<&'a str>,
+    pub event: &'a str,
+    pub path: Option<&'a str>,
+    pub detail: Option<&'a str>,
+    /// `None` if the operation succeeded
+    pub error: Option<&'a str>,
+}
+
+/// Queries of the application, implemented for every supported database.
+///
+/// Methods that update a user return `false` if the user doesn't exist.
+#[async_trait]
+pub trait Database: Send + Sync {
+    /// Checks that the database can be reached
+    async fn ping(&self) -> DbResult<()>;
+    /// Open connections of the pool
+    fn size(&self) -> u32;
+    /// Idle connections of the pool
+    fn num_idle(&self) -> usize;
+
+    /// Migrations of this backend compiled into the binary
+    fn migrator(&self) -> &'static Migrator;
+    /// Compares the applied migrations with the migrations of this version
+    async fn migration_status(&self) -> Result<Status, MigrateError>;
+    /// Applies all pending migrations and returns them. With `dry_run`, the
+    /// pending migrations are only returned.
+    async fn migrate(&self, dry_run: bool) -> Result<Vec<Migration>, MigrateError>;
+
+    /// Returns the ID of the new user
+    async fn create_user(&self, user: &NewUser<'_>) -> DbResult<i32>;
+    async fn delete_user(&self, id: i32) -> DbResult<bool>;
+    async fn user_id(&self, name: &str) -> DbResult<Option<i32>>;
+    /// IDs and names of all users
+    async fn user_ids(&self) -> DbResult<Vec<(i32, String)>>;
+    async fn name_exists(&self, name: &str) -> DbResult<bool>;
+    async fn email_exists(&self, email: &str) -> DbResult<bool>;
+    async fn account(&self, id: i32) -> DbResult<Option<Account>>;
+    async fn account_by_name(&self, name: &str) -> DbResult<Option<Account>>;
+    async fn credentials_by_name(&self, name: &str) -> DbResult<Option<Credentials>>;
+    async fn credentials_by_email(&self, email: &str) -> DbResult<Option<Credentials>>;
+    /// Password hash, fails with [sqlx::Error::RowNotFound] for unknown users
+    async fn password(&self, id: i32) -> DbResult<String>;
+    /// Fails with [sqlx::Error::RowNotFound] for unknown users
+    async fn contact(&self, id: i32) -> DbResult<Contact>;
+    async fn limits(&self, id: i32) -> DbResult<Option<Limits>>;
+    /// Users whose name or email matches the `LIKE` pattern, ordered by ID.
+    /// `\` escapes wildcards in the pattern.
+    async fn users(
+        &self,
+        pattern: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> DbResult<Vec<UserRecord>>;
+
+    async fn set_name(&self, id: i32, name: &str) -> DbResult<bool>;
+    /// Keeps the verification state if the address didn't change
+    async fn set_email(&self, id: i32, email: &str) -> DbResult<bool>;
+    /// Marks `email` as verified and clears `unverified_status`. Returns `false`
+    /// if `email` isn't the current address of the user.
+    async fn verify_email(
+        &self,
+        id: i32,
+        email: &str,
+        unverified_status: &str,
+    ) -> DbResult<bool>;
+    async fn set_password(&self, id: i32, hash: &str) -> DbResult<bool>;
+    async fn set_role(&self, id: i32, role: Role) -> DbResult<bool>;
+    async fn set_status(&self, id: i32, status: Option<&str>) -> DbResult<bool>;
+    /// Locks the account, an unverified one stays unverified after unlocking it
+    async fn lock_user(&self, id: i32) -> DbResult<bool>;
+    /// Only lifts the lock, accounts that still have to verify their email
+    /// address stay unverified
+    async fn unlock_user(&self, id: i32) -> DbResult<bool>;
+    async fn set_quota(&self, id: i32, quota: Option<i64>) -> DbResult<bool>;
+    async fn set_bandwidth(&self, id: i32, bandwidth: Option<i64>) -> DbResult<bool>;
+
+    /// ID of the account with the name or email `login` and whether sign in
+    /// attempts on it are currently blocked
+    async fn login_state(&self, login: &str) -> DbResult<Option<(i32, bool)>>;
+    /// Counts an attempt on the account `login` refers to unless attempts on it
+    /// are blocked, returns its ID and the attempts since the last successful
+    /// sign in
+    async fn add_failed_login(&self, login: &str) -> DbResult<Option<(i32, i32)>>;
+    /// Takes back an attempt that didn't check the password
+    async fn remove_failed_login(&self, id: i32) -> DbResult<()>;
+    /// Blocks sign in attempts for `seconds`, `reset` starts counting the
+    /// failed attempts over
+    async fn block_login(&self, id: i32, seconds: u64, reset: bool) -> DbResult<()>;
+    async fn reset_failed_logins(&self, id: i32) -> DbResult<()>;
+
+    /// Returns `false` if the user already created `limit` invites
+    async fn create_invite(
+        &self,
+        code: &str,
+        user_id: i32,
+        limit: i64,
+    ) -> DbResult<bool>;
+    /// Invites created by the user, oldest first
+    async fn invites(&self, user_id: i32) -> DbResult<Vec<Invite>>;
+    /// Returns `false` if the invite doesn't exist or was already claimed
+    async fn claim_invite(&self, code: &str) -> DbResult<bool>;
+    /// Makes a claimed invite available again unless it was redeemed
+    async fn release_invite(&self, code: &str) -> DbResult<()>;
+    async fn redeem_invite(&self, code: &str, user_id: i32) -> DbResult<()>;
+
+    /// ID of the user linked to the account at an OpenID Connect provider
+    async fn oidc_user(&self, provider: &str, subject: &str) -> DbResult<Option<i32>>;
+    async fn link_oidc_user(
+        &self,
+        provider: &str,
+        subject: &str,
+        user_id: i32,
+    ) -> DbResult<()>;
+    async fn user_id_by_verified_email(&self, email: &str) -> DbResult<Option<i32>>;
+
+    async fn add_audit_entry(&self, entry: &NewAuditEntry<'_>) -> DbResult<()>;
+    /// Entries matching the query, newest first
+    async fn audit_entries(
+        &self,
+        query: &AuditQuery,
+        limit: i64,
+        offset: i64,
+    ) -> DbResult<Vec<AuditEntry>>;
+    /// Entries matching the query with an ID greater than `after`, oldest first.
+    /// `limit` and `offset` of the query are ignored.
+    async fn export_audit_entries(
+        &self,
+        query: &AuditQuery,
+        after: i64,
+        limit: i64,
+    ) -> DbResult<Vec<AuditEntry>>;
+}
+
+/// Connects to the configured database
+pub async fn connect(config: &config::Database) -> DbResult<Arc<dyn Database>> {
+    let url = config.url();
+    let db: Arc<dyn Database> = match config.server_type() {
+        Some(DbServerType::Postgres) => {
+            Arc::new(postgres::Postgres::connect(&url, config.pool).await?)
+        }
+        Some(DbServerType::Sqlite) => {
+            Arc::new(sqlite::Sqlite::connect(&url, config.pool).await?)
+        }
+        // the url contains the password and isn't part of the error
+        None => {
+            return Err(sqlx::Error::Configuration(
+                "only Postgres and SQLite are supported".into(),
+            ))
+        }
+    };
+    Ok(db)
+}
+
+/// Returns true if `err` is caused by a duplicate value in a unique column
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    match err {
+        // Postgres and SQLite (SQLITE_CONSTRAINT_UNIQUE) error codes
+        sqlx::Error::Database(err) => {
+            matches!(err.code().as_deref(), Some("23505") | Some("2067"))
+        }
+        _ => false,
+    }
+}
+
+/// Returns true if `err` is caused by a duplicate value in `table.column`
+pub fn violates_unique(err: &sqlx::Error, table: &str, column: &str) -> bool {
+    if !is_unique_violation(err) {
+        return false;
+    }
+    let msg = match err {
+        sqlx::Error::Database(err) => err.message(),
+        _ => return false,
+    };
+    // Postgres names the constraint, SQLite the column
+    msg.contains(&format!("{}_{}_key", table, column))
+        || msg.contains(&format!("{}.{}", table, column))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_match() {
+        let versions = |migrator: &Migrator| -> Vec<(i64, String)> {
+            migrator
+                .iter()
+                .map(|migration| (migration.version, migration.description.to_string()))
+                .collect()
+        };
+        assert_eq!(versions(&postgres::MIGRATOR), versions(&sqlite::MIGRATOR));
+    }
+}
@@ -0,0 +1,644 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Postgres backend, queries are checked at compile time against `sqlx-data.json`.
+
+use async_trait::async_trait;
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Connection, PgPool};
+
+use super::*;
+use crate::api::v1::admin::{
+    STATUS_LOCKED, STATUS_LOCKED_UNVERIFIED, STATUS_UNVERIFIED,
+};
+use crate::audit::Event;
+use crate::migrate::{self, Migration, Status};
+
+/// Migrations of the Postgres schema
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres/");
+
+/// Key of the advisory lock held while migrating ("triox" in ASCII)
+const LOCK_KEY: i64 = 0x74_72_69_6f_78;
+
+pub struct Postgres {
+    pool: PgPool,
+}
+
+impl Postgres {
+    pub async fn connect(url: &str, pool: u32) -> DbResult<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(pool)
+            .connect(url)
+            .await?;
+        Ok(Self { pool })
+    }
+}
+
+/// Applies the pending migrations, the caller holds the advisory lock
+async fn apply(
+    con: &mut PoolConnection<sqlx::Postgres>,
+    dry_run: bool,
+) -> Result<Vec<Migration>, MigrateError> {
+    let status = migrate::status_of(&mut **con, &MIGRATOR).await?;
+    if let Some(version) = status.dirty {
+        return Err(MigrateError::Dirty(version));
+    }
+    if !dry_run && !status.pending.is_empty() {
+        MIGRATOR.run(&mut **con).await?;
+    }
+    Ok(status.pending)
+}
+
+#[async_trait]
+impl Database for Postgres {
+    async fn ping(&self) -> DbResult<()> {
+        self.pool.acquire().await?.ping().await
+    }
+
+    fn size(&self) -> u32 {
+        self.pool.size()
+    }
+
+    fn num_idle(&self) -> usize {
+        self.pool.num_idle()
+    }
+
+    fn migrator(&self) -> &'static Migrator {
+        &MIGRATOR
+    }
+
+    async fn migration_status(&self) -> Result<Status, MigrateError> {
+        let mut con = self.pool.acquire().await?;
+        migrate::status_of(&mut *con, &MIGRATOR).await
+    }
+
+    // Migrations are applied while holding an advisory lock, so instances that
+    // start at the same time against one database don't race each other.
+    async fn migrate(&self, dry_run: bool) -> Result<Vec<Migration>, MigrateError> {
+        let mut con = self.pool.acquire().await?;
+
+        // other instances wait here until the migrations are applied,
+        // pg_advisory_lock returns void which the query macros can't describe
+        sqlx::query("SELECT pg_advisory_lock($1)")
+            .bind(LOCK_KEY)
+            .execute(&mut con)
+            .await?;
+
+        let res = apply(&mut con, dry_run).await;
+
+        sqlx::query("SELECT pg_advisory_unlock($1)")
+            .bind(LOCK_KEY)
+            .execute(&mut con)
+            .await?;
+        res
+    }
+
+    async fn create_user(&self, user: &NewUser<'_>) -> DbResult<i32> {
+        let rec = sqlx::query!(
+            "INSERT INTO triox_users
+            (name, password, email, email_verified, role, provider, status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+            user.name,
+            user.password,
+            user.email,
+            user.email_verified,
+            user.role.to_db(),
+            user.provider,
+            user.status,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(rec.id)
+    }
+
+    async fn delete_user(&self, id: i32) -> DbResult<bool> {
+        let res = sqlx::query!("DELETE FROM triox_users WHERE id = ($1)", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn user_id(&self, name: &str) -> DbResult<Option<i32>> {
+        let rec = sqlx::query!("SELECT id FROM triox_users WHERE name = $1", name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(rec.map(|rec| rec.id))
+    }
+
+    async fn user_ids(&self) -> DbResult<Vec<(i32, String)>> {
+        let users = sqlx::query!("SELECT id, name FROM triox_users")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|rec| (rec.id, rec.name))
+            .collect();
+        Ok(users)
+    }
+
+    async fn name_exists(&self, name: &str) -> DbResult<bool> {
+        let rec = sqlx::query!(
+            "SELECT EXISTS (SELECT 1 from triox_users WHERE name = $1)",
+            name,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(rec.exists.unwrap_or(false))
+    }
+
+    async fn email_exists(&self, email: &str) -> DbResult<bool> {
+        let rec = sqlx::query!(
+            "SELECT EXISTS (SELECT 1 from triox_users WHERE email = $1)",
+            email,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(rec.exists.unwrap_or(false))
+    }
+
+    async fn account(&self, id: i32) -> DbResult<Option<Account>> {
+        let rec = sqlx::query!(
+            "SELECT id, role, status, provider FROM triox_users WHERE id = $1",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(rec.map(|rec| Account {
+            id: rec.id,
+            role: Role::from_db(rec.role),
+            status: rec.status,
+            provider: rec.provider,
+        }))
+    }
+
+    async fn account_by_name(&self, name: &str) -> DbResult<Option<Account>> {
+        let rec = sqlx::query!(
+            "SELECT id, role, status, provider FROM triox_users WHERE name = $1",
+            name
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(rec.map(|rec| Account {
+            id: rec.id,
+            role: Role::from_db(rec.role),
+            status: rec.status,
+            provider: rec.provider,
+        }))
+    }
+
+    async fn credentials_by_name(&self, name: &str) -> DbResult<Option<Credentials>> {
+        sqlx::query_as!(
+            Credentials,
+            r#"SELECT id, password, email_verified, status, provider
+            FROM triox_users WHERE name = ($1)"#,
+            name,
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn credentials_by_email(&self, email: &str) -> DbResult<Option<Credentials>> {
+        sqlx::query_as!(
+            Credentials,
+            r#"SELECT id, password, email_verified, status, provider
+            FROM triox_users WHERE email = ($1)"#,
+            email,
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn password(&self, id: i32) -> DbResult<String> {
+        let rec = sqlx::query!("SELECT password FROM triox_users WHERE id = ($1)", id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(rec.password)
+    }
+
+    async fn contact(&self, id: i32) -> DbResult<Contact> {
+        sqlx::query_as!(
+            Contact,
+            "SELECT name, email, email_verified, locale FROM triox_users WHERE id = ($1)",
+            id,
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn limits(&self, id: i32) -> DbResult<Option<Limits>> {
+        sqlx::query_as!(
+            Limits,
+            "SELECT quota, bandwidth FROM triox_users WHERE id = $1",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn users(
+        &self,
+        pattern: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> DbResult<Vec<UserRecord>> {
+        let users = sqlx::query!(
+            "SELECT id, name, email, email_verified, role, status, quota, bandwidth,
+            locked_until > NOW() AS login_blocked
+            FROM triox_users
+            WHERE $1::TEXT IS NULL OR name ILIKE $1 OR email ILIKE $1
+            ORDER BY id LIMIT $2 OFFSET $3",
+            pattern,
+            limit,
+            offset,
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|rec| UserRecord {
+            id: rec.id,
+            name: rec.name,
+            email: rec.email,
+            email_verified: rec.email_verified,
+            role: Role::from_db(rec.role),
+            status: rec.status,
+            quota: rec.quota,
+            bandwidth: rec.bandwidth,
+            login_blocked: rec.login_blocked.unwrap_or(false),
+        })
+        .collect();
+        Ok(users)
+    }
+
+    async fn set_name(&self, id: i32, name: &str) -> DbResult<bool> {
+        let res =
+            sqlx::query!("UPDATE triox_users set name = $1 WHERE id = $2", name, id,)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_email(&self, id: i32, email: &str) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_users set email = $1,
+            email_verified = COALESCE(email = $1::VARCHAR AND email_verified, FALSE)
+            WHERE id = $2",
+            email,
+            id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn verify_email(
+        &self,
+        id: i32,
+        email: &str,
+        unverified_status: &str,
+    ) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_users SET email_verified = TRUE, status = NULLIF(status, $3)
+            WHERE id = $1 AND email = $2",
+            id,
+            email,
+            unverified_status,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_password(&self, id: i32, hash: &str) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_users SET password = $1 WHERE id = $2",
+            hash,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_role(&self, id: i32, role: Role) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_users SET role = $1 WHERE id = $2",
+            role.to_db(),
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_status(&self, id: i32, status: Option<&str>) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_users SET status = $1 WHERE id = $2",
+            status,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn lock_user(&self, id: i32) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_users
+            SET status = CASE WHEN status IN ($2, $3) THEN $3 ELSE $4 END
+            WHERE id = $1",
+            id,
+            STATUS_UNVERIFIED,
+            STATUS_LOCKED_UNVERIFIED,
+            STATUS_LOCKED,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn unlock_user(&self, id: i32) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_users
+            SET status = CASE WHEN status = $2 AND NOT email_verified THEN $3
+            WHEN status IN ($2, $4) THEN NULL ELSE status END
+            WHERE id = $1",
+            id,
+            STATUS_LOCKED_UNVERIFIED,
+            STATUS_UNVERIFIED,
+            STATUS_LOCKED,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_quota(&self, id: i32, quota: Option<i64>) -> DbResult<bool> {
+        let res =
+            sqlx::query!("UPDATE triox_users SET quota = $1 WHERE id = $2", quota, id)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_bandwidth(&self, id: i32, bandwidth: Option<i64>) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_users SET bandwidth = $1 WHERE id = $2",
+            bandwidth,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn login_state(&self, login: &str) -> DbResult<Option<(i32, bool)>> {
+        let rec = sqlx::query!(
+            "SELECT id, locked_until > NOW() AS blocked FROM triox_users
+            WHERE name = $1 OR email = $1",
+            login,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(rec.map(|rec| (rec.id, rec.blocked.unwrap_or(false))))
+    }
+
+    async fn add_failed_login(&self, login: &str) -> DbResult<Option<(i32, i32)>> {
+        let rec = sqlx::query!(
+            "UPDATE triox_users SET failed_logins = failed_logins + 1
+            WHERE (name = $1 OR email = $1)
+            AND (locked_until IS NULL OR locked_until <= NOW())
+            RETURNING id, failed_logins",
+            login,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(rec.map(|rec| (rec.id, rec.failed_logins)))
+    }
+
+    async fn remove_failed_login(&self, id: i32) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE triox_users SET failed_logins = GREATEST(failed_logins - 1, 0)
+            WHERE id = $1",
+            id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn block_login(&self, id: i32, seconds: u64, reset: bool) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE triox_users SET locked_until = NOW() + $2 * INTERVAL '1 second',
+            failed_logins = CASE WHEN $3 THEN 0 ELSE failed_logins END
+            WHERE id = $1",
+            id,
+            seconds as f64,
+            reset,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn reset_failed_logins(&self, id: i32) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE triox_users SET failed_logins = 0, locked_until = NULL WHERE id = $1",
+            id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_invite(
+        &self,
+        code: &str,
+        user_id: i32,
+        limit: i64,
+    ) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "INSERT INTO triox_invites (code, created_by)
+            SELECT $1, $2
+            WHERE (SELECT COUNT(*) FROM triox_invites WHERE created_by = $2) < $3",
+            code,
+            user_id,
+            limit,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn invites(&self, user_id: i32) -> DbResult<Vec<Invite>> {
+        let invites = sqlx::query!(
+            "SELECT code, used_by FROM triox_invites
+            WHERE created_by = $1 ORDER BY created_at",
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|rec| Invite {
+            code: rec.code,
+            used: rec.used_by.is_some(),
+        })
+        .collect();
+        Ok(invites)
+    }
+
+    async fn claim_invite(&self, code: &str) -> DbResult<bool> {
+        let res = sqlx::query!(
+            "UPDATE triox_invites SET used_at = NOW() WHERE code = $1 AND used_at IS NULL",
+            code,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn release_invite(&self, code: &str) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE triox_invites SET used_at = NULL WHERE code = $1 AND used_by IS NULL",
+            code,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn redeem_invite(&self, code: &str, user_id: i32) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE triox_invites SET used_by = $1 WHERE code = $2",
+            user_id,
+            code,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn oidc_user(&self, provider: &str, subject: &str) -> DbResult<Option<i32>> {
+        let rec = sqlx::query!(
+            "SELECT user_id FROM triox_oidc_identities WHERE provider = $1 AND subject = $2",
+            provider,
+            subject,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(rec.map(|rec| rec.user_id))
+    }
+
+    async fn link_oidc_user(
+        &self,
+        provider: &str,
+        subject: &str,
+        user_id: i32,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "INSERT INTO triox_oidc_identities (provider, subject, user_id)
+            VALUES ($1, $2, $3)",
+            provider,
+            subject,
+            user_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn user_id_by_verified_email(&self, email: &str) -> DbResult<Option<i32>> {
+        let rec = sqlx::query!(
+            "SELECT id FROM triox_users WHERE email = $1 AND email_verified = TRUE",
+            email,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(rec.map(|rec| rec.id))
+    }
+
+    async fn add_audit_entry(&self, entry: &NewAuditEntry<'_>) -> DbResult<()> {
+        sqlx::query!(
+            "INSERT INTO triox_audit_log (user_id, ip, event, path, detail, success, error)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            entry.user_id,
+            entry.ip,
+            entry.event,
+            entry.path,
+            entry.detail,
+            entry.error.is_none(),
+            entry.error,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn audit_entries(
+        &self,
+        query: &AuditQuery,
+        limit: i64,
+        offset: i64,
+    ) -> DbResult<Vec<AuditEntry>> {
+        sqlx::query_as!(
+            AuditEntry,
+            r#"SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT AS "timestamp!",
+            user_id, ip, event, path, detail, success, error
+            FROM triox_audit_log
+            WHERE ($1::INTEGER IS NULL OR user_id = $1)
+            AND ($2::VARCHAR IS NULL OR event = $2)
+            AND ($3::BIGINT IS NULL OR created_at >= TO_TIMESTAMP($3))
+            AND ($4::BIGINT IS NULL OR created_at < TO_TIMESTAMP($4))
+            ORDER BY id DESC LIMIT $5 OFFSET $6"#,
+            query.user_id,
+            query.event.map(Event::as_str),
+            query.since,
+            query.until,
+            limit,
+            offset,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn export_audit_entries(
+        &self,
+        query: &AuditQuery,
+        after: i64,
+        limit: i64,
+    ) -> DbResult<Vec<AuditEntry>> {
+        sqlx::query_as!(
+            AuditEntry,
+            r#"SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT AS "timestamp!",
+            user_id, ip, event, path, detail, success, error
+            FROM triox_audit_log
+            WHERE ($1::INTEGER IS NULL OR user_id = $1)
+            AND ($2::VARCHAR IS NULL OR event = $2)
+            AND ($3::BIGINT IS NULL OR created_at >= TO_TIMESTAMP($3))
+            AND ($4::BIGINT IS NULL OR created_at < TO_TIMESTAMP($4))
+            AND id > $5
+            ORDER BY id LIMIT $6"#,
+            query.user_id,
+            query.event.map(Event::as_str),
+            query.since,
+            query.until,
+            after,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+}
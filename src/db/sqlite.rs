@@ -0,0 +1,816 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Embedded SQLite backend for small installations without a database server.
+//!
+//! The offline data of the query macros only covers one database, so the
+//! queries of this backend aren't checked at compile time. They are covered
+//! by the tests instead. Timestamps are stored as UNIX timestamps in seconds.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow,
+};
+use sqlx::{Connection, Row, SqlitePool};
+
+use super::*;
+use crate::api::v1::admin::{
+    STATUS_LOCKED, STATUS_LOCKED_UNVERIFIED, STATUS_UNVERIFIED,
+};
+use crate::audit::Event;
+use crate::migrate::{self, Migration, Status};
+use crate::tokens::now;
+
+/// Migrations of the SQLite schema
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite/");
+
+pub struct Sqlite {
+    pool: SqlitePool,
+}
+
+impl Sqlite {
+    /// The database file is created if it doesn't exist yet
+    pub async fn connect(url: &str, pool: u32) -> DbResult<Self> {
+        // readers don't block the writer in WAL mode, concurrent writers
+        // wait for each other
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new()
+            .max_connections(pool)
+            .connect_with(options)
+            .await?;
+        Ok(Self { pool })
+    }
+}
+
+fn timestamp() -> i64 {
+    now() as i64
+}
+
+fn account(row: SqliteRow) -> DbResult<Account> {
+    Ok(Account {
+        id: row.try_get("id")?,
+        role: Role::from_db(row.try_get("role")?),
+        status: row.try_get("status")?,
+        provider: row.try_get("provider")?,
+    })
+}
+
+fn credentials(row: SqliteRow) -> DbResult<Credentials> {
+    Ok(Credentials {
+        id: row.try_get("id")?,
+        password: row.try_get("password")?,
+        email_verified: row.try_get("email_verified")?,
+        status: row.try_get("status")?,
+        provider: row.try_get("provider")?,
+    })
+}
+
+fn audit_entry(row: SqliteRow) -> DbResult<AuditEntry> {
+    Ok(AuditEntry {
+        id: row.try_get("id")?,
+        timestamp: row.try_get("created_at")?,
+        user_id: row.try_get("user_id")?,
+        ip: row.try_get("ip")?,
+        event: row.try_get("event")?,
+        path: row.try_get("path")?,
+        detail: row.try_get("detail")?,
+        success: row.try_get("success")?,
+        error: row.try_get("error")?,
+    })
+}
+
+#[async_trait]
+impl Database for Sqlite {
+    async fn ping(&self) -> DbResult<()> {
+        self.pool.acquire().await?.ping().await
+    }
+
+    fn size(&self) -> u32 {
+        self.pool.size()
+    }
+
+    fn num_idle(&self) -> usize {
+        self.pool.num_idle()
+    }
+
+    fn migrator(&self) -> &'static Migrator {
+        &MIGRATOR
+    }
+
+    async fn migration_status(&self) -> Result<Status, MigrateError> {
+        let mut con = self.pool.acquire().await?;
+        migrate::status_of(&mut *con, &MIGRATOR).await
+    }
+
+    // the database file belongs to a single instance, there is nobody to
+    // coordinate with
+    async fn migrate(&self, dry_run: bool) -> Result<Vec<Migration>, MigrateError> {
+        let mut con = self.pool.acquire().await?;
+        let status = migrate::status_of(&mut *con, &MIGRATOR).await?;
+        if let Some(version) = status.dirty {
+            return Err(MigrateError::Dirty(version));
+        }
+        if !dry_run && !status.pending.is_empty() {
+            MIGRATOR.run(&mut *con).await?;
+        }
+        Ok(status.pending)
+    }
+
+    async fn create_user(&self, user: &NewUser<'_>) -> DbResult<i32> {
+        sqlx::query(
+            "INSERT INTO triox_users
+            (name, password, email, email_verified, role, provider, status)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING id",
+        )
+        .bind(user.name)
+        .bind(user.password)
+        .bind(user.email)
+        .bind(user.email_verified)
+        .bind(user.role.to_db())
+        .bind(user.provider)
+        .bind(user.status)
+        .fetch_one(&self.pool)
+        .await?
+        .try_get("id")
+    }
+
+    async fn delete_user(&self, id: i32) -> DbResult<bool> {
+        let res = sqlx::query("DELETE FROM triox_users WHERE id = ?1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn user_id(&self, name: &str) -> DbResult<Option<i32>> {
+        sqlx::query("SELECT id FROM triox_users WHERE name = ?1")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| row.try_get("id"))
+            .transpose()
+    }
+
+    async fn user_ids(&self) -> DbResult<Vec<(i32, String)>> {
+        sqlx::query("SELECT id, name FROM triox_users")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|row| {
+                Ok((
+                    row.try_get::<i32, _>("id")?,
+                    row.try_get::<String, _>("name")?,
+                ))
+            })
+            .collect()
+    }
+
+    async fn name_exists(&self, name: &str) -> DbResult<bool> {
+        sqlx::query("SELECT EXISTS (SELECT 1 from triox_users WHERE name = ?1) AS found")
+            .bind(name)
+            .fetch_one(&self.pool)
+            .await?
+            .try_get("found")
+    }
+
+    async fn email_exists(&self, email: &str) -> DbResult<bool> {
+        sqlx::query(
+            "SELECT EXISTS (SELECT 1 from triox_users WHERE email = ?1) AS found",
+        )
+        .bind(email)
+        .fetch_one(&self.pool)
+        .await?
+        .try_get("found")
+    }
+
+    async fn account(&self, id: i32) -> DbResult<Option<Account>> {
+        sqlx::query("SELECT id, role, status, provider FROM triox_users WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(account)
+            .transpose()
+    }
+
+    async fn account_by_name(&self, name: &str) -> DbResult<Option<Account>> {
+        sqlx::query("SELECT id, role, status, provider FROM triox_users WHERE name = ?1")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(account)
+            .transpose()
+    }
+
+    async fn credentials_by_name(&self, name: &str) -> DbResult<Option<Credentials>> {
+        sqlx::query(
+            "SELECT id, password, email_verified, status, provider
+            FROM triox_users WHERE name = ?1",
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(credentials)
+        .transpose()
+    }
+
+    async fn credentials_by_email(&self, email: &str) -> DbResult<Option<Credentials>> {
+        sqlx::query(
+            "SELECT id, password, email_verified, status, provider
+            FROM triox_users WHERE email = ?1",
+        )
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(credentials)
+        .transpose()
+    }
+
+    async fn password(&self, id: i32) -> DbResult<String> {
+        sqlx::query("SELECT password FROM triox_users WHERE id = ?1")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await?
+            .try_get("password")
+    }
+
+    async fn contact(&self, id: i32) -> DbResult<Contact> {
+        let row = sqlx::query(
+            "SELECT name, email, email_verified, locale FROM triox_users WHERE id = ?1",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Contact {
+            name: row.try_get("name")?,
+            email: row.try_get("email")?,
+            email_verified: row.try_get("email_verified")?,
+            locale: row.try_get("locale")?,
+        })
+    }
+
+    async fn limits(&self, id: i32) -> DbResult<Option<Limits>> {
+        sqlx::query("SELECT quota, bandwidth FROM triox_users WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| {
+                Ok(Limits {
+                    quota: row.try_get("quota")?,
+                    bandwidth: row.try_get("bandwidth")?,
+                })
+            })
+            .transpose()
+    }
+
+    async fn users(
+        &self,
+        pattern: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> DbResult<Vec<UserRecord>> {
+        // LIKE ignores the case of ASCII characters like ILIKE in Postgres
+        sqlx::query(
+            r#"SELECT id, name, email, email_verified, role, status, quota, bandwidth,
+            locked_until > ?4 AS login_blocked
+            FROM triox_users
+            WHERE ?1 IS NULL OR name LIKE ?1 ESCAPE '\' OR email LIKE ?1 ESCAPE '\'
+            ORDER BY id LIMIT ?2 OFFSET ?3"#,
+        )
+        .bind(pattern)
+        .bind(limit)
+        .bind(offset)
+        .bind(timestamp())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(UserRecord {
+                id: row.try_get("id")?,
+                name: row.try_get("name")?,
+                email: row.try_get("email")?,
+                email_verified: row.try_get("email_verified")?,
+                role: Role::from_db(row.try_get("role")?),
+                status: row.try_get("status")?,
+                quota: row.try_get("quota")?,
+                bandwidth: row.try_get("bandwidth")?,
+                login_blocked: row
+                    .try_get::<Option<bool>, _>("login_blocked")?
+                    .unwrap_or(false),
+            })
+        })
+        .collect()
+    }
+
+    async fn set_name(&self, id: i32, name: &str) -> DbResult<bool> {
+        let res = sqlx::query("UPDATE triox_users SET name = ?1 WHERE id = ?2")
+            .bind(name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_email(&self, id: i32, email: &str) -> DbResult<bool> {
+        let res = sqlx::query(
+            "UPDATE triox_users SET email = ?1,
+            email_verified = COALESCE(email = ?1 AND email_verified, FALSE)
+            WHERE id = ?2",
+        )
+        .bind(email)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn verify_email(
+        &self,
+        id: i32,
+        email: &str,
+        unverified_status: &str,
+    ) -> DbResult<bool> {
+        let res = sqlx::query(
+            "UPDATE triox_users SET email_verified = TRUE, status = NULLIF(status, ?3)
+            WHERE id = ?1 AND email = ?2",
+        )
+        .bind(id)
+        .bind(email)
+        .bind(unverified_status)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_password(&self, id: i32, hash: &str) -> DbResult<bool> {
+        let res = sqlx::query("UPDATE triox_users SET password = ?1 WHERE id = ?2")
+            .bind(hash)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_role(&self, id: i32, role: Role) -> DbResult<bool> {
+        let res = sqlx::query("UPDATE triox_users SET role = ?1 WHERE id = ?2")
+            .bind(role.to_db())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_status(&self, id: i32, status: Option<&str>) -> DbResult<bool> {
+        let res = sqlx::query("UPDATE triox_users SET status = ?1 WHERE id = ?2")
+            .bind(status)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn lock_user(&self, id: i32) -> DbResult<bool> {
+        let res = sqlx::query(
+            "UPDATE triox_users
+            SET status = CASE WHEN status IN (?2, ?3) THEN ?3 ELSE ?4 END
+            WHERE id = ?1",
+        )
+        .bind(id)
+        .bind(STATUS_UNVERIFIED)
+        .bind(STATUS_LOCKED_UNVERIFIED)
+        .bind(STATUS_LOCKED)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn unlock_user(&self, id: i32) -> DbResult<bool> {
+        let res = sqlx::query(
+            "UPDATE triox_users
+            SET status = CASE WHEN status = ?2 AND NOT email_verified THEN ?3
+            WHEN status IN (?2, ?4) THEN NULL ELSE status END
+            WHERE id = ?1",
+        )
+        .bind(id)
+        .bind(STATUS_LOCKED_UNVERIFIED)
+        .bind(STATUS_UNVERIFIED)
+        .bind(STATUS_LOCKED)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_quota(&self, id: i32, quota: Option<i64>) -> DbResult<bool> {
+        let res = sqlx::query("UPDATE triox_users SET quota = ?1 WHERE id = ?2")
+            .bind(quota)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_bandwidth(&self, id: i32, bandwidth: Option<i64>) -> DbResult<bool> {
+        let res = sqlx::query("UPDATE triox_users SET bandwidth = ?1 WHERE id = ?2")
+            .bind(bandwidth)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn login_state(&self, login: &str) -> DbResult<Option<(i32, bool)>> {
+        sqlx::query(
+            "SELECT id, locked_until > ?2 AS blocked FROM triox_users
+            WHERE name = ?1 OR email = ?1",
+        )
+        .bind(login)
+        .bind(timestamp())
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| {
+            let blocked: Option<bool> = row.try_get("blocked")?;
+            Ok((row.try_get::<i32, _>("id")?, blocked.unwrap_or(false)))
+        })
+        .transpose()
+    }
+
+    async fn add_failed_login(&self, login: &str) -> DbResult<Option<(i32, i32)>> {
+        sqlx::query(
+            "UPDATE triox_users SET failed_logins = failed_logins + 1
+            WHERE (name = ?1 OR email = ?1)
+            AND (locked_until IS NULL OR locked_until <= ?2)
+            RETURNING id, failed_logins",
+        )
+        .bind(login)
+        .bind(timestamp())
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| Ok((row.try_get("id")?, row.try_get("failed_logins")?)))
+        .transpose()
+    }
+
+    async fn remove_failed_login(&self, id: i32) -> DbResult<()> {
+        sqlx::query(
+            "UPDATE triox_users SET failed_logins = MAX(failed_logins - 1, 0)
+            WHERE id = ?1",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn block_login(&self, id: i32, seconds: u64, reset: bool) -> DbResult<()> {
+        let until = timestamp().saturating_add(seconds.min(i64::MAX as u64) as i64);
+        sqlx::query(
+            "UPDATE triox_users SET locked_until = ?2,
+            failed_logins = CASE WHEN ?3 THEN 0 ELSE failed_logins END
+            WHERE id = ?1",
+        )
+        .bind(id)
+        .bind(until)
+        .bind(reset)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn reset_failed_logins(&self, id: i32) -> DbResult<()> {
+        sqlx::query(
+            "UPDATE triox_users SET failed_logins = 0, locked_until = NULL WHERE id = ?1",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_invite(
+        &self,
+        code: &str,
+        user_id: i32,
+        limit: i64,
+    ) -> DbResult<bool> {
+        let res = sqlx::query(
+            "INSERT INTO triox_invites (code, created_by)
+            SELECT ?1, ?2
+            WHERE (SELECT COUNT(*) FROM triox_invites WHERE created_by = ?2) < ?3",
+        )
+        .bind(code)
+        .bind(user_id)
+        .bind(limit)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn invites(&self, user_id: i32) -> DbResult<Vec<Invite>> {
+        // invites created within the same second keep their insertion order
+        sqlx::query(
+            "SELECT code, used_by FROM triox_invites
+            WHERE created_by = ?1 ORDER BY created_at, rowid",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(Invite {
+                code: row.try_get("code")?,
+                used: row.try_get::<Option<i32>, _>("used_by")?.is_some(),
+            })
+        })
+        .collect()
+    }
+
+    async fn claim_invite(&self, code: &str) -> DbResult<bool> {
+        let res = sqlx::query(
+            "UPDATE triox_invites SET used_at = ?2 WHERE code = ?1 AND used_at IS NULL",
+        )
+        .bind(code)
+        .bind(timestamp())
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn release_invite(&self, code: &str) -> DbResult<()> {
+        sqlx::query(
+            "UPDATE triox_invites SET used_at = NULL WHERE code = ?1 AND used_by IS NULL",
+        )
+        .bind(code)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn redeem_invite(&self, code: &str, user_id: i32) -> DbResult<()> {
+        sqlx::query("UPDATE triox_invites SET used_by = ?1 WHERE code = ?2")
+            .bind(user_id)
+            .bind(code)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn oidc_user(&self, provider: &str, subject: &str) -> DbResult<Option<i32>> {
+        sqlx::query(
+            "SELECT user_id FROM triox_oidc_identities WHERE provider = ?1 AND subject = ?2",
+        )
+        .bind(provider)
+        .bind(subject)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| row.try_get("user_id"))
+        .transpose()
+    }
+
+    async fn link_oidc_user(
+        &self,
+        provider: &str,
+        subject: &str,
+        user_id: i32,
+    ) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO triox_oidc_identities (provider, subject, user_id)
+            VALUES (?1, ?2, ?3)",
+        )
+        .bind(provider)
+        .bind(subject)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn user_id_by_verified_email(&self, email: &str) -> DbResult<Option<i32>> {
+        sqlx::query(
+            "SELECT id FROM triox_users WHERE email = ?1 AND email_verified = TRUE",
+        )
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| row.try_get("id"))
+        .transpose()
+    }
+
+    async fn add_audit_entry(&self, entry: &NewAuditEntry<'_>) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO triox_audit_log (user_id, ip, event, path, detail, success, error)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )
+        .bind(entry.user_id)
+        .bind(entry.ip)
+        .bind(entry.event)
+        .bind(entry.path)
+        .bind(entry.detail)
+        .bind(entry.error.is_none())
+        .bind(entry.error)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn audit_entries(
+        &self,
+        query: &AuditQuery,
+        limit: i64,
+        offset: i64,
+    ) -> DbResult<Vec<AuditEntry>> {
+        sqlx::query(
+            "SELECT id, created_at, user_id, ip, event, path, detail, success, error
+            FROM triox_audit_log
+            WHERE (?1 IS NULL OR user_id = ?1)
+            AND (?2 IS NULL OR event = ?2)
+            AND (?3 IS NULL OR created_at >= ?3)
+            AND (?4 IS NULL OR created_at < ?4)
+            ORDER BY id DESC LIMIT ?5 OFFSET ?6",
+        )
+        .bind(query.user_id)
+        .bind(query.event.map(Event::as_str))
+        .bind(query.since)
+        .bind(query.until)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(audit_entry)
+        .collect()
+    }
+
+    async fn export_audit_entries(
+        &self,
+        query: &AuditQuery,
+        after: i64,
+        limit: i64,
+    ) -> DbResult<Vec<AuditEntry>> {
+        sqlx::query(
+            "SELECT id, created_at, user_id, ip, event, path, detail, success, error
+            FROM triox_audit_log
+            WHERE (?1 IS NULL OR user_id = ?1)
+            AND (?2 IS NULL OR event = ?2)
+            AND (?3 IS NULL OR created_at >= ?3)
+            AND (?4 IS NULL OR created_at < ?4)
+            AND id > ?5
+            ORDER BY id LIMIT ?6",
+        )
+        .bind(query.user_id)
+        .bind(query.event.map(Event::as_str))
+        .bind(query.since)
+        .bind(query.until)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(audit_entry)
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// every connection to an in-memory database has its own database
+    async fn memory() -> Sqlite {
+        let db = Sqlite::connect("sqlite::memory:", 1).await.unwrap();
+        db.migrate(false).await.unwrap();
+        db
+    }
+
+    #[actix_rt::test]
+    async fn sqlite_works() {
+        let db = memory().await;
+        assert!(db.migration_status().await.unwrap().is_up_to_date());
+        db.ping().await.unwrap();
+
+        let user = NewUser {
+            name: "sqliteuser",
+            password: "hash",
+            email: Some("sqlite@example.com"),
+            email_verified: false,
+            role: Role::User,
+            provider: "local",
+            status: None,
+        };
+        let id = db.create_user(&user).await.unwrap();
+        let err = db.create_user(&user).await.unwrap_err();
+        assert!(violates_unique(&err, "triox_users", "name"));
+        assert!(db.name_exists("sqliteuser").await.unwrap());
+        assert!(!db.email_exists("other@example.com").await.unwrap());
+
+        // wildcards are matched literally
+        let users = db.users(Some("%SQLITE%"), 10, 0).await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert!(!users[0].login_blocked);
+        assert!(db.users(Some("%\\_%"), 10, 0).await.unwrap().is_empty());
+
+        assert!(db
+            .verify_email(id, "sqlite@example.com", "unverified")
+            .await
+            .unwrap());
+        assert!(db.set_email(id, "sqlite@example.com").await.unwrap());
+        assert!(db.contact(id).await.unwrap().email_verified);
+        assert_eq!(
+            db.user_id_by_verified_email("sqlite@example.com")
+                .await
+                .unwrap(),
+            Some(id)
+        );
+
+        assert_eq!(
+            db.add_failed_login("sqliteuser").await.unwrap(),
+            Some((id, 1))
+        );
+        db.block_login(id, 60, true).await.unwrap();
+        assert_eq!(
+            db.login_state("sqliteuser").await.unwrap(),
+            Some((id, true))
+        );
+        // attempts on blocked accounts aren't counted
+        assert_eq!(db.add_failed_login("sqliteuser").await.unwrap(), None);
+        db.reset_failed_logins(id).await.unwrap();
+        assert_eq!(
+            db.login_state("sqlite@example.com").await.unwrap(),
+            Some((id, false))
+        );
+        assert_eq!(
+            db.add_failed_login("sqlite@example.com").await.unwrap(),
+            Some((id, 1))
+        );
+        db.remove_failed_login(id).await.unwrap();
+        db.remove_failed_login(id).await.unwrap();
+        assert_eq!(
+            db.add_failed_login("sqliteuser").await.unwrap(),
+            Some((id, 1))
+        );
+        assert_eq!(db.add_failed_login("nobody").await.unwrap(), None);
+
+        // unlocking keeps other statuses, the address was verified already
+        assert!(db.set_status(id, Some(STATUS_UNVERIFIED)).await.unwrap());
+        assert!(db.unlock_user(id).await.unwrap());
+        let status = db.account(id).await.unwrap().unwrap().status;
+        assert_eq!(status.as_deref(), Some(STATUS_UNVERIFIED));
+        assert!(db.lock_user(id).await.unwrap());
+        assert!(db.unlock_user(id).await.unwrap());
+        assert_eq!(db.account(id).await.unwrap().unwrap().status, None);
+
+        assert!(db.create_invite("code", id, 1).await.unwrap());
+        assert!(!db.create_invite("other", id, 1).await.unwrap());
+        assert!(db.claim_invite("code").await.unwrap());
+        assert!(!db.claim_invite("code").await.unwrap());
+        db.release_invite("code").await.unwrap();
+        assert!(db.claim_invite("code").await.unwrap());
+
+        db.add_audit_entry(&NewAuditEntry {
+            user_id: Some(id),
+            ip: None,
+            event: Event::Login.as_str(),
+            path: None,
+            detail: None,
+            error: Some("failed"),
+        })
+        .await
+        .unwrap();
+        let query = AuditQuery {
+            user_id: Some(id),
+            since: Some(timestamp() - 60),
+            ..AuditQuery::default()
+        };
+        let entries = db.audit_entries(&query, 10, 0).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(!entries[0].success);
+        assert!(db
+            .export_audit_entries(&query, entries[0].id, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // identities and invites are removed with the user
+        db.link_oidc_user("idp", "subject", id).await.unwrap();
+        assert!(db.delete_user(id).await.unwrap());
+        assert_eq!(db.oidc_user("idp", "subject").await.unwrap(), None);
+        assert!(db.invites(id).await.unwrap().is_empty());
+    }
+}
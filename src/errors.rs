@@ -44,12 +44,43 @@ pub enum ServiceError {
     AccountNotFound,
     #[display(fmt = "Passwords don't match")]
     PasswordsDontMatch,
+    #[display(fmt = "Email address not verified")]
+    EmailNotVerified,
+    #[display(fmt = "Invalid or expired token")]
+    InvalidToken,
+    #[display(fmt = "Email delivery is not available")]
+    MailerUnavailable,
+    #[display(fmt = "Admin privileges required")]
+    AdminRequired,
+    #[display(fmt = "Account locked")]
+    AccountLocked,
+    #[display(fmt = "Storage quota exceeded")]
+    QuotaExceeded,
+    #[display(fmt = "Registration is closed")]
+    ClosedForRegistration,
+    #[display(fmt = "Invalid invite code")]
+    InvalidInvite,
+    #[display(fmt = "No invites left")]
+    InviteLimitReached,
+    #[display(fmt = "Authentication service unavailable")]
+    AuthProviderUnavailable,
+    #[display(fmt = "Too many failed sign in attempts, try again later")]
+    TooManyLoginAttempts,
+    #[display(fmt = "Too many requests, try again later")]
+    RateLimited,
+    #[display(fmt = "Invalid configuration")]
+    InvalidConfiguration,
+    #[display(fmt = "Missing or invalid CSRF token")]
+    InvalidCsrfToken,
 }
 
 #[derive(Serialize)]
 #[cfg(not(tarpaulin_include))]
 struct ErrorToResponse {
     error: String,
+    /// ID of the failed request, see [RequestId](crate::middleware::request_id::RequestId)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 impl ResponseError for ServiceError {
@@ -59,6 +90,7 @@ impl ResponseError for ServiceError {
             .body(
                 serde_json::to_string(&ErrorToResponse {
                     error: self.to_string(),
+                    request_id: crate::logging::request_id(),
                 })
                 .unwrap(),
             )
@@ -79,6 +111,20 @@ impl ResponseError for ServiceError {
             ServiceError::FSReadOnly => StatusCode::METHOD_NOT_ALLOWED,
             ServiceError::InvalidCredentials => StatusCode::UNAUTHORIZED,
             ServiceError::CredentialError(_e) => StatusCode::BAD_REQUEST,
+            ServiceError::EmailNotVerified => StatusCode::FORBIDDEN,
+            ServiceError::InvalidToken => StatusCode::BAD_REQUEST,
+            ServiceError::MailerUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceError::AdminRequired => StatusCode::FORBIDDEN,
+            ServiceError::AccountLocked => StatusCode::FORBIDDEN,
+            ServiceError::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            ServiceError::ClosedForRegistration => StatusCode::FORBIDDEN,
+            ServiceError::InvalidInvite => StatusCode::BAD_REQUEST,
+            ServiceError::InviteLimitReached => StatusCode::FORBIDDEN,
+            ServiceError::AuthProviderUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceError::TooManyLoginAttempts => StatusCode::TOO_MANY_REQUESTS,
+            ServiceError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ServiceError::InvalidConfiguration => StatusCode::UNPROCESSABLE_ENTITY,
+            ServiceError::InvalidCsrfToken => StatusCode::FORBIDDEN,
         }
     }
 }
@@ -115,12 +161,8 @@ impl From<CredsError> for ServiceError {
 impl From<sqlx::Error> for ServiceError {
     #[cfg(not(tarpaulin_include))]
     fn from(e: sqlx::Error) -> Self {
-        use sqlx::error::Error;
-        use std::borrow::Cow;
-        if let Error::Database(err) = e {
-            if err.code() == Some(Cow::from("23505")) {
-                return ServiceError::UsernameTaken;
-            }
+        if crate::db::is_unique_violation(&e) {
+            return ServiceError::UsernameTaken;
         }
         ServiceError::InternalServerError
     }
@@ -0,0 +1,108 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::io::Write;
+
+use env_logger::Env;
+use serde_json::{json, Value};
+
+use crate::config::LogFormat;
+
+tokio::task_local! {
+    /// ID of the request that is currently handled, set by the
+    /// [RequestId](crate::middleware::request_id::RequestId) middleware
+    pub static REQUEST_ID: String;
+}
+
+/// ID of the request that is currently handled, if any
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Sets up the logger, `RUST_LOG` takes precedence over `default_level`.
+pub fn init(default_level: &str, format: LogFormat) {
+    env_logger::Builder::from_env(Env::default().default_filter_or(default_level))
+        .format(move |buf, record| {
+            let timestamp = buf.timestamp().to_string();
+            let request_id = request_id();
+            let line = match format {
+                LogFormat::Text => text_line(&timestamp, record, request_id.as_deref()),
+                LogFormat::Json => json_line(&timestamp, record, request_id.as_deref()),
+            };
+            writeln!(buf, "{}", line)
+        })
+        .init();
+}
+
+fn text_line(timestamp: &str, record: &log::Record, request_id: Option<&str>) -> String {
+    match request_id {
+        Some(id) => format!(
+            "[{} {:<5} {} {}] {}",
+            timestamp,
+            record.level(),
+            record.target(),
+            id,
+            record.args()
+        ),
+        None => format!(
+            "[{} {:<5} {}] {}",
+            timestamp,
+            record.level(),
+            record.target(),
+            record.args()
+        ),
+    }
+}
+
+/// one JSON object per line
+fn json_line(timestamp: &str, record: &log::Record, request_id: Option<&str>) -> String {
+    let mut line = json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let (Some(id), Value::Object(fields)) = (request_id, &mut line) {
+        fields.insert("request_id".into(), id.into());
+    }
+    line.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_line_works() {
+        let line = json_line(
+            "2022-11-06T10:00:00Z",
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("triox::test")
+                .args(format_args!("quota of \"{}\" exceeded", "user"))
+                .build(),
+            Some("abc"),
+        );
+
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["timestamp"], "2022-11-06T10:00:00Z");
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "triox::test");
+        assert_eq!(value["message"], "quota of \"user\" exceeded");
+        assert_eq!(value["request_id"], "abc");
+        assert!(!line.contains('\n'));
+    }
+}
@@ -0,0 +1,296 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Outgoing emails.
+//!
+//! Messages are rendered from [templates] and put into a queue.
+//! A background task delivers them and retries failed deliveries
+//! with an exponential backoff, so request handlers never wait for the SMTP server.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tokio::sync::mpsc;
+
+use crate::config::{MailTransport, Smtp, SmtpEncryption};
+use crate::errors::*;
+
+pub mod templates;
+
+pub use templates::Template;
+
+/// An email waiting for delivery.
+#[derive(Clone, Debug)]
+pub struct Email {
+    /// Recipient address
+    pub to: String,
+    /// Locale of the recipient as stored in `triox_users.locale`
+    pub locale: Option<String>,
+    pub template: Template,
+    /// Values for the placeholders of the template
+    pub vars: Vec<(&'static str, String)>,
+}
+
+#[derive(Clone)]
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+}
+
+impl Transport {
+    fn new(config: &Smtp) -> Result<Self, String> {
+        match config.transport {
+            MailTransport::Smtp => {
+                let host = config
+                    .host
+                    .as_deref()
+                    .ok_or("smtp.host is required for the smtp transport")?;
+
+                let builder = match config.encryption {
+                    SmtpEncryption::None => {
+                        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+                    }
+                    SmtpEncryption::Starttls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+                            .map_err(|e| e.to_string())?
+                    }
+                    SmtpEncryption::Tls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+                            .map_err(|e| e.to_string())?
+                    }
+                };
+
+                let builder = match config.port {
+                    Some(port) => builder.port(port),
+                    None => builder,
+                };
+
+                let builder = match (&config.username, &config.password) {
+                    (Some(username), Some(password)) => builder.credentials(
+                        Credentials::new(username.clone(), password.clone()),
+                    ),
+                    _ => builder,
+                };
+
+                Ok(Transport::Smtp(builder.build()))
+            }
+            MailTransport::File => {
+                let directory = config
+                    .directory
+                    .as_deref()
+                    .ok_or("smtp.directory is required for the file transport")?;
+                std::fs::create_dir_all(directory).map_err(|e| e.to_string())?;
+                Ok(Transport::File(AsyncFileTransport::new(directory)))
+            }
+        }
+    }
+
+    /// Connects to the SMTP server, the file transport is always available.
+    async fn test_connection(&self) -> Result<(), String> {
+        match self {
+            Transport::Smtp(t) => match t.test_connection().await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err("SMTP server rejected the connection".into()),
+                Err(e) => Err(e.to_string()),
+            },
+            Transport::File(_) => Ok(()),
+        }
+    }
+
+    async fn send(&self, message: Message) -> Result<(), String> {
+        match self {
+            Transport::Smtp(t) => {
+                t.send(message).await.map(|_| ()).map_err(|e| e.to_string())
+            }
+            Transport::File(t) => {
+                t.send(message).await.map(|_| ()).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// Handle to the mail queue.
+/// Can be accessed through `AppState::mailer` if `[smtp]` is configured.
+#[derive(Clone)]
+pub struct Mailer {
+    from: Mailbox,
+    queue: mpsc::Sender<Message>,
+    transport: Arc<Transport>,
+}
+
+impl Mailer {
+    /// Creates the transport and spawns the delivery task.
+    /// Must be called from within a tokio runtime.
+    pub fn new(config: &Smtp) -> Result<Self, String> {
+        let from: Mailbox = config.from.parse().map_err(|e| format!("{}", e))?;
+        let transport = Arc::new(Transport::new(config)?);
+        let (queue, receiver) = mpsc::channel(config.queue_size.max(1));
+
+        tokio::spawn(deliver_queue(
+            transport.clone(),
+            receiver,
+            config.max_retries,
+            Duration::from_millis(config.retry_delay),
+        ));
+
+        Ok(Mailer {
+            from,
+            queue,
+            transport,
+        })
+    }
+
+    /// Checks whether the mail server is reachable.
+    pub async fn test_connection(&self) -> Result<(), String> {
+        self.transport.test_connection().await
+    }
+
+    /// Renders the email and puts it into the queue.
+    /// Returns as soon as the email is queued, delivery happens in the background.
+    pub fn send(&self, email: Email) -> ServiceResult<()> {
+        let to: Mailbox = email.to.parse().map_err(|_| ServiceError::NotAnEmail)?;
+        let (subject, body) =
+            email.template.render(email.locale.as_deref(), &email.vars);
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| {
+                log::error!("Unable to build email: {}", e);
+                ServiceError::InternalServerError
+            })?;
+
+        self.queue.try_send(message).map_err(|e| {
+            log::error!("Unable to queue email: {}", e);
+            ServiceError::InternalServerError
+        })
+    }
+}
+
+async fn deliver_queue(
+    transport: Arc<Transport>,
+    mut receiver: mpsc::Receiver<Message>,
+    max_retries: u32,
+    retry_delay: Duration,
+) {
+    while let Some(message) = receiver.recv().await {
+        // deliver concurrently, so a retrying message doesn't hold back the queue
+        tokio::spawn(deliver(
+            transport.clone(),
+            message,
+            max_retries,
+            retry_delay,
+        ));
+    }
+}
+
+async fn deliver(
+    transport: Arc<Transport>,
+    message: Message,
+    max_retries: u32,
+    retry_delay: Duration,
+) {
+    let mut delay = retry_delay;
+    for attempt in 0..=max_retries {
+        match transport.send(message.clone()).await {
+            Ok(()) => return,
+            Err(e) if attempt < max_retries => {
+                log::warn!(
+                    "Email delivery failed (attempt {}/{}), retrying in {:?}: {}",
+                    attempt + 1,
+                    max_retries + 1,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => log::error!("Email delivery failed, giving up: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn file_transport_works() {
+        const DIR: &str = "./data/test-mail";
+        let _ = tokio::fs::remove_dir_all(DIR).await;
+
+        let config = Smtp {
+            transport: MailTransport::File,
+            from: "Triox <noreply@localhost>".into(),
+            host: None,
+            port: None,
+            username: None,
+            password: None,
+            encryption: SmtpEncryption::None,
+            directory: Some(DIR.into()),
+            queue_size: 10,
+            max_retries: 0,
+            retry_delay: 0,
+        };
+        let mailer = Mailer::new(&config).unwrap();
+
+        assert_eq!(
+            mailer.send(Email {
+                to: "not an email".into(),
+                locale: None,
+                template: Template::VerifyEmail,
+                vars: Vec::new(),
+            }),
+            Err(ServiceError::NotAnEmail)
+        );
+
+        mailer
+            .send(Email {
+                to: "mailtest@example.com".into(),
+                locale: Some("en_US".into()),
+                template: Template::VerifyEmail,
+                vars: vec![("username", "mailtest".into()), ("link", "LINK".into())],
+            })
+            .unwrap();
+
+        // wait for the background task
+        let mut delivered = Vec::new();
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut dir = tokio::fs::read_dir(DIR).await.unwrap();
+            while let Some(entry) = dir.next_entry().await.unwrap() {
+                delivered.push(tokio::fs::read_to_string(entry.path()).await.unwrap());
+            }
+            if !delivered.is_empty() {
+                break;
+            }
+        }
+
+        assert_eq!(delivered.len(), 1);
+        assert!(delivered[0].contains("mailtest@example.com"));
+        assert!(delivered[0].contains("Verify your email address"));
+        let _ = tokio::fs::remove_dir_all(DIR).await;
+    }
+}
@@ -0,0 +1,177 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Localized email templates.
+//!
+//! Templates are plain text with `{name}` placeholders that are replaced
+//! by the variables passed along with the message.
+
+/// Language used when the locale of a user is unknown or unsupported.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Kinds of emails sent by Triox.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Template {
+    /// Confirm ownership of an email address.
+    /// Variables: `username`, `link`
+    VerifyEmail,
+    /// Sign in was blocked after too many failed attempts.
+    /// Variables: `username`, `minutes`
+    AccountLocked,
+}
+
+struct Text {
+    subject: &'static str,
+    body: &'static str,
+}
+
+impl Template {
+    fn text(&self, language: &str) -> Option<Text> {
+        match (self, language) {
+            (Template::VerifyEmail, "en") => Some(Text {
+                subject: "Verify your email address",
+                body: "Hello {username},\n\n\
+                       please confirm your email address by opening the link below:\n\n\
+                       {link}\n\n\
+                       If you didn't request this, you can ignore this email.\n",
+            }),
+            (Template::VerifyEmail, "de") => Some(Text {
+                subject: "Bestätige deine E-Mail-Adresse",
+                body: "Hallo {username},\n\n\
+                       bitte bestätige deine E-Mail-Adresse über den folgenden Link:\n\n\
+                       {link}\n\n\
+                       Falls du das nicht angefordert hast, kannst du diese E-Mail ignorieren.\n",
+            }),
+            (Template::AccountLocked, "en") => Some(Text {
+                subject: "Your account was locked",
+                body: "Hello {username},\n\n\
+                       there were too many failed attempts to sign in to your account.\n\
+                       Signing in is blocked for the next {minutes} minutes.\n\n\
+                       If this wasn't you, someone might be guessing your password.\n\
+                       Consider choosing a stronger one.\n",
+            }),
+            (Template::AccountLocked, "de") => Some(Text {
+                subject: "Dein Konto wurde gesperrt",
+                body: "Hallo {username},\n\n\
+                       es gab zu viele fehlgeschlagene Anmeldeversuche für dein Konto.\n\
+                       Die Anmeldung ist für die nächsten {minutes} Minuten gesperrt.\n\n\
+                       Falls du das nicht warst, versucht jemand dein Passwort zu erraten.\n\
+                       Du solltest ein stärkeres Passwort wählen.\n",
+            }),
+            _ => None,
+        }
+    }
+
+    /// Renders subject and body in the language of `locale`,
+    /// falling back to [DEFAULT_LANGUAGE].
+    pub fn render(
+        &self,
+        locale: Option<&str>,
+        vars: &[(&str, String)],
+    ) -> (String, String) {
+        let text = self
+            .text(&language(locale))
+            .or_else(|| self.text(DEFAULT_LANGUAGE))
+            .expect("every template has a default language variant");
+
+        (substitute(text.subject, vars), substitute(text.body, vars))
+    }
+}
+
+/// Extracts the language part of a locale as stored in `triox_users.locale`
+/// (e.g. `de_DE` -> `de`).
+fn language(locale: Option<&str>) -> String {
+    locale
+        .map(|l| l.trim())
+        .and_then(|l| l.split(&['_', '-'][..]).next())
+        .filter(|l| !l.is_empty())
+        .unwrap_or(DEFAULT_LANGUAGE)
+        .to_lowercase()
+}
+
+/// Replaces placeholders in a single pass, so placeholders inside of values
+/// are kept as they are. Unknown placeholders are kept as well.
+fn substitute(text: &str, vars: &[(&str, String)]) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| (value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                output.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_is_extracted_from_locale() {
+        assert_eq!(language(Some("de_DE")), "de");
+        assert_eq!(language(Some("en-US")), "en");
+        assert_eq!(language(Some("fr   ")), "fr");
+        assert_eq!(language(Some("")), DEFAULT_LANGUAGE);
+        assert_eq!(language(None), DEFAULT_LANGUAGE);
+    }
+
+    #[test]
+    fn render_works() {
+        let vars = [
+            ("username", "testuser".to_owned()),
+            ("link", "https://example.com/verify".to_owned()),
+        ];
+
+        let (subject, body) = Template::VerifyEmail.render(Some("de_DE"), &vars);
+        assert_eq!(subject, "Bestätige deine E-Mail-Adresse");
+        assert!(body.starts_with("Hallo testuser,"));
+        assert!(body.contains("https://example.com/verify"));
+
+        // unsupported languages fall back to english
+        let (subject, body) = Template::VerifyEmail.render(Some("fr_FR"), &vars);
+        assert_eq!(subject, "Verify your email address");
+        assert!(!body.contains("{link}"));
+    }
+
+    #[test]
+    fn substitute_works() {
+        let vars = [
+            ("username", "{link}".to_owned()),
+            ("link", "https://example.com/verify".to_owned()),
+        ];
+        assert_eq!(
+            substitute("{username}: {link} {unknown} {", &vars),
+            "{link}: https://example.com/verify {unknown} {"
+        );
+    }
+}
@@ -22,35 +22,65 @@ mod config;
 /// This module defines a configuration struct for Triox that allows more robust and efficient access to configuration.
 mod app_state;
 
+/// Web UI bundled into the binary.
+mod assets;
+
 /// API for authentication. Including sign in, sign out and user information.
 mod auth;
 
+/// Audit log of security relevant events.
+mod audit;
+
 /// Tests.
 #[cfg(test)]
 #[macro_use]
 mod tests;
 
+/// Queries of the application for Postgres and SQLite.
+mod db;
+
 /// errors.
 mod errors;
 
+/// Log output and request IDs.
+mod logging;
+
+/// Outgoing emails with SMTP and file transports.
+mod mailer;
+
+/// Database migrations.
+mod migrate;
+
+/// Prometheus metrics.
+mod metrics;
+
+/// Authentication providers: local passwords, LDAP and OpenID Connect.
+mod providers;
+
+/// TLS certificates, obtained with ACME or loaded from files.
+mod tls;
+
+/// Tokens signed with the server secret.
+mod tokens;
+
 // Cli options
 mod cli;
 
+/// Administrative commands of the command line interface.
+mod commands;
+
 use std::sync::Arc;
 
-use actix_files::NamedFile;
 use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_web::cookie::SameSite;
 use actix_web::{http, web, App, HttpRequest, HttpResponse, HttpServer};
-use env_logger::Env;
-use lazy_static::lazy_static;
-
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 
 use crate::api::v1::ROUTES as V1_API_ROUTES;
 use crate::apps::files::FILE_ROUTES;
-use crate::config::AppConfig;
+use crate::config::{AcmeChallenge, AppConfig};
 
 pub use crate::app_state::AppState;
+pub use crate::middleware::admin::RequireAdmin;
 pub use crate::middleware::auth::CheckLogin;
 
 pub type AppData = actix_web::web::Data<Arc<AppState>>;
@@ -61,16 +91,13 @@ pub const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 pub const PKG_DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 pub const PKG_HOMEPAGE: &str = env!("CARGO_PKG_HOMEPAGE");
 
-lazy_static! {
-    pub static ref SETTINGS: AppConfig = {
-        let cli_options = cli::Options::new();
-        AppConfig::new(cli_options.config_dir.as_ref()).unwrap()
-    };
-}
+/// Format of the default `Logger` with the request ID
+const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#;
 
 /// index page
-async fn index(_req: HttpRequest) -> actix_web::Result<NamedFile> {
-    Ok(NamedFile::open("static/index.html")?.set_content_type(mime::TEXT_HTML_UTF_8))
+async fn index(req: HttpRequest, data: AppData) -> actix_web::Result<HttpResponse> {
+    assets::file(&req, &data, "index.html")
 }
 
 #[actix_web::get("/", wrap = "crate::CheckLogin")]
@@ -97,30 +124,169 @@ async fn source_code(_req: HttpRequest) -> HttpResponse {
 async fn main() -> std::io::Result<()> {
     let cli_options = cli::Options::new();
 
+    // reports all problems instead of exiting on invalid configurations
+    if cli_options.command == Some(cli::Command::CheckConfig) {
+        let valid = commands::check_config(&cli_options.config_dir);
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
+    let settings = match AppConfig::load(&cli_options.config_dir) {
+        Ok(settings) => settings,
+        Err(problems) => {
+            commands::print_problems(&cli_options.config_dir, &problems);
+            std::process::exit(1);
+        }
+    };
+
     // setup logger
-    env_logger::Builder::from_env(
-        Env::default().default_filter_or(cli_options.log_level),
-    )
-    .init();
+    logging::init(
+        &cli_options.log_level,
+        cli_options.log_format.unwrap_or(settings.log.format),
+    );
+
+    let app_state = app_state::AppState::new(&cli_options.config_dir, settings).await;
+    // only settings that can't be reloaded are read from here
+    let settings = app_state.settings();
 
-    // initialize static variables to prevent panicking later
-    lazy_static::initialize(&SETTINGS);
-    lazy_static::initialize(&middleware::rate_limit::RATE_LIMIT_CONFIG);
+    // the migrate command applies migrations itself
+    let migrate_command = matches!(
+        cli_options.command,
+        Some(cli::Command::MigrateStatus | cli::Command::MigrateUp { .. })
+    );
+    if settings.database.auto_migrate && !migrate_command {
+        let applied = migrate::up(&*app_state.db, false).await.unwrap();
+        for migration in applied {
+            log::info!(
+                "Applied migration {} {}",
+                migration.version,
+                migration.description
+            );
+        }
+    } else if !migrate_command {
+        match migrate::status(&*app_state.db).await {
+            Ok(status) if !status.is_up_to_date() => log::warn!(
+                "Database has {} pending migrations, run `triox migrate`",
+                status.pending.len()
+            ),
+            Ok(_) => (),
+            Err(e) => log::error!("Unable to check migrations: {}", e),
+        }
+    }
+
+    if let Some(command) = cli_options.command {
+        let data = actix_web::web::Data::new(app_state);
+        let success = commands::run(command, &data).await;
+        std::process::exit(if success { 0 } else { 1 });
+    }
 
-    let app_state = app_state::AppState::new().await;
+    if cli_options.migrate_storage {
+        let migrated =
+            apps::files::storage::migrate_to_id_layout(&*app_state.db).await?;
+        log::info!("Migrated storage of {} users", migrated);
+        return Ok(());
+    }
+
+    // `kill -HUP` reloads the configuration
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+
+        let app_state = app_state.clone();
+        let mut hangup = signal(SignalKind::hangup())?;
+        actix_rt::spawn(async move {
+            while hangup.recv().await.is_some() {
+                log::info!("Received SIGHUP, reloading configuration");
+                app_state.reload_settings();
+            }
+        });
+    }
 
-    sqlx::migrate!("./migrations/")
-        .run(&app_state.db)
-        .await
-        .unwrap();
+    if settings.tls.enabled {
+        match app_state.tls.load(&settings.tls) {
+            Ok(_) => (),
+            // ACME requests a certificate once the server is running
+            Err(e) if settings.tls.acme.is_some() => {
+                log::info!("No TLS certificate loaded yet: {}", e)
+            }
+            Err(e) => panic!("Couldn't load TLS certificate: {}", e),
+        }
+    }
+    if settings.tls.enabled && settings.tls.acme.is_some() {
+        actix_rt::spawn(tls::acme::renew(settings.clone(), app_state.tls.clone()));
+    }
 
     let app_state = actix_web::web::Data::new(app_state);
+    // plain HTTP listeners answer HTTP-01 challenges and redirect to HTTPS
+    let mut http_listeners: Vec<(String, bool, bool)> = Vec::new();
+    match &settings.tls.acme {
+        Some(acme)
+            if settings.tls.enabled && acme.challenge == AcmeChallenge::Http01 =>
+        {
+            http_listeners.push((acme.http_listen.clone(), true, false))
+        }
+        _ => (),
+    }
+    if let Some(listen) = &settings.tls.redirect_listen {
+        match http_listeners
+            .iter_mut()
+            .find(|(address, ..)| address == listen)
+        {
+            Some((_, _, redirect)) => *redirect = true,
+            None => http_listeners.push((listen.clone(), false, true)),
+        }
+    }
+    let mut http_servers = Vec::new();
+    for (listen, challenges, redirect) in http_listeners {
+        let app_state = app_state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_state.clone())
+                .configure(|cfg| {
+                    if challenges {
+                        tls::acme::services(cfg)
+                    }
+                })
+                .configure(|cfg| {
+                    if redirect {
+                        tls::redirect_services(cfg)
+                    }
+                })
+        })
+        .workers(1)
+        .bind(&listen)?
+        .run();
+        http_servers.push(server);
+    }
+    let metrics_server = match &settings.metrics.listen {
+        Some(listen) if settings.metrics.enabled => {
+            let app_state = app_state.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(app_state.clone())
+                    .configure(metrics::services)
+            })
+            .workers(1)
+            .bind(listen)?
+            .run();
+            Some(server)
+        }
+        _ => None,
+    };
+    // metrics are served by the main server unless they have their own address
+    let serve_metrics = settings.metrics.enabled && settings.metrics.listen.is_none();
 
     // setup HTTP server
+    let server_settings = settings.clone();
     let mut server = HttpServer::new(move || {
+        let settings = &server_settings;
         App::new()
             .wrap(actix_web::middleware::Compress::default())
-            .wrap(get_identity_service())
+            .wrap(middleware::csrf::Csrf::new(
+                &settings.server.secret,
+                settings.https(),
+            ))
+            .wrap(get_identity_service(settings))
+            .wrap(middleware::security_headers::security_headers(settings))
             .wrap(
                 actix_web::middleware::ErrorHandlers::new()
                     .handler(http::StatusCode::NOT_FOUND, errors::render_404),
@@ -130,57 +296,67 @@ async fn main() -> std::io::Result<()> {
             ))
             // setup application state extractor
             .app_data(app_state.clone())
-            .wrap(actix_web::middleware::Logger::default())
+            .wrap(middleware::request_id::RequestId)
+            .wrap(actix_web::middleware::Logger::new(ACCESS_LOG_FORMAT))
+            .wrap(actix_web::middleware::Condition::new(
+                settings.metrics.enabled,
+                middleware::metrics::RequestMetrics,
+            ))
             .service(redirect)
             .route("/source", web::get().to(source_code))
             // static pages
             .route("/index", web::get().to(index))
             .route("/sign_in", web::get().to(auth::sign_in_page))
             .route("/sign_up", web::get().to(auth::sign_up_page))
-            // serve static files from ./static/ to /static/
-            .service(actix_files::Files::new("/static", "static"))
+            // serve the bundled files of static/ to /static/
+            .configure(assets::services)
             // setup files API
             .configure(apps::files::services)
             // setup auth API
             .configure(api::v1::services)
+            .configure(|cfg| {
+                if serve_metrics {
+                    metrics::services(cfg)
+                }
+            })
     });
 
-    let listen_address = SETTINGS.server.listen_address();
+    let listen_address = settings.server.listen_address();
 
-    server = if SETTINGS.tls.enabled {
-        let mut ssl_acceptor_builder =
-            SslAcceptor::mozilla_intermediate(SslMethod::tls())
-                .expect("Couldn't create SslAcceptor");
-        ssl_acceptor_builder
-            .set_private_key_file(
-                SETTINGS.tls.key_path.as_ref().unwrap(),
-                SslFiletype::PEM,
-            )
-            .expect("Couldn't set private key");
-        ssl_acceptor_builder
-            .set_certificate_chain_file(SETTINGS.tls.certificate_path.as_ref().unwrap())
-            .expect("Couldn't set certificate chain file");
-        server.bind_openssl(listen_address, ssl_acceptor_builder)?
+    server = if settings.tls.enabled {
+        let acceptor = app_state
+            .tls
+            .acceptor()
+            .expect("Couldn't create SslAcceptor");
+        server.bind_openssl(listen_address, acceptor)?
     } else {
         server.bind(listen_address)?
     };
 
-    if SETTINGS.server.workers != 0 {
-        server = server.workers(SETTINGS.server.workers);
+    if settings.server.workers != 0 {
+        server = server.workers(settings.server.workers);
     }
 
-    server.server_hostname(&SETTINGS.server.host).run().await
+    let server = server.server_hostname(&settings.server.host).run();
+    let servers = std::iter::once(server)
+        .chain(metrics_server)
+        .chain(http_servers);
+    futures::future::try_join_all(servers).await?;
+    Ok(())
 }
 
 #[cfg(not(tarpaulin_include))]
-pub fn get_identity_service() -> IdentityService<CookieIdentityPolicy> {
-    let cookie_secret = &SETTINGS.server.secret;
+pub fn get_identity_service(
+    settings: &AppConfig,
+) -> IdentityService<CookieIdentityPolicy> {
+    let cookie_secret = &settings.server.secret;
     IdentityService::new(
         CookieIdentityPolicy::new(cookie_secret.as_bytes())
             .name("Authorization")
             //TODO change cookie age
             .max_age_secs(216000)
-            .domain(&SETTINGS.server.domain)
-            .secure(false),
+            .domain(&settings.server.domain)
+            .secure(settings.https())
+            .same_site(SameSite::Lax),
     )
 }
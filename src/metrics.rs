@@ -0,0 +1,372 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use dashmap::DashMap;
+
+use crate::db::Database;
+use crate::errors::*;
+use crate::AppData;
+
+pub const METRICS_ROUTE: &str = "/metrics";
+
+/// Users count as active sessions until this long after their last request
+pub const SESSION_ACTIVITY: Duration = Duration::from_secs(15 * 60);
+
+/// Upper bounds of the request duration histogram in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static::lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// observations per bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct RouteStats {
+    statuses: BTreeMap<u16, u64>,
+    duration: Histogram,
+}
+
+/// Counters of the whole process, exposed in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    /// keyed by method and route pattern
+    routes: DashMap<(String, String), RouteStats>,
+    active_users: DashMap<i32, Instant>,
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    login_failures: AtomicU64,
+    rate_limited: DashMap<&'static str, u64>,
+}
+
+impl Metrics {
+    /// `route` is the pattern of the matched resource, so that paths with
+    /// parameters don't create new series
+    pub fn observe_request(
+        &self,
+        method: &str,
+        route: &str,
+        status: u16,
+        duration: Duration,
+    ) {
+        let mut stats = self
+            .routes
+            .entry((method.to_owned(), route.to_owned()))
+            .or_default();
+        *stats.statuses.entry(status).or_default() += 1;
+        stats.duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_user(&self, user_id: i32, now: Instant) {
+        self.active_users.insert(user_id, now);
+    }
+
+    /// Sessions are stored in cookies, users that made a request within
+    /// [SESSION_ACTIVITY] are counted instead
+    pub fn active_sessions(&self, now: Instant) -> usize {
+        self.active_users.retain(|_, last_seen| {
+            now.saturating_duration_since(*last_seen) < SESSION_ACTIVITY
+        });
+        self.active_users.len()
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.bytes_downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn login_failed(&self) {
+        self.login_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rate_limited(&self, policy: &'static str) {
+        *self.rate_limited.entry(policy).or_default() += 1;
+    }
+
+    pub fn render(&self, db: &dyn Database, pool_max: u32) -> String {
+        let mut out = String::new();
+
+        let mut routes: Vec<_> = self.routes.iter().collect();
+        routes.sort_by(|a, b| a.key().cmp(b.key()));
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Handled HTTP requests.",
+        );
+        for entry in &routes {
+            let (method, route) = entry.key();
+            for (status, count) in &entry.statuses {
+                let _ = writeln!(
+                    out,
+                    "triox_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    escape(method),
+                    escape(route),
+                    status,
+                    count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time spent handling HTTP requests.",
+        );
+        for entry in &routes {
+            let (method, route) = entry.key();
+            let labels =
+                format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            let histogram = &entry.duration;
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "triox_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "triox_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "triox_http_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "triox_http_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+        drop(routes);
+
+        let mut values = vec![
+            (
+                "uploaded_bytes_total",
+                "counter",
+                "Bytes of uploaded files.",
+                self.bytes_uploaded.load(Ordering::Relaxed),
+            ),
+            (
+                "downloaded_bytes_total",
+                "counter",
+                "Bytes of downloaded files.",
+                self.bytes_downloaded.load(Ordering::Relaxed),
+            ),
+            (
+                "login_failures_total",
+                "counter",
+                "Failed sign in attempts.",
+                self.login_failures.load(Ordering::Relaxed),
+            ),
+            (
+                "active_sessions",
+                "gauge",
+                "Users that made a request within the last 15 minutes.",
+                self.active_sessions(Instant::now()) as u64,
+            ),
+        ];
+        values.extend([
+            (
+                "db_pool_connections",
+                "gauge",
+                "Open database connections.",
+                db.size() as u64,
+            ),
+            (
+                "db_pool_idle_connections",
+                "gauge",
+                "Idle database connections.",
+                db.num_idle() as u64,
+            ),
+            (
+                "db_pool_max_connections",
+                "gauge",
+                "Configured size of the database pool.",
+                pool_max as u64,
+            ),
+        ]);
+        for (name, kind, help, value) in values {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "triox_{} {}", name, value);
+        }
+
+        header(
+            &mut out,
+            "rate_limited_requests_total",
+            "counter",
+            "Requests rejected by the rate limiter.",
+        );
+        let mut rate_limited: Vec<_> = self
+            .rate_limited
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        rate_limited.sort();
+        for (policy, count) in rate_limited {
+            let _ = writeln!(
+                out,
+                "triox_rate_limited_requests_total{{policy=\"{}\"}} {}",
+                policy, count
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP triox_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE triox_{} {}", name, kind);
+}
+
+/// escapes label values
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// `Authorization: Bearer <token>` has to match the configured token
+pub fn authorized(token: Option<&str>, req: &HttpRequest) -> bool {
+    match token {
+        None => true,
+        Some(token) => req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map_or(false, |provided| provided == token),
+    }
+}
+
+/// Prometheus metrics
+pub async fn metrics(req: HttpRequest, data: AppData) -> ServiceResult<HttpResponse> {
+    let settings = data.settings();
+    if !authorized(settings.metrics.token.as_deref(), &req) {
+        return Err(ServiceError::InvalidCredentials);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render(&*data.db, settings.database.pool)))
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.route(METRICS_ROUTE, web::get().to(metrics));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test;
+
+    #[actix_rt::test]
+    async fn render_works() {
+        let data = crate::tests::app_state().await;
+        let metrics = Metrics::default();
+        let now = Instant::now();
+
+        metrics.observe_request(
+            "GET",
+            "/api/v1/meta/health",
+            200,
+            Duration::from_millis(20),
+        );
+        metrics.observe_request(
+            "GET",
+            "/api/v1/meta/health",
+            500,
+            Duration::from_secs(20),
+        );
+        metrics.observe_user(1, now - SESSION_ACTIVITY);
+        metrics.observe_user(2, now);
+        metrics.add_uploaded(100);
+        metrics.login_failed();
+        metrics.rate_limited("auth");
+
+        let out = metrics.render(&*data.db, 4);
+        let lines: Vec<&str> = out.lines().collect();
+        for line in [
+            r#"triox_http_requests_total{method="GET",route="/api/v1/meta/health",status="200"} 1"#,
+            r#"triox_http_requests_total{method="GET",route="/api/v1/meta/health",status="500"} 1"#,
+            r#"triox_http_request_duration_seconds_bucket{method="GET",route="/api/v1/meta/health",le="0.01"} 0"#,
+            r#"triox_http_request_duration_seconds_bucket{method="GET",route="/api/v1/meta/health",le="0.025"} 1"#,
+            r#"triox_http_request_duration_seconds_bucket{method="GET",route="/api/v1/meta/health",le="10"} 1"#,
+            r#"triox_http_request_duration_seconds_bucket{method="GET",route="/api/v1/meta/health",le="+Inf"} 2"#,
+            r#"triox_http_request_duration_seconds_count{method="GET",route="/api/v1/meta/health"} 2"#,
+            "triox_uploaded_bytes_total 100",
+            "triox_downloaded_bytes_total 0",
+            "triox_login_failures_total 1",
+            "triox_active_sessions 1",
+            "triox_db_pool_max_connections 4",
+            r#"triox_rate_limited_requests_total{policy="auth"} 1"#,
+        ] {
+            assert!(lines.contains(&line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn authorized_works() {
+        let req = test::TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        assert!(authorized(None, &req));
+        assert!(authorized(Some("secret"), &req));
+        assert!(!authorized(Some("other"), &req));
+        assert!(!authorized(
+            Some("secret"),
+            &test::TestRequest::default().to_http_request()
+        ));
+    }
+}
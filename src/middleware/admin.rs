@@ -0,0 +1,97 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#![allow(clippy::type_complexity)]
+use std::rc::Rc;
+
+use actix_identity::RequestIdentity;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{http, Error, HttpResponse};
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use super::auth::{parse_user_id, SIGIN_PAGE};
+use crate::api::v1::admin::runners::is_admin;
+use crate::errors::*;
+use crate::AppData;
+
+/// Like [CheckLogin](super::auth::CheckLogin), but additionally requires
+/// the signed in user to be an unlocked admin.
+pub struct RequireAdmin;
+
+impl<S> Transform<S, ServiceRequest> for RequireAdmin
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Transform = RequireAdminMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequireAdminMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+pub struct RequireAdminMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S> Service<ServiceRequest> for RequireAdminMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let user_id = match req.get_identity().as_deref().and_then(parse_user_id) {
+                Some(user_id) => user_id,
+                None => {
+                    return Ok(req.into_response(
+                        HttpResponse::Found()
+                            .insert_header((http::header::LOCATION, SIGIN_PAGE))
+                            .finish(),
+                    ))
+                }
+            };
+
+            let data = req.app_data::<AppData>().cloned();
+            let admin = match data {
+                Some(data) => is_admin(user_id, &data).await?,
+                None => false,
+            };
+
+            if admin {
+                service.call(req).await
+            } else {
+                Ok(req.error_response(ServiceError::AdminRequired))
+            }
+        })
+    }
+}
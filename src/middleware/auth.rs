@@ -16,20 +16,44 @@
 */
 
 #![allow(clippy::type_complexity)]
-use actix_identity::Identity;
+use std::rc::Rc;
+use std::time::Instant;
+
+use actix_identity::{Identity, RequestIdentity};
 use actix_service::{Service, Transform};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
-use actix_web::{http, Error, FromRequest, HttpResponse};
+use actix_web::{http, Error, HttpResponse};
+
+use futures::future::{ok, LocalBoxFuture, Ready};
 
-use futures::future::{ok, Either, Ready};
+use crate::api::v1::admin::is_locked;
+use crate::errors::*;
+use crate::metrics::METRICS;
+use crate::AppData;
 
 pub const SIGIN_PAGE: &str = "/sign_in";
 
+/// Parses the ID of the signed in user from the session identity.
+pub fn parse_user_id(identity: &str) -> Option<i32> {
+    identity.parse().ok()
+}
+
+/// Returns the ID of the signed in user (`triox_users.id`).
+/// Services wrapped by [CheckLogin] can rely on this to succeed.
+pub fn get_user_id(id: &Identity) -> ServiceResult<i32> {
+    id.identity()
+        .as_deref()
+        .and_then(parse_user_id)
+        .ok_or(ServiceError::PermissionDenied)
+}
+
+/// Requires a signed in user. Sessions of deleted accounts redirect to the
+/// sign in page like missing ones, locked accounts are rejected.
 pub struct CheckLogin;
 
 impl<S> Transform<S, ServiceRequest> for CheckLogin
 where
-    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Response = ServiceResponse;
@@ -39,40 +63,55 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(CheckLoginMiddleware { service })
+        ok(CheckLoginMiddleware {
+            service: Rc::new(service),
+        })
     }
 }
 pub struct CheckLoginMiddleware<S> {
-    service: S,
+    service: Rc<S>,
 }
 
 impl<S> Service<ServiceRequest> for CheckLoginMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Response = ServiceResponse;
     type Error = Error;
-    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     actix_service::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let (r, mut pl) = req.into_parts();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            // sessions created before identities were keyed by ID are rejected as well
+            let user_id = req.get_identity().as_deref().and_then(parse_user_id);
+            let account = match (user_id, req.app_data::<AppData>().cloned()) {
+                (Some(user_id), Some(data)) => {
+                    data.db.account(user_id).await.map_err(ServiceError::from)?
+                }
+                _ => None,
+            };
 
-        if let Ok(Some(_)) = Identity::from_request(&r, &mut pl)
-            .into_inner()
-            .map(|x| x.identity())
-        {
-            let req = ServiceRequest::from_parts(r, pl);
-            Either::Left(self.service.call(req))
-        } else {
-            let req = ServiceRequest::from_parts(r, pl); //.ok().unwrap();
-            Either::Right(ok(req.into_response(
-                HttpResponse::Found()
-                    .insert_header((http::header::LOCATION, SIGIN_PAGE))
-                    .finish(),
-            )))
-        }
+            match account {
+                // the status is checked on every request, so locking an
+                // account ends its sessions right away
+                Some(account) if is_locked(account.status.as_deref()) => {
+                    Ok(req.error_response(ServiceError::PermissionDenied))
+                }
+                Some(account) => {
+                    METRICS.observe_user(account.id, Instant::now());
+                    service.call(req).await
+                }
+                None => Ok(req.into_response(
+                    HttpResponse::Found()
+                        .insert_header((http::header::LOCATION, SIGIN_PAGE))
+                        .finish(),
+                )),
+            }
+        })
     }
 }
@@ -0,0 +1,288 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#![allow(clippy::type_complexity)]
+//! Protection against cross-site request forgery.
+//!
+//! Signed in users get a token in the [CSRF_COOKIE] cookie, which scripts
+//! of Triox can read but other sites can't. Requests of signed in users that
+//! change anything have to repeat it in the [CSRF_HEADER] header.
+
+use std::rc::Rc;
+
+use actix_identity::RequestIdentity;
+use actix_service::{Service, Transform};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use crate::errors::*;
+use crate::middleware::auth::parse_user_id;
+use crate::tokens::{sign, unsign};
+
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// New token for the user, signed with `secret`
+pub fn token(secret: &str, user_id: i32) -> String {
+    let nonce: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    sign(secret, &format!("csrf:{}:{}", user_id, nonce))
+}
+
+/// Returns true if `token` was issued to the user
+pub fn is_valid(secret: &str, token: &str, user_id: i32) -> bool {
+    unsign(secret, token)
+        .and_then(|payload| {
+            let (id, _nonce) = payload.strip_prefix("csrf:")?.split_once(':')?;
+            Some(id == user_id.to_string())
+        })
+        .unwrap_or(false)
+}
+
+/// Requires a CSRF token for requests of signed in users with methods other
+/// than GET, HEAD, OPTIONS and TRACE, and hands out tokens on the others.
+/// Has to be wrapped by the identity service.
+pub struct Csrf {
+    secret: Rc<String>,
+    /// the cookie is only sent over HTTPS
+    secure: bool,
+}
+
+impl Csrf {
+    pub fn new(secret: &str, secure: bool) -> Self {
+        Self {
+            secret: Rc::new(secret.to_owned()),
+            secure,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware {
+            service: Rc::new(service),
+            secret: self.secret.clone(),
+            secure: self.secure,
+        })
+    }
+}
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+    secret: Rc<String>,
+    secure: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // anonymous requests can't be forged to act on behalf of a user
+        let user_id = match req.get_identity().as_deref().and_then(parse_user_id) {
+            Some(user_id) => user_id,
+            None => return Box::pin(self.service.call(req)),
+        };
+
+        if !req.method().is_safe() {
+            let valid = req
+                .headers()
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map_or(false, |token| is_valid(&self.secret, token, user_id));
+            if !valid {
+                return Box::pin(async { Err(ServiceError::InvalidCsrfToken.into()) });
+            }
+            return Box::pin(self.service.call(req));
+        }
+
+        let has_token = req.cookie(CSRF_COOKIE).map_or(false, |cookie| {
+            is_valid(&self.secret, cookie.value(), user_id)
+        });
+        let cookie = if has_token {
+            None
+        } else {
+            // readable by scripts, SameSite keeps it from other sites
+            Some(
+                Cookie::build(CSRF_COOKIE, token(&self.secret, user_id))
+                    .path("/")
+                    .secure(self.secure)
+                    .same_site(SameSite::Strict)
+                    .finish(),
+            )
+        };
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(cookie) = cookie {
+                res.response_mut().add_cookie(&cookie)?;
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_identity::Identity;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    const SECRET: &str = "csrf test secret";
+
+    #[test]
+    fn is_valid_works() {
+        let token = token(SECRET, 1);
+        assert!(is_valid(SECRET, &token, 1));
+        assert!(!is_valid(SECRET, &token, 2));
+        assert!(!is_valid("other secret", &token, 1));
+        assert!(!is_valid(SECRET, &sign(SECRET, "1"), 1));
+        assert!(!is_valid(SECRET, "garbage", 1));
+    }
+
+    #[actix_rt::test]
+    async fn csrf_works() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Csrf::new(SECRET, false))
+                .wrap(crate::get_identity_service(&crate::tests::settings()))
+                .route(
+                    "/signin",
+                    web::post().to(|id: Identity| async move {
+                        id.remember("1".into());
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route("/", web::get().to(|| async { HttpResponse::Ok().finish() }))
+                .route(
+                    "/",
+                    web::delete().to(|| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+
+        // anonymous requests don't need a token
+        let res = test::call_service(
+            &app,
+            test::TestRequest::post().uri("/signin").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let identity = res.response().cookies().next().unwrap().into_owned();
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri("/")
+                .cookie(identity.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/")
+                .cookie(identity.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let csrf = res
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == CSRF_COOKIE)
+            .unwrap()
+            .into_owned();
+        assert!(!csrf.http_only().unwrap_or(false));
+
+        // a valid cookie isn't replaced
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/")
+                .cookie(identity.clone())
+                .cookie(csrf.clone())
+                .to_request(),
+        )
+        .await;
+        assert!(res.response().cookies().next().is_none());
+
+        // the cookie alone isn't enough
+        let res = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri("/")
+                .cookie(identity.clone())
+                .cookie(csrf.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri("/")
+                .cookie(identity.clone())
+                .insert_header((CSRF_HEADER, csrf.value()))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // tokens of other users are rejected
+        let res = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri("/")
+                .cookie(identity)
+                .insert_header((CSRF_HEADER, token(SECRET, 2)))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
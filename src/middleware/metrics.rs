@@ -0,0 +1,148 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#![allow(clippy::type_complexity)]
+use std::rc::Rc;
+use std::time::Instant;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::metrics::METRICS;
+
+/// Label of requests that didn't match a route
+const UNMATCHED: &str = "unmatched";
+/// Label of requests with a method outside of [method_label]'s list
+const OTHER_METHOD: &str = "other";
+
+/// Clients can send any method token, so only the standard methods get
+/// their own label
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => OTHER_METHOD,
+    }
+}
+
+/// Counts requests and their duration per route.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let method = method_label(req.method());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            // the route is only known after the request was routed
+            let (route, status) = match &res {
+                Ok(res) => (res.request().match_pattern(), res.status().as_u16()),
+                Err(e) => (None, e.as_response_error().status_code().as_u16()),
+            };
+            METRICS.observe_request(
+                method,
+                route.as_deref().unwrap_or(UNMATCHED),
+                status,
+                start.elapsed(),
+            );
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn request_metrics_works() {
+        let app = test::init_service(App::new().wrap(RequestMetrics).route(
+            "/metrics-test/{id}",
+            web::get().to(|| async { HttpResponse::Ok().finish() }),
+        ))
+        .await;
+
+        for id in 0..2 {
+            let req = test::TestRequest::get()
+                .uri(&format!("/metrics-test/{}", id))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        // arbitrary methods share a label
+        for method in ["FOO", "BAR"] {
+            let req = test::TestRequest::default()
+                .method(Method::from_bytes(method.as_bytes()).unwrap())
+                .uri("/metrics-test/0")
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let data = crate::tests::app_state().await;
+        let out = METRICS.render(&*data.db, 1);
+        assert!(out.lines().any(|line| line
+            == r#"triox_http_requests_total{method="GET",route="/metrics-test/{id}",status="200"} 2"#));
+        assert!(!out.contains(r#"method="FOO""#));
+        assert!(out.contains(r#"method="other""#));
+    }
+}
@@ -1,2 +1,7 @@
+pub mod admin;
 pub mod auth;
+pub mod csrf;
+pub mod metrics;
 pub mod rate_limit;
+pub mod request_id;
+pub mod security_headers;
@@ -1,39 +1,544 @@
-use actix_governor::{
-    Governor, GovernorConfig, GovernorConfigBuilder, PeerIpKeyExtractor,
-};
-use actix_optional_middleware::{Dummy, Group};
-use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
-use actix_web::Error;
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#![allow(clippy::type_complexity)]
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_identity::RequestIdentity;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::Error;
+use dashmap::DashMap;
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use super::auth::parse_user_id;
+use crate::config::{AppConfig, RateLimitPolicy};
+use crate::errors::*;
+use crate::metrics::METRICS;
+use crate::AppData;
+
+pub const X_RATELIMIT_LIMIT: &str = "x-ratelimit-limit";
+pub const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+/// Seconds until all requests of the policy are available again
+pub const X_RATELIMIT_RESET: &str = "x-ratelimit-reset";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Expired buckets are removed after this many requests
+const CLEANUP_INTERVAL: usize = 1024;
+
+/// Route groups with their own rate limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Policy {
+    Auth,
+    Uploads,
+    Downloads,
+    Account,
+}
+
+impl Policy {
+    pub const ALL: [Policy; 4] = [
+        Policy::Auth,
+        Policy::Uploads,
+        Policy::Downloads,
+        Policy::Account,
+    ];
+
+    /// Label of the policy in metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Policy::Auth => "auth",
+            Policy::Uploads => "uploads",
+            Policy::Downloads => "downloads",
+            Policy::Account => "account",
+        }
+    }
+
+    pub fn config(self, settings: &AppConfig) -> Option<RateLimitPolicy> {
+        let rate_limit = &settings.rate_limit;
+        match self {
+            Policy::Auth => rate_limit.auth.or(
+                match (
+                    settings.server.rate_limit_period,
+                    settings.server.rate_limit_burst_size,
+                ) {
+                    (Some(period), Some(burst)) => {
+                        Some(RateLimitPolicy { period, burst })
+                    }
+                    _ => None,
+                },
+            ),
+            Policy::Uploads => rate_limit.uploads,
+            Policy::Downloads => rate_limit.downloads,
+            Policy::Account => rate_limit.account,
+        }
+    }
+}
+
+/// Limiters of the configured policies
+pub struct Limiters {
+    trusted_proxies: Vec<TrustedProxy>,
+    limiters: RwLock<HashMap<Policy, Arc<Limiter>>>,
+}
+
+impl Limiters {
+    pub fn new(settings: &AppConfig) -> Self {
+        let limiters = Limiters {
+            // validated when loading the configuration
+            trusted_proxies: settings
+                .rate_limit
+                .trusted_proxies
+                .iter()
+                .map(|proxy| proxy.parse().expect("Invalid trusted proxy."))
+                .collect(),
+            limiters: RwLock::default(),
+        };
+        limiters.configure(settings);
+        limiters
+    }
+
+    /// Creates limiters for the configured policies and removes the others.
+    /// Limiters of unchanged policies keep their buckets, trusted proxies
+    /// only change on restart.
+    pub fn configure(&self, settings: &AppConfig) {
+        let mut limiters = self.limiters.write().unwrap();
+        for policy in Policy::ALL {
+            match policy.config(settings) {
+                Some(config) => {
+                    let unchanged = limiters
+                        .get(&policy)
+                        .map_or(false, |limiter| limiter.uses(config));
+                    if !unchanged {
+                        log::info!("Rate limiter for {:?} requests initialized", policy);
+                        limiters.insert(
+                            policy,
+                            Arc::new(Limiter::new(config, self.trusted_proxies.clone())),
+                        );
+                    }
+                }
+                None => {
+                    limiters.remove(&policy);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, policy: Policy) -> Option<Arc<Limiter>> {
+        self.limiters.read().unwrap().get(&policy).cloned()
+    }
+
+    pub fn trusted_proxies(&self) -> &[TrustedProxy] {
+        &self.trusted_proxies
+    }
+}
+
+/// Address or network of a reverse proxy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrustedProxy {
+    addr: IpAddr,
+    prefix: u8,
+}
 
-lazy_static::lazy_static! {
-    pub static ref RATE_LIMIT_CONFIG: Option<GovernorConfig<PeerIpKeyExtractor>> =
-    if let (Some(period), Some(burst_size)) =
-        (crate::SETTINGS.server.rate_limit_period,
-         crate::SETTINGS.server.rate_limit_burst_size)
+impl TrustedProxy {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid proxy address \"{}\"", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(TrustedProxy { addr, prefix })
+    }
+}
+
+/// Determines the client address of a request.
+///
+/// Addresses from `X-Forwarded-For` are only used while the request came
+/// through trusted proxies, the list is walked from the closest hop.
+pub fn client_ip(
+    peer: IpAddr,
+    forwarded_for: &[&str],
+    trusted: &[TrustedProxy],
+) -> IpAddr {
+    let mut client = peer;
+    for hop in forwarded_for
+        .iter()
+        .flat_map(|header| header.split(','))
+        .rev()
     {
-        if cfg!(test) {
-            return None;
-        }
-        let gov_cfg = GovernorConfigBuilder::default()
-            .per_millisecond(period)
-            .burst_size(burst_size)
-            .finish()
-            .expect("Invalid rate limiter configuration.");
-        log::info!("Rate limiter initialized");
-        Some(gov_cfg)
-    } else {
-        None
-    };
-}
-
-pub fn get_rate_limit_middleware<S>() -> Group<Dummy, Governor<PeerIpKeyExtractor>, S>
+        if !trusted.iter().any(|proxy| proxy.contains(client)) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+/// Client address of a request, see [client_ip]
+pub fn request_ip(
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+    trusted: &[TrustedProxy],
+) -> IpAddr {
+    let peer = peer
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let forwarded_for: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    client_ip(peer, &forwarded_for, trusted)
+}
+
+/// Identifies whose requests are counted together
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    User(i32),
+    Ip(IpAddr),
+}
+
+/// Outcome of a rate limit check
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until all requests are available again
+    pub reset: Duration,
+    /// Time until the next request is allowed
+    pub retry_after: Duration,
+}
+
+impl Decision {
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        // headers use whole seconds, round up so clients don't retry too early
+        let secs = |duration: Duration| {
+            let millis = duration.as_millis();
+            ((millis + 999) / 1000).to_string()
+        };
+
+        let values = [
+            (X_RATELIMIT_LIMIT, self.limit.to_string()),
+            (X_RATELIMIT_REMAINING, self.remaining.to_string()),
+            (X_RATELIMIT_RESET, secs(self.reset)),
+        ];
+        for (name, value) in values {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+        if !self.allowed {
+            if let Ok(value) = HeaderValue::from_str(&secs(self.retry_after)) {
+                headers.insert(RETRY_AFTER, value);
+            }
+        }
+    }
+}
+
+/// Token buckets of a single policy, shared by all workers
+///
+/// Stores the theoretical arrival time of the next request for every key
+/// (generic cell rate algorithm), so a bucket is empty as long as that
+/// time is more than `period * burst` ahead.
+pub struct Limiter {
+    period: Duration,
+    burst: u32,
+    trusted_proxies: Vec<TrustedProxy>,
+    buckets: DashMap<Key, Instant>,
+    requests: AtomicUsize,
+}
+
+impl Limiter {
+    pub fn new(config: RateLimitPolicy, trusted_proxies: Vec<TrustedProxy>) -> Self {
+        Limiter {
+            period: Duration::from_millis(config.period),
+            burst: config.burst,
+            trusted_proxies,
+            buckets: DashMap::new(),
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    fn uses(&self, config: RateLimitPolicy) -> bool {
+        self.period == Duration::from_millis(config.period) && self.burst == config.burst
+    }
+
+    /// Signed in users are limited per account, everyone else per address
+    pub fn key(&self, req: &ServiceRequest) -> Key {
+        if let Some(user_id) = req.get_identity().as_deref().and_then(parse_user_id) {
+            return Key::User(user_id);
+        }
+
+        Key::Ip(request_ip(
+            req.peer_addr(),
+            req.headers(),
+            &self.trusted_proxies,
+        ))
+    }
+
+    pub fn check(&self, key: Key, now: Instant) -> Decision {
+        if self.requests.fetch_add(1, Ordering::Relaxed) % CLEANUP_INTERVAL == 0 {
+            self.buckets.retain(|_, tat| *tat > now);
+        }
+
+        let capacity = self.period * self.burst;
+        let mut tat = self.buckets.entry(key).or_insert(now);
+        let next = (*tat).max(now) + self.period;
+
+        if next > now + capacity {
+            Decision {
+                allowed: false,
+                limit: self.burst,
+                remaining: 0,
+                reset: tat.saturating_duration_since(now),
+                retry_after: next - (now + capacity),
+            }
+        } else {
+            *tat = next;
+            let available = now + capacity - next;
+            Decision {
+                allowed: true,
+                limit: self.burst,
+                remaining: (available.as_millis() / self.period.as_millis()) as u32,
+                reset: next - now,
+                retry_after: Duration::ZERO,
+            }
+        }
+    }
+}
+
+/// Limits requests according to a [Policy], passes all requests
+/// if the policy isn't configured.
+pub struct RateLimit {
+    policy: Policy,
+    /// used instead of the configured limiter
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl RateLimit {
+    pub fn new(policy: Policy) -> Self {
+        RateLimit {
+            policy,
+            limiter: None,
+        }
+    }
+
+    pub fn with_limiter(policy: Policy, limiter: Arc<Limiter>) -> Self {
+        RateLimit {
+            policy,
+            limiter: Some(limiter),
+        }
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            policy: self.policy,
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    policy: Policy,
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl<S> Service<ServiceRequest> for RateLimitMiddleware<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
+    S::Future: 'static,
 {
-    if let Some(rate_limit_cfg) = &*RATE_LIMIT_CONFIG {
-        Group::Real(Rc::new(Governor::new(rate_limit_cfg)))
-    } else {
-        Group::default()
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // looked up for every request, the configuration can be reloaded
+        let limiter = self.limiter.clone().or_else(|| {
+            req.app_data::<AppData>()
+                .and_then(|data| data.limiters.get(self.policy))
+        });
+        let limiter = match limiter {
+            Some(limiter) => limiter,
+            None => return Box::pin(self.service.call(req)),
+        };
+
+        let decision = limiter.check(limiter.key(&req), Instant::now());
+        if !decision.allowed {
+            METRICS.rate_limited(self.policy.as_str());
+            let mut res = req.error_response(ServiceError::RateLimited);
+            decision.insert_headers(res.headers_mut());
+            return Box::pin(ok(res));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            decision.insert_headers(res.headers_mut());
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    fn limiter(period: u64, burst: u32) -> Limiter {
+        Limiter::new(RateLimitPolicy { period, burst }, Vec::new())
+    }
+
+    #[test]
+    fn bucket_works() {
+        let limiter = limiter(1000, 2);
+        let key = Key::User(1);
+        let now = Instant::now();
+
+        let first = limiter.check(key, now);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert_eq!(first.reset, Duration::from_secs(1));
+
+        let second = limiter.check(key, now);
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+
+        let rejected = limiter.check(key, now);
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.retry_after, Duration::from_secs(1));
+
+        // other keys have their own bucket
+        assert!(limiter.check(Key::User(2), now).allowed);
+
+        // one request is replenished per period
+        let later = now + Duration::from_millis(1500);
+        assert!(limiter.check(key, later).allowed);
+        assert!(!limiter.check(key, later).allowed);
+    }
+
+    #[test]
+    fn client_ip_works() {
+        let trusted: Vec<TrustedProxy> = ["10.0.0.0/8", "::1"]
+            .iter()
+            .map(|proxy| proxy.parse().unwrap())
+            .collect();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert!("10.0.0.0/33".parse::<TrustedProxy>().is_err());
+        assert!("localhost".parse::<TrustedProxy>().is_err());
+
+        // untrusted peers can't spoof their address
+        assert_eq!(
+            client_ip(ip("1.2.3.4"), &["5.6.7.8"], &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(client_ip(ip("10.1.2.3"), &[], &trusted), ip("10.1.2.3"));
+        assert_eq!(
+            client_ip(ip("::1"), &["5.6.7.8, 10.0.0.2"], &trusted),
+            ip("5.6.7.8")
+        );
+        // addresses added by the client are ignored
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &["9.9.9.9", "5.6.7.8"], &trusted),
+            ip("5.6.7.8")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn rate_limit_middleware_works() {
+        let app = test::init_service(
+            App::new().service(
+                web::resource("/")
+                    .wrap(RateLimit::with_limiter(
+                        Policy::Auth,
+                        Arc::new(limiter(60_000, 1)),
+                    ))
+                    .to(HttpResponse::Ok),
+            ),
+        )
+        .await;
+
+        let req = || {
+            test::TestRequest::get()
+                .uri("/")
+                .peer_addr("127.0.0.1:1234".parse().unwrap())
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, req()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(X_RATELIMIT_LIMIT).unwrap(), "1");
+        assert_eq!(resp.headers().get(X_RATELIMIT_REMAINING).unwrap(), "0");
+        assert_eq!(resp.headers().get(X_RATELIMIT_RESET).unwrap(), "60");
+
+        let resp = test::call_service(&app, req()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "60");
     }
 }
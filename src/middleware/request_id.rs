@@ -0,0 +1,183 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#![allow(clippy::type_complexity)]
+use std::rc::Rc;
+
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use crate::logging::REQUEST_ID;
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// Longest accepted ID from the `X-Request-Id` header
+pub const MAX_LENGTH: usize = 128;
+const GENERATED_LENGTH: usize = 20;
+
+/// IDs set by clients or proxies are reused if they are safe to log
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn generate() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Assigns an ID to every request, taken from the `X-Request-Id` header or
+/// generated. The ID is available to log lines and error responses while the
+/// request is handled and returned in the `X-Request-Id` response header.
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware {
+            service: Rc::new(service),
+        })
+    }
+}
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(str::to_owned)
+            .unwrap_or_else(generate);
+
+        let fut = REQUEST_ID.sync_scope(id.clone(), || self.service.call(req));
+
+        Box::pin(REQUEST_ID.scope(id.clone(), async move {
+            let header = HeaderValue::from_str(&id).ok();
+            match fut.await {
+                Ok(mut res) => {
+                    if let Some(value) = header {
+                        res.headers_mut()
+                            .insert(HeaderName::from_static(X_REQUEST_ID), value);
+                    }
+                    Ok(res)
+                }
+                // render errors while the ID is set, so that it is part of the body
+                Err(e) => {
+                    let mut res = e.error_response();
+                    if let Some(value) = header {
+                        res.headers_mut()
+                            .insert(HeaderName::from_static(X_REQUEST_ID), value);
+                    }
+                    Err(InternalError::from_response(e, res).into())
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+
+    use crate::errors::*;
+    use crate::tests::ErrorToResponse;
+
+    async fn fail() -> ServiceResult<&'static str> {
+        log::info!("failing request");
+        Err(ServiceError::BadRequest)
+    }
+
+    #[test]
+    fn is_valid_works() {
+        assert!(is_valid("f1d2-3c4b_5a.6"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("id\nwith newline"));
+        assert!(!is_valid(&"a".repeat(MAX_LENGTH + 1)));
+        assert!(is_valid(&generate()));
+    }
+
+    #[actix_rt::test]
+    async fn request_id_works() {
+        let app = test::init_service(
+            App::new().wrap(RequestId).route("/", web::get().to(fail)),
+        )
+        .await;
+
+        // generated
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/").to_request())
+                .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let id = resp.headers().get(X_REQUEST_ID).unwrap().to_str().unwrap();
+        assert_eq!(id.len(), GENERATED_LENGTH);
+        let id = id.to_owned();
+        let txt: ErrorToResponse = test::read_body_json(resp).await;
+        assert_eq!(txt.request_id, Some(id));
+
+        // taken from the request
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/")
+                .insert_header((X_REQUEST_ID, "support-1234"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.headers().get(X_REQUEST_ID).unwrap(), "support-1234");
+        let txt: ErrorToResponse = test::read_body_json(resp).await;
+        assert_eq!(txt.request_id.as_deref(), Some("support-1234"));
+    }
+}
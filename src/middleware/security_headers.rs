@@ -0,0 +1,116 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Headers that protect the pages of Triox in browsers.
+
+use actix_web::http::header;
+use actix_web::middleware::DefaultHeaders;
+
+use crate::config::AppConfig;
+
+/// Adds the security headers of the configuration and HSTS to all responses
+/// that don't set them already.
+pub fn security_headers(settings: &AppConfig) -> DefaultHeaders {
+    let config = &settings.security_headers;
+    let mut headers =
+        DefaultHeaders::new().add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+    for (name, value) in [
+        (
+            header::CONTENT_SECURITY_POLICY,
+            &config.content_security_policy,
+        ),
+        (header::X_FRAME_OPTIONS, &config.frame_options),
+        (header::REFERRER_POLICY, &config.referrer_policy),
+    ] {
+        if !value.is_empty() {
+            headers = headers.add((name, value.as_str()));
+        }
+    }
+
+    // browsers ignore the header on plain HTTP responses
+    match &settings.tls.hsts {
+        Some(hsts) if settings.https() => {
+            headers.add((header::STRICT_TRANSPORT_SECURITY, hsts.header_value()))
+        }
+        _ => headers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, web, App, HttpResponse};
+
+    use crate::config::Hsts;
+
+    #[actix_rt::test]
+    async fn security_headers_works() {
+        let mut settings = crate::tests::settings();
+        settings.security_headers.referrer_policy = String::new();
+        settings.tls.enabled = true;
+        settings.tls.hsts = Some(Hsts {
+            max_age: 60,
+            include_subdomains: false,
+            preload: false,
+        });
+
+        let app = test::init_service(
+            App::new()
+                .wrap(security_headers(&settings))
+                .route("/", web::get().to(|| async { HttpResponse::Ok().finish() }))
+                .route(
+                    "/frame",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header((header::X_FRAME_OPTIONS, "SAMEORIGIN"))
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/").to_request())
+                .await;
+        let headers = res.headers();
+        assert_eq!(
+            headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        assert_eq!(headers.get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(
+            headers.get(header::CONTENT_SECURITY_POLICY).unwrap(),
+            settings.security_headers.content_security_policy.as_str()
+        );
+        assert_eq!(
+            headers.get(header::STRICT_TRANSPORT_SECURITY).unwrap(),
+            "max-age=60"
+        );
+        assert!(headers.get(header::REFERRER_POLICY).is_none());
+
+        // headers of the handler are kept
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/frame").to_request(),
+        )
+        .await;
+        assert_eq!(
+            res.headers().get(header::X_FRAME_OPTIONS).unwrap(),
+            "SAMEORIGIN"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Database migrations.
+//!
+//! Every backend has its own migrations, see [crate::db]. Their versions and
+//! descriptions are the same, so the status reads the same for both.
+
+use std::collections::HashSet;
+
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+
+use crate::db::Database;
+
+/// Migration known to this version
+#[derive(Clone, Debug, PartialEq)]
+pub struct Migration {
+    pub version: i64,
+    pub description: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Status {
+    pub applied: Vec<Migration>,
+    pub pending: Vec<Migration>,
+    /// Migration that failed halfway and needs manual repair
+    pub dirty: Option<i64>,
+}
+
+impl Status {
+    pub fn is_up_to_date(&self) -> bool {
+        self.pending.is_empty() && self.dirty.is_none()
+    }
+}
+
+/// Compares the migrations applied through `con` with `migrator`
+pub async fn status_of<C: Migrate>(
+    con: &mut C,
+    migrator: &Migrator,
+) -> Result<Status, MigrateError> {
+    con.ensure_migrations_table().await?;
+    let dirty = con.dirty_version().await?;
+    let applied: HashSet<i64> = con
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+
+    let mut status = Status {
+        dirty,
+        ..Status::default()
+    };
+    for migration in migrator.iter() {
+        let entry = Migration {
+            version: migration.version,
+            description: migration.description.to_string(),
+        };
+        if applied.contains(&migration.version) {
+            status.applied.push(entry);
+        } else {
+            status.pending.push(entry);
+        }
+    }
+    Ok(status)
+}
+
+/// Compares the applied migrations with the migrations of this version.
+pub async fn status(db: &dyn Database) -> Result<Status, MigrateError> {
+    db.migration_status().await
+}
+
+/// Applies all pending migrations and returns them. With `dry_run`, the
+/// pending migrations are only returned.
+pub async fn up(
+    db: &dyn Database,
+    dry_run: bool,
+) -> Result<Vec<Migration>, MigrateError> {
+    db.migrate(dry_run).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn migrate_works() {
+        let data = crate::tests::app_state().await;
+
+        up(&*data.db, false).await.unwrap();
+        let status = status(&*data.db).await.unwrap();
+        assert!(status.is_up_to_date());
+        assert_eq!(status.applied.len(), data.db.migrator().iter().count());
+
+        // nothing left to apply
+        assert!(up(&*data.db, true).await.unwrap().is_empty());
+    }
+}
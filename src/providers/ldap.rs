@@ -0,0 +1,384 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ldap3::{
+    ldap_escape, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry,
+};
+
+use super::{check_status, create_account, AuthProvider};
+use crate::api::v1::admin::Role;
+use crate::config::Ldap;
+use crate::errors::*;
+use crate::AppData;
+
+/// Value of `triox_users.provider` for accounts from the directory
+pub const NAME: &str = "ldap";
+
+/// Entry of a user in the directory
+#[derive(Clone, Debug, Default)]
+pub struct Entry {
+    pub dn: String,
+    pub attrs: HashMap<String, Vec<String>>,
+}
+
+impl Entry {
+    /// values of an attribute, attribute names are case insensitive
+    pub fn values(&self, attr: &str) -> &[String] {
+        self.attrs
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(attr))
+            .map_or(&[][..], |(_, values)| values.as_slice())
+    }
+
+    pub fn first(&self, attr: &str) -> Option<&str> {
+        self.values(attr).first().map(|value| value.as_str())
+    }
+}
+
+/// Operations the provider needs from the directory server
+#[async_trait(?Send)]
+pub trait Directory: Send + Sync {
+    /// searches the entry of a user
+    async fn find(&self, login: &str) -> ServiceResult<Option<Entry>>;
+
+    /// checks the password of an entry
+    async fn bind(&self, dn: &str, password: &str) -> ServiceResult<bool>;
+}
+
+fn unavailable(e: LdapError) -> ServiceError {
+    log::error!("LDAP request failed: {}", e);
+    ServiceError::AuthProviderUnavailable
+}
+
+/// [Directory] backed by an LDAP server
+pub struct LdapDirectory {
+    config: Ldap,
+}
+
+impl LdapDirectory {
+    pub fn new(config: Ldap) -> Self {
+        Self { config }
+    }
+
+    async fn connect(&self) -> ServiceResult<ldap3::Ldap> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(self.config.timeout))
+            .set_starttls(self.config.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(unavailable)?;
+        ldap3::drive!(conn);
+        Ok(ldap)
+    }
+}
+
+#[async_trait(?Send)]
+impl Directory for LdapDirectory {
+    async fn find(&self, login: &str) -> ServiceResult<Option<Entry>> {
+        let mut ldap = self.connect().await?;
+
+        if let Some(bind_dn) = &self.config.bind_dn {
+            let password = self.config.bind_password.as_deref().unwrap_or_default();
+            ldap.simple_bind(bind_dn, password)
+                .await
+                .and_then(|res| res.success())
+                .map_err(unavailable)?;
+        }
+
+        let filter = self.config.filter.replace("{login}", &ldap_escape(login));
+        let attrs = vec![
+            self.config.username_attribute.as_str(),
+            self.config.email_attribute.as_str(),
+            self.config.group_attribute.as_str(),
+        ];
+        let (mut entries, _) = ldap
+            .search(&self.config.base_dn, Scope::Subtree, &filter, attrs)
+            .await
+            .and_then(|res| res.success())
+            .map_err(unavailable)?;
+        let _ = ldap.unbind().await;
+
+        if entries.len() > 1 {
+            log::warn!(
+                "LDAP filter matches {} entries for {}",
+                entries.len(),
+                login
+            );
+            return Err(ServiceError::InvalidCredentials);
+        }
+
+        Ok(entries.pop().map(|entry| {
+            let entry = SearchEntry::construct(entry);
+            Entry {
+                dn: entry.dn,
+                attrs: entry.attrs,
+            }
+        }))
+    }
+
+    async fn bind(&self, dn: &str, password: &str) -> ServiceResult<bool> {
+        let mut ldap = self.connect().await?;
+        let res = ldap.simple_bind(dn, password).await.map_err(unavailable)?;
+        let _ = ldap.unbind().await;
+        Ok(res.rc == 0)
+    }
+}
+
+/// Authenticates users against a directory and creates their
+/// account and storage on the first sign in.
+pub struct LdapProvider {
+    config: Ldap,
+    directory: Box<dyn Directory>,
+}
+
+impl LdapProvider {
+    pub fn new(config: Ldap) -> Self {
+        let directory = Box::new(LdapDirectory::new(config.clone()));
+        Self::with_directory(config, directory)
+    }
+
+    pub fn with_directory(config: Ldap, directory: Box<dyn Directory>) -> Self {
+        Self { config, directory }
+    }
+
+    /// maps the groups of a user to a role
+    fn role(&self, entry: &Entry) -> Role {
+        let admin = entry
+            .values(&self.config.group_attribute)
+            .iter()
+            .any(|group| {
+                self.config
+                    .admin_groups
+                    .iter()
+                    .any(|admin_group| admin_group.eq_ignore_ascii_case(group))
+            });
+
+        if admin {
+            Role::Admin
+        } else {
+            Role::User
+        }
+    }
+
+    /// returns the ID of the local account, creates it if necessary
+    async fn provision(
+        &self,
+        username: &str,
+        email: Option<&str>,
+        role: Role,
+        data: &AppData,
+    ) -> ServiceResult<i32> {
+        // the name is used in storage paths, so it has to pass the same
+        // checks as names of local accounts
+        let username = data.creds.username(username).map_err(|e| {
+            log::warn!("LDAP user {} has no valid username, ignoring", username);
+            ServiceError::from(e)
+        })?;
+        let username = username.as_str();
+
+        let existing = data.db.account_by_name(username).await?;
+
+        if let Some(user) = existing {
+            if user.provider != NAME {
+                log::warn!(
+                    "LDAP user {} has the same name as a local account, ignoring",
+                    username
+                );
+                return Err(ServiceError::AccountNotFound);
+            }
+            check_status(user.status.as_deref())?;
+
+            // group memberships might have changed
+            data.db.set_role(user.id, role).await?;
+            return Ok(user.id);
+        }
+
+        create_account(username, email, role, NAME, data).await
+    }
+}
+
+#[async_trait(?Send)]
+impl AuthProvider for LdapProvider {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    async fn authenticate(
+        &self,
+        login: &str,
+        password: &str,
+        data: &AppData,
+    ) -> ServiceResult<i32> {
+        // servers accept binds without password as anonymous binds
+        if password.is_empty() {
+            return Err(ServiceError::InvalidCredentials);
+        }
+
+        let entry = self
+            .directory
+            .find(login)
+            .await?
+            .ok_or(ServiceError::AccountNotFound)?;
+
+        if !self.directory.bind(&entry.dn, password).await? {
+            return Err(ServiceError::InvalidCredentials);
+        }
+
+        let username = entry
+            .first(&self.config.username_attribute)
+            .unwrap_or(login);
+        let email = entry.first(&self.config.email_attribute);
+        self.provision(username, email, self.role(&entry), data)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::v1::admin::runners::is_admin;
+    use crate::providers::local::LocalProvider;
+    use crate::tests::*;
+
+    const ADMINS: &str = "cn=admins,ou=groups,dc=example,dc=com";
+
+    /// in-process directory with a single user
+    struct StubDirectory {
+        entry: Entry,
+        password: &'static str,
+    }
+
+    #[async_trait(?Send)]
+    impl Directory for StubDirectory {
+        async fn find(&self, login: &str) -> ServiceResult<Option<Entry>> {
+            Ok(Some(self.entry.clone())
+                .filter(|entry| entry.first("uid") == Some(login)))
+        }
+
+        async fn bind(&self, dn: &str, password: &str) -> ServiceResult<bool> {
+            Ok(dn == self.entry.dn && password == self.password)
+        }
+    }
+
+    fn config() -> Ldap {
+        Ldap {
+            url: "ldap://localhost".into(),
+            starttls: false,
+            timeout: 5,
+            bind_dn: None,
+            bind_password: None,
+            base_dn: "ou=people,dc=example,dc=com".into(),
+            filter: "(uid={login})".into(),
+            username_attribute: "uid".into(),
+            email_attribute: "mail".into(),
+            group_attribute: "memberOf".into(),
+            admin_groups: vec![ADMINS.into()],
+        }
+    }
+
+    fn provider(name: &str, groups: Vec<String>) -> LdapProvider {
+        let mut attrs = HashMap::new();
+        attrs.insert("uid".into(), vec![name.into()]);
+        attrs.insert("mail".into(), vec![format!("{}@example.com", name)]);
+        attrs.insert("memberOf".into(), groups);
+        let entry = Entry {
+            dn: format!("uid={},ou=people,dc=example,dc=com", name),
+            attrs,
+        };
+        LdapProvider::with_directory(
+            config(),
+            Box::new(StubDirectory {
+                entry,
+                password: "ldappassword",
+            }),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn ldap_provider_works() {
+        const NAME: &str = "testldapuser";
+        const LOCAL_NAME: &str = "testldaplocal";
+        const INVALID_NAME: &str = "test ldap user";
+        const PASSWORD: &str = "longpassword";
+
+        let data = actix_web::web::Data::new(app_state().await);
+        delete_user(NAME, &data).await;
+        delete_user(LOCAL_NAME, &data).await;
+
+        let admin = provider(NAME, vec![ADMINS.into()]);
+        assert_eq!(
+            admin.authenticate("unknown", "ldappassword", &data).await,
+            Err(ServiceError::AccountNotFound)
+        );
+        assert_eq!(
+            admin.authenticate(NAME, PASSWORD, &data).await,
+            Err(ServiceError::InvalidCredentials)
+        );
+        assert_eq!(
+            admin.authenticate(NAME, "", &data).await,
+            Err(ServiceError::InvalidCredentials)
+        );
+
+        // first sign in creates account and storage
+        let id = admin
+            .authenticate(NAME, "ldappassword", &data)
+            .await
+            .unwrap();
+        assert_eq!(id, user_id(NAME, &data).await);
+        assert!(crate::apps::files::storage::user_path(id)
+            .join("files")
+            .exists());
+        assert!(is_admin(id, &data).await.unwrap());
+
+        // roles follow the group memberships
+        let user = provider(NAME, Vec::new());
+        assert_eq!(user.authenticate(NAME, "ldappassword", &data).await, Ok(id));
+        assert!(!is_admin(id, &data).await.unwrap());
+
+        // the account has no usable local password
+        assert_eq!(
+            LocalProvider
+                .authenticate(NAME, "ldappassword", &data)
+                .await,
+            Err(ServiceError::InvalidCredentials)
+        );
+
+        // local accounts aren't taken over
+        register(LOCAL_NAME, None, PASSWORD).await;
+        assert_eq!(
+            provider(LOCAL_NAME, Vec::new())
+                .authenticate(LOCAL_NAME, "ldappassword", &data)
+                .await,
+            Err(ServiceError::AccountNotFound)
+        );
+
+        // names that can't be registered are refused
+        assert!(matches!(
+            provider(INVALID_NAME, Vec::new())
+                .authenticate(INVALID_NAME, "ldappassword", &data)
+                .await,
+            Err(ServiceError::CredentialError(_))
+        ));
+        assert_eq!(data.db.user_id(INVALID_NAME).await.unwrap(), None);
+
+        delete_user(NAME, &data).await;
+        delete_user(LOCAL_NAME, &data).await;
+    }
+}
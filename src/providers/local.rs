@@ -0,0 +1,69 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use argon2_creds::Config;
+use async_trait::async_trait;
+
+use super::{check_status, AuthProvider};
+use crate::errors::*;
+use crate::AppData;
+
+/// Value of `triox_users.provider` for accounts with a local password
+pub const NAME: &str = "local";
+
+/// Checks the password hash stored in `triox_users`.
+/// Accepts both username and email address as login.
+pub struct LocalProvider;
+
+#[async_trait(?Send)]
+impl AuthProvider for LocalProvider {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    async fn authenticate(
+        &self,
+        login: &str,
+        password: &str,
+        data: &AppData,
+    ) -> ServiceResult<i32> {
+        let by_email = login.contains('@');
+
+        let res = if by_email {
+            data.db.credentials_by_email(login).await
+        } else {
+            data.db.credentials_by_name(login).await
+        };
+
+        let stored = match res {
+            Ok(Some(stored)) => stored,
+            Ok(None) => return Err(ServiceError::AccountNotFound),
+            Err(_) => return Err(ServiceError::InternalServerError),
+        };
+
+        // the password of accounts from other providers is unknown
+        if stored.provider != NAME || !Config::verify(&stored.password, password)? {
+            return Err(ServiceError::InvalidCredentials);
+        }
+        check_status(stored.status.as_deref())?;
+        // only verified addresses can be used for signing in
+        if by_email && !stored.email_verified {
+            return Err(ServiceError::EmailNotVerified);
+        }
+
+        Ok(stored.id)
+    }
+}
@@ -0,0 +1,150 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Per-account tracking of failed sign in attempts.
+//!
+//! Every failed attempt blocks further attempts on the account for an exponentially
+//! growing delay, after [Lockout::max_failures] attempts the account is locked for
+//! [Lockout::duration] and the user gets notified. Since this is tracked per account
+//! it also catches guessing that is distributed over many clients.
+//!
+//! Attempts are counted before the credentials are checked, so parallel attempts can't
+//! get past [Lockout::max_failures] while earlier ones are still in flight.
+
+use crate::config::Lockout;
+use crate::errors::*;
+use crate::mailer::{Email, Template};
+use crate::AppData;
+
+/// Seconds further attempts are blocked after `failures` consecutive failed attempts
+pub fn delay(config: &Lockout, failures: u32) -> u64 {
+    if failures >= config.max_failures {
+        return config.duration;
+    }
+
+    let factor = 1u64
+        .checked_shl(failures.saturating_sub(1))
+        .unwrap_or(u64::MAX);
+    config
+        .base_delay
+        .saturating_mul(factor)
+        .min(config.max_delay)
+}
+
+/// Counts an attempt on the account `login` refers to and returns its ID and the
+/// attempts since the last successful sign in, fails if attempts on it are
+/// currently blocked
+pub async fn check(login: &str, data: &AppData) -> ServiceResult<Option<(i32, u32)>> {
+    match data.db.add_failed_login(login).await? {
+        Some((id, attempts)) => {
+            let attempts = attempts.max(0) as u32;
+            if attempts > data.settings().lockout.max_failures {
+                // a parallel attempt already used up the last one
+                data.db.remove_failed_login(id).await?;
+                return Err(ServiceError::TooManyLoginAttempts);
+            }
+            Ok(Some((id, attempts)))
+        }
+        None if data.db.login_state(login).await?.is_some() => {
+            Err(ServiceError::TooManyLoginAttempts)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Blocks the account for a while after the attempt `check` counted as `failures` failed
+pub async fn record_failure(
+    user_id: i32,
+    failures: u32,
+    data: &AppData,
+) -> ServiceResult<()> {
+    let settings = data.settings();
+    let config = &settings.lockout;
+
+    let delay = delay(config, failures);
+    let locked = failures >= config.max_failures;
+
+    // the counter starts over once the account is locked
+    data.db.block_login(user_id, delay, locked).await?;
+
+    if locked {
+        let user = data.db.contact(user_id).await?;
+        log::warn!(
+            "Locked account {} after {} failed sign in attempts",
+            user.name,
+            failures
+        );
+
+        if let (Some(mailer), Some(email), true) =
+            (&data.mailer, user.email, user.email_verified)
+        {
+            let notification = Email {
+                to: email,
+                locale: user.locale,
+                template: Template::AccountLocked,
+                vars: vec![
+                    ("username", user.name),
+                    ("minutes", ((delay + 59) / 60).to_string()),
+                ],
+            };
+            if let Err(e) = mailer.send(notification) {
+                log::warn!("Unable to send lockout notification: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Takes back the attempt `check` counted when the password wasn't checked
+pub async fn release(user_id: i32, data: &AppData) -> ServiceResult<()> {
+    data.db.remove_failed_login(user_id).await?;
+    Ok(())
+}
+
+/// Clears the failed attempts after a successful sign in or an admin unlock
+pub async fn reset(user_id: i32, data: &AppData) -> ServiceResult<()> {
+    data.db.reset_failed_logins(user_id).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_works() {
+        let config = Lockout {
+            max_failures: 5,
+            base_delay: 2,
+            max_delay: 10,
+            duration: 600,
+        };
+
+        assert_eq!(delay(&config, 1), 2);
+        assert_eq!(delay(&config, 2), 4);
+        assert_eq!(delay(&config, 3), 8);
+        assert_eq!(delay(&config, 4), 10);
+        assert_eq!(delay(&config, 5), 600);
+        assert_eq!(delay(&config, 80), 600);
+
+        let config = Lockout {
+            max_failures: 100,
+            ..config
+        };
+        assert_eq!(delay(&config, 90), 10);
+    }
+}
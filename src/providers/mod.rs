@@ -0,0 +1,197 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use async_trait::async_trait;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use crate::api::v1::admin::{is_locked, Role, STATUS_UNVERIFIED};
+use crate::config::AppConfig;
+use crate::db::NewUser;
+use crate::errors::*;
+use crate::AppData;
+
+pub mod ldap;
+pub mod local;
+pub mod lockout;
+pub mod oidc;
+
+/// Checks credentials of users against a source like the local
+/// database or a directory service.
+#[async_trait(?Send)]
+pub trait AuthProvider: Send + Sync {
+    /// Value of `triox_users.provider` for accounts managed by this provider
+    fn name(&self) -> &'static str;
+
+    /// Returns the ID of the authenticated user.
+    /// Fails with [ServiceError::AccountNotFound] if the provider doesn't know
+    /// the user, the next provider is tried in that case.
+    async fn authenticate(
+        &self,
+        login: &str,
+        password: &str,
+        data: &AppData,
+    ) -> ServiceResult<i32>;
+}
+
+/// Configured providers in the order they are tried
+pub fn configured(settings: &AppConfig) -> Vec<Box<dyn AuthProvider>> {
+    let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+    if let Some(config) = &settings.ldap {
+        providers.push(Box::new(ldap::LdapProvider::new(config.clone())));
+    }
+    providers.push(Box::new(local::LocalProvider));
+    providers
+}
+
+/// Authenticates a user with the first provider that knows them.
+/// Unavailable providers are skipped, so local accounts can still sign in
+/// during an outage of the directory.
+/// Failed attempts are tracked per account, see [lockout].
+pub async fn authenticate(
+    login: &str,
+    password: &str,
+    data: &AppData,
+) -> ServiceResult<i32> {
+    let account = lockout::check(login, data).await?;
+
+    let mut unavailable = false;
+    let mut res = Err(ServiceError::AccountNotFound);
+    for provider in data.providers.iter() {
+        res = provider.authenticate(login, password, data).await;
+        match res {
+            Err(ServiceError::AccountNotFound) => (),
+            Err(ServiceError::AuthProviderUnavailable) => unavailable = true,
+            _ => break,
+        }
+    }
+
+    // only the answers about local accounts are final, the others might
+    // belong to the provider that couldn't be asked
+    if unavailable && res.is_err() {
+        let local = match account {
+            Some((user_id, _)) => data
+                .db
+                .account(user_id)
+                .await?
+                .map_or(false, |account| account.provider == local::NAME),
+            None => false,
+        };
+        if !local {
+            res = Err(ServiceError::AuthProviderUnavailable);
+        }
+    }
+
+    match (&res, account) {
+        (Ok(user_id), _) => lockout::reset(*user_id, data).await?,
+        (Err(ServiceError::InvalidCredentials), Some((user_id, failures))) => {
+            lockout::record_failure(user_id, failures, data).await?
+        }
+        (Err(_), Some((user_id, _))) => lockout::release(user_id, data).await?,
+        _ => (),
+    }
+    res
+}
+
+/// Rejects accounts that aren't allowed to sign in, based on `triox_users.status`.
+pub fn check_status(status: Option<&str>) -> ServiceResult<()> {
+    match status {
+        status if is_locked(status) => Err(ServiceError::AccountLocked),
+        Some(STATUS_UNVERIFIED) => Err(ServiceError::EmailNotVerified),
+        _ => Ok(()),
+    }
+}
+
+/// Creates an account that is managed by an external provider,
+/// along with its storage directory.
+pub async fn create_account(
+    username: &str,
+    verified_email: Option<&str>,
+    role: Role,
+    provider: &str,
+    data: &AppData,
+) -> ServiceResult<i32> {
+    // the account can't be used with a local password
+    let password: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let hash = data.creds.password(&password)?;
+
+    let user = NewUser {
+        name: username,
+        password: &hash,
+        email: verified_email,
+        email_verified: verified_email.is_some(),
+        role,
+        provider,
+        status: None,
+    };
+    let user_id = data.db.create_user(&user).await?;
+
+    let path = crate::apps::files::storage::user_path(user_id).join("files");
+    tokio::fs::create_dir_all(path).await?;
+
+    log::info!("Created account for {} user {}", provider, username);
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Ldap;
+    use crate::tests::*;
+
+    #[actix_rt::test]
+    async fn unavailable_provider_is_skipped() {
+        const NAME: &str = "testldapoutage";
+        const PASSWORD: &str = "longpassword";
+
+        let mut settings = settings();
+        settings.ldap = Some(Ldap {
+            // nothing listens on the port
+            url: "ldap://127.0.0.1:1".into(),
+            starttls: false,
+            timeout: 1,
+            bind_dn: None,
+            bind_password: None,
+            base_dn: "ou=people,dc=example,dc=com".into(),
+            filter: "(uid={login})".into(),
+            username_attribute: "uid".into(),
+            email_attribute: "mail".into(),
+            group_attribute: "memberOf".into(),
+            admin_groups: Vec::new(),
+        });
+        let data = actix_web::web::Data::new(app_state_with(settings).await);
+        delete_user(NAME, &data).await;
+        register(NAME, None, PASSWORD).await;
+        let id = user_id(NAME, &data).await;
+
+        assert_eq!(authenticate(NAME, PASSWORD, &data).await, Ok(id));
+        assert_eq!(
+            authenticate(NAME, "wrongpassword", &data).await,
+            Err(ServiceError::InvalidCredentials)
+        );
+        // the user might exist in the directory
+        assert_eq!(
+            authenticate("testldapunknown", PASSWORD, &data).await,
+            Err(ServiceError::AuthProviderUnavailable)
+        );
+
+        delete_user(NAME, &data).await;
+    }
+}
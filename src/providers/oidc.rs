@@ -0,0 +1,414 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::config::Oidc;
+use crate::errors::*;
+use crate::tokens::{now, sign, unsign};
+
+/// Time in seconds a user has for signing in at the identity provider
+pub const LOGIN_LIFETIME: u64 = 10 * 60;
+
+/// Endpoints of an identity provider
+#[derive(Clone, Debug, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    /// keys that sign ID tokens
+    pub jwks_uri: String,
+}
+
+/// Header of a signed JWT
+#[derive(Debug, Deserialize)]
+struct JwsHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Public key of a provider (RFC 7517)
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    /// RSA modulus and exponent
+    n: Option<String>,
+    e: Option<String>,
+    /// EC curve and coordinates
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+impl Jwk {
+    /// the key if it can verify signatures of `alg`
+    fn public_key(&self, alg: &str) -> Option<PKey<Public>> {
+        let number = |value: &Option<String>| {
+            let bytes = decode_base64(value.as_deref()?).ok()?;
+            BigNum::from_slice(&bytes).ok()
+        };
+
+        match (alg, self.kty.as_str(), self.crv.as_deref()) {
+            ("RS256", "RSA", _) => {
+                let rsa =
+                    Rsa::from_public_components(number(&self.n)?, number(&self.e)?);
+                PKey::from_rsa(rsa.ok()?).ok()
+            }
+            ("ES256", "EC", Some("P-256")) => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).ok()?;
+                let (x, y) = (number(&self.x)?, number(&self.y)?);
+                let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y);
+                PKey::from_ec_key(key.ok()?).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Claims of a validated ID token
+#[derive(Clone, Debug)]
+pub struct Claims(Map<String, Value>);
+
+impl Claims {
+    pub fn get(&self, claim: &str) -> Option<&str> {
+        self.0.get(claim).and_then(Value::as_str)
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.get("sub")
+    }
+
+    /// email address, if the provider verified it
+    pub fn verified_email(&self) -> Option<&str> {
+        match self.0.get("email_verified") {
+            Some(Value::Bool(true)) => self.get("email"),
+            _ => None,
+        }
+    }
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn unavailable<E: std::fmt::Display>(e: E) -> ServiceError {
+    log::error!("OpenID Connect request failed: {}", e);
+    ServiceError::AuthProviderUnavailable
+}
+
+/// State of a sign in between the redirect to the identity provider and the callback.
+/// It is stored in a signed cookie.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingLogin {
+    pub provider: String,
+    pub state: String,
+    pub nonce: String,
+    /// PKCE code verifier
+    pub verifier: String,
+    pub expires: u64,
+}
+
+impl PendingLogin {
+    pub fn new(provider: &str) -> Self {
+        Self {
+            provider: provider.to_owned(),
+            state: random_string(32),
+            nonce: random_string(32),
+            verifier: random_string(64),
+            expires: now() + LOGIN_LIFETIME,
+        }
+    }
+
+    /// PKCE code challenge (S256)
+    pub fn challenge(&self) -> String {
+        base64::encode_config(
+            Sha256::digest(self.verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    /// signed with `secret`, see [sign]
+    pub fn encode(&self, secret: &str) -> String {
+        sign(
+            secret,
+            &format!(
+                "{}:{}:{}:{}:{}",
+                self.expires, self.state, self.nonce, self.verifier, self.provider
+            ),
+        )
+    }
+
+    pub fn decode(secret: &str, token: &str) -> ServiceResult<Self> {
+        let payload = unsign(secret, token).ok_or(ServiceError::InvalidToken)?;
+        let parts: Vec<&str> = payload.splitn(5, ':').collect();
+        let (expires, state, nonce, verifier, provider) = match parts[..] {
+            [expires, state, nonce, verifier, provider] => {
+                (expires, state, nonce, verifier, provider)
+            }
+            _ => return Err(ServiceError::InvalidToken),
+        };
+
+        let expires: u64 = expires.parse().map_err(|_| ServiceError::InvalidToken)?;
+        if expires < now() {
+            return Err(ServiceError::InvalidToken);
+        }
+
+        Ok(Self {
+            provider: provider.to_owned(),
+            state: state.to_owned(),
+            nonce: nonce.to_owned(),
+            verifier: verifier.to_owned(),
+            expires,
+        })
+    }
+}
+
+/// Fetches the provider configuration from `{issuer}/.well-known/openid-configuration`.
+pub async fn discover(config: &Oidc) -> ServiceResult<Discovery> {
+    let issuer = config.issuer.trim_end_matches('/');
+    let url = format!("{}/.well-known/openid-configuration", issuer);
+
+    let discovery: Discovery = awc::Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(unavailable)?
+        .json()
+        .await
+        .map_err(unavailable)?;
+
+    if discovery.issuer.trim_end_matches('/') != issuer {
+        return Err(unavailable(format!(
+            "issuer mismatch, expected {} got {}",
+            issuer, discovery.issuer
+        )));
+    }
+    Ok(discovery)
+}
+
+/// URL of the authorization endpoint the user is redirected to
+pub fn authorization_url(
+    config: &Oidc,
+    discovery: &Discovery,
+    login: &PendingLogin,
+    redirect_uri: &str,
+) -> ServiceResult<String> {
+    let mut url = Url::parse(&discovery.authorization_endpoint).map_err(unavailable)?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("scope", &config.scopes.join(" "))
+        .append_pair("state", &login.state)
+        .append_pair("nonce", &login.nonce)
+        .append_pair("code_challenge", &login.challenge())
+        .append_pair("code_challenge_method", "S256");
+    Ok(url.into())
+}
+
+/// Exchanges the authorization code for an ID token and returns its validated claims.
+pub async fn exchange_code(
+    config: &Oidc,
+    discovery: &Discovery,
+    login: &PendingLogin,
+    code: &str,
+    redirect_uri: &str,
+) -> ServiceResult<Claims> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", login.verifier.as_str()),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+
+    let mut res = awc::Client::default()
+        .post(&discovery.token_endpoint)
+        .send_form(&form)
+        .await
+        .map_err(unavailable)?;
+
+    if !res.status().is_success() {
+        log::warn!("Token request at {} failed: {}", config.name, res.status());
+        return Err(ServiceError::InvalidToken);
+    }
+    let tokens: TokenResponse = res.json().await.map_err(unavailable)?;
+
+    // the endpoints might not use TLS, so the token is only trusted
+    // with a valid signature
+    verify_signature(&tokens.id_token, discovery).await?;
+    let claims = decode_id_token(&tokens.id_token)?;
+    validate(&claims, config, discovery, login)?;
+    Ok(claims)
+}
+
+fn decode_base64(value: &str) -> ServiceResult<Vec<u8>> {
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ServiceError::InvalidToken)
+}
+
+/// Checks the signature of an ID token with the keys of the provider.
+/// Only RS256 and ES256 are supported, unsigned tokens are rejected.
+async fn verify_signature(token: &str, discovery: &Discovery) -> ServiceResult<()> {
+    let (input, signature) = token.rsplit_once('.').ok_or(ServiceError::InvalidToken)?;
+    let header = input.split('.').next().unwrap_or_default();
+    let header: JwsHeader = serde_json::from_slice(&decode_base64(header)?)
+        .map_err(|_| ServiceError::InvalidToken)?;
+    let signature = decode_base64(signature)?;
+
+    let keys: JwkSet = awc::Client::default()
+        .get(&discovery.jwks_uri)
+        .send()
+        .await
+        .map_err(unavailable)?
+        .json()
+        .await
+        .map_err(unavailable)?;
+    let key = keys
+        .keys
+        .iter()
+        .filter(|key| key.usage.as_deref().map_or(true, |usage| usage == "sig"))
+        .filter(|key| header.kid.is_none() || key.kid == header.kid)
+        .find_map(|key| key.public_key(&header.alg))
+        .ok_or(ServiceError::InvalidToken)?;
+
+    match verify(&key, &header.alg, input.as_bytes(), &signature) {
+        Ok(true) => Ok(()),
+        _ => {
+            log::warn!("Rejected ID token with an invalid signature");
+            Err(ServiceError::InvalidToken)
+        }
+    }
+}
+
+fn verify(
+    key: &PKey<Public>,
+    alg: &str,
+    input: &[u8],
+    signature: &[u8],
+) -> Result<bool, ErrorStack> {
+    // JWS uses the concatenated coordinates instead of DER (RFC 7518, section 3.4)
+    let signature = if alg == "ES256" {
+        if signature.len() != 64 {
+            return Ok(false);
+        }
+        let r = BigNum::from_slice(&signature[..32])?;
+        let s = BigNum::from_slice(&signature[32..])?;
+        EcdsaSig::from_private_components(r, s)?.to_der()?
+    } else {
+        signature.to_vec()
+    };
+
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+    verifier.update(input)?;
+    verifier.verify(&signature)
+}
+
+fn decode_id_token(token: &str) -> ServiceResult<Claims> {
+    let payload = token.split('.').nth(1).ok_or(ServiceError::InvalidToken)?;
+    let payload = decode_base64(payload)?;
+    let claims: Map<String, Value> =
+        serde_json::from_slice(&payload).map_err(|_| ServiceError::InvalidToken)?;
+    Ok(Claims(claims))
+}
+
+fn validate(
+    claims: &Claims,
+    config: &Oidc,
+    discovery: &Discovery,
+    login: &PendingLogin,
+) -> ServiceResult<()> {
+    let audience_matches = match claims.0.get("aud") {
+        Some(Value::String(aud)) => aud == &config.client_id,
+        Some(Value::Array(aud)) => aud
+            .iter()
+            .any(|aud| aud.as_str() == Some(config.client_id.as_str())),
+        _ => false,
+    };
+    let expires = claims.0.get("exp").and_then(Value::as_u64).unwrap_or(0);
+
+    if claims.get("iss") != Some(discovery.issuer.as_str())
+        || !audience_matches
+        || expires < now()
+        || claims.get("nonce") != Some(login.nonce.as_str())
+        || claims.subject().is_none()
+    {
+        log::warn!("Rejected ID token from {}", config.name);
+        return Err(ServiceError::InvalidToken);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_login_works() {
+        const SECRET: &str = "pending login secret";
+        let login = PendingLogin::new("company");
+        assert_eq!(
+            PendingLogin::decode(SECRET, &login.encode(SECRET)),
+            Ok(login.clone())
+        );
+        assert_eq!(login.challenge().len(), 43);
+
+        let mut expired = login;
+        expired.expires = now() - 1;
+        assert_eq!(
+            PendingLogin::decode(SECRET, &expired.encode(SECRET)),
+            Err(ServiceError::InvalidToken)
+        );
+        assert_eq!(
+            PendingLogin::decode(SECRET, "garbage"),
+            Err(ServiceError::InvalidToken)
+        );
+    }
+}
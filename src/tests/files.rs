@@ -2,9 +2,8 @@ use actix_web::http::StatusCode;
 use actix_web::test;
 use tokio::fs;
 
-use crate::app_state::AppState;
 use crate::apps::files::list::ListResponse;
-use crate::apps::files::SourceAndDest;
+use crate::apps::files::{QueryPath, SourceAndDest};
 use crate::tests::*;
 use crate::*;
 
@@ -16,11 +15,14 @@ async fn file_works() {
     const FILE_NAME: &str = "test_file";
     const DIR_NAME: &str = "test_dir";
     let file_name2 = format!("{}/{}", DIR_NAME, FILE_NAME);
+    let dir = QueryPath {
+        path: DIR_NAME.into(),
+    };
     const FILE_NAME3: &str = "test_file2";
     const MOVE_FILE: &str = "moved";
 
     {
-        let data = AppState::new().await;
+        let data = app_state().await;
         delete_user(NAME, &data).await;
     }
 
@@ -40,7 +42,10 @@ async fn file_works() {
 
     // upload file
 
-    let file_name = format!("./data/users/{}/files/test_file", NAME);
+    let file_name = format!(
+        "./data/users/{}/files/test_file",
+        user_id(NAME, &data).await
+    );
     fs::File::create(&file_name).await.unwrap();
     fs::write(&file_name, CONTENT).await.unwrap();
 
@@ -68,7 +73,7 @@ async fn file_works() {
     // create dir
     let response = test::call_service(
         &app,
-        get_req!(&path(FILE_ROUTES.create_dir, DIR_NAME))
+        post_request!(&dir, FILE_ROUTES.create_dir)
             .cookie(cookies.clone())
             .to_request(),
     )
@@ -143,7 +148,8 @@ async fn file_works() {
     // remove and list files
     let response = test::call_service(
         &app,
-        get_req!(&path(FILE_ROUTES.remove, FILE_NAME))
+        test::TestRequest::delete()
+            .uri(&path(FILE_ROUTES.remove, FILE_NAME))
             .cookie(cookies.clone())
             .to_request(),
     )
@@ -160,3 +166,151 @@ async fn file_works() {
     let body: ListResponse = test::read_body_json(response).await;
     assert!(!body.files.iter().any(|file| file.name == FILE_NAME));
 }
+
+#[actix_rt::test]
+async fn read_only_works() {
+    const NAME: &str = "readonlyuser";
+    const PASSWORD: &str = "randompassword";
+    const DIR_NAME: &str = "test_dir";
+
+    {
+        let data = app_state().await;
+        delete_user(NAME, &data).await;
+    }
+
+    let (data, _, signin_resp) = register_and_signin(NAME, None, PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+
+    let mut read_only = settings();
+    read_only.files.read_only = true;
+    let read_only = app_state_with(read_only).await;
+    let read_only_app = get_app!(read_only).await;
+
+    let dir = QueryPath {
+        path: DIR_NAME.into(),
+    };
+
+    let mut writable = settings();
+    writable.files.read_only = false;
+    let writable = app_state_with(writable).await;
+    let writable_app = get_app!(writable).await;
+
+    let response = test::call_service(
+        &read_only_app,
+        post_request!(&dir, FILE_ROUTES.create_dir)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    let response = test::call_service(
+        &writable_app,
+        post_request!(&dir, FILE_ROUTES.create_dir)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    delete_user(NAME, &data).await;
+}
+
+#[actix_rt::test]
+async fn copy_quota_works() {
+    const NAME: &str = "copyquotauser";
+    const PASSWORD: &str = "randompassword";
+
+    {
+        let data = app_state().await;
+        delete_user(NAME, &data).await;
+    }
+
+    let (data, _, signin_resp) = register_and_signin(NAME, None, PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+    let app = get_app!(data).await;
+    let id = user_id(NAME, &data).await;
+
+    // the contents exceed the remaining quota, the directory entry itself doesn't
+    let dir = format!("./data/users/{}/files/big", id);
+    fs::create_dir_all(&dir).await.unwrap();
+    for name in ["a", "b"] {
+        fs::write(format!("{}/{}", dir, name), vec![0; 5000])
+            .await
+            .unwrap();
+    }
+    data.db.set_quota(id, Some(18_000)).await.unwrap();
+
+    let copy = SourceAndDest {
+        from: "big".into(),
+        to: "big2".into(),
+    };
+    let response = test::call_service(
+        &app,
+        post_request!(&copy, FILE_ROUTES.copy)
+            .cookie(cookies.clone())
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+    delete_user(NAME, &data).await;
+}
+
+#[actix_rt::test]
+async fn upload_quota_works() {
+    const NAME: &str = "uploadquotauser";
+    const PASSWORD: &str = "randompassword";
+    const BOUNDARY: &str = "trioxuploadboundary";
+
+    {
+        let data = app_state().await;
+        delete_user(NAME, &data).await;
+    }
+
+    let (data, _, signin_resp) = register_and_signin(NAME, None, PASSWORD).await;
+    let cookies = get_cookie!(signin_resp);
+    let app = get_app!(data).await;
+    let id = user_id(NAME, &data).await;
+
+    let upload = |name: &str, content: &[u8]| {
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n",
+            BOUNDARY, name
+        )
+        .into_bytes();
+        body.extend_from_slice(content);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+
+        post_request!(&path(FILE_ROUTES.upload, ""))
+            .insert_header((
+                actix_web::http::header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            ))
+            .cookie(cookies.clone())
+            .set_payload(body)
+            .to_request()
+    };
+
+    let file = format!("./data/users/{}/files/big", id);
+    fs::write(&file, vec![0; 5000]).await.unwrap();
+    data.db.set_quota(id, Some(8000)).await.unwrap();
+
+    // the overwritten file doesn't count
+    let response = test::call_service(&app, upload("big", &[1; 5000])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(fs::read(&file).await.unwrap(), vec![1; 5000]);
+
+    let response = test::call_service(&app, upload("other", &[1; 5000])).await;
+    assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+    assert!(fs::metadata(format!("./data/users/{}/files/other", id))
+        .await
+        .is_err());
+    let mut uploads = fs::read_dir(format!("./data/users/{}/uploads", id))
+        .await
+        .unwrap();
+    assert!(uploads.next_entry().await.unwrap().is_none());
+
+    delete_user(NAME, &data).await;
+}
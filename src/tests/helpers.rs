@@ -4,14 +4,44 @@ use actix_web::{body, test};
 use actix_web::{dev::ServiceResponse, http::StatusCode};
 use serde::{Deserialize, Serialize};
 
+use crate::api::v1::account::verify::verification_token;
 use crate::api::v1::auth::runners::{Login, Register};
 use crate::api::v1::ROUTES;
 use crate::app_state::AppState;
+use crate::config::AppConfig;
 use crate::errors::*;
 
+/// Directory of the configuration used by tests
+pub const CONFIG_DIR: &str = "config";
+
+/// Configuration of the tests, without rate limits so that tests sharing
+/// a client address don't interfere.
+pub fn settings() -> AppConfig {
+    let mut settings = AppConfig::load(CONFIG_DIR)
+        .unwrap_or_else(|problems| panic!("Invalid configuration: {:?}", problems));
+    settings.server.rate_limit_period = None;
+    settings.server.rate_limit_burst_size = None;
+    settings.rate_limit.auth = None;
+    settings.rate_limit.uploads = None;
+    settings.rate_limit.downloads = None;
+    settings.rate_limit.account = None;
+    settings
+}
+
+/// State with the configuration of the tests
+pub async fn app_state() -> Arc<AppState> {
+    app_state_with(settings()).await
+}
+
+/// State with a custom configuration, see [settings]
+pub async fn app_state_with(settings: AppConfig) -> Arc<AppState> {
+    AppState::new(CONFIG_DIR, settings).await
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ErrorToResponse {
     pub error: String,
+    pub request_id: Option<String>,
 }
 
 #[macro_export]
@@ -22,20 +52,28 @@ macro_rules! get_cookie {
 }
 
 pub async fn delete_user(name: &str, data: &AppState) {
-    let r = sqlx::query!("DELETE FROM triox_users WHERE name = ($1)", name,)
-        .execute(&data.db)
-        .await;
+    let r = match data.db.user_id(name).await {
+        Ok(Some(id)) => data.db.delete_user(id).await.map(|_| id),
+        Ok(None) => Err(sqlx::Error::RowNotFound),
+        Err(e) => Err(e),
+    };
 
     // delete storage path of the user
-    let path: std::path::PathBuf = [".", "data", "users", name].iter().collect();
-
-    let _ = tokio::fs::remove_dir_all(path);
+    if let Ok(id) = &r {
+        let path = crate::apps::files::storage::user_path(*id);
+        let _ = tokio::fs::remove_dir_all(path).await;
+    }
     println!();
     println!();
     println!();
     println!("Deleting user: {:?}", &r);
 }
 
+/// get the ID of a user
+pub async fn user_id(name: &str, data: &AppState) -> i32 {
+    data.db.user_id(name).await.unwrap().unwrap()
+}
+
 #[macro_export]
 macro_rules! post_request {
     ($uri:expr) => {
@@ -74,7 +112,7 @@ macro_rules! get_app {
     () => {
         test::init_service(
             App::new()
-                .wrap(get_identity_service())
+                .wrap(get_identity_service(&crate::tests::settings()))
                 .wrap(actix_middleware::NormalizePath::new(
                     actix_middleware::TrailingSlash::Trim,
                 ))
@@ -88,7 +126,7 @@ macro_rules! get_app {
 
     ($data:expr, "app") => {
         actix_web::App::new()
-            .wrap(crate::get_identity_service())
+            .wrap(crate::get_identity_service(&$data.settings()))
             .wrap(actix_web::middleware::NormalizePath::new(
                 actix_web::middleware::TrailingSlash::Trim,
             ))
@@ -114,7 +152,7 @@ pub async fn register_and_signin(
 
 /// register utility
 pub async fn register(name: &str, email: Option<String>, password: &str) {
-    let data = AppState::new().await;
+    let data = app_state().await;
     let app = get_app!(data).await;
 
     // 1. Register
@@ -123,6 +161,7 @@ pub async fn register(name: &str, email: Option<String>, password: &str) {
         password: password.into(),
         confirm_password: password.into(),
         email,
+        invite: None,
     };
     let resp =
         test::call_service(&app, post_request!(&msg, ROUTES.auth.register).to_request())
@@ -139,7 +178,7 @@ pub async fn signin(
     Login,
     ServiceResponse<body::EitherBody<body::BoxBody>>,
 ) {
-    let data = AppState::new().await;
+    let data = app_state().await;
     let app = get_app!(data.clone()).await;
 
     // 2. signin
@@ -154,6 +193,23 @@ pub async fn signin(
     (data, creds, signin_resp)
 }
 
+/// verify the email address of a user by following the link of the verification email
+pub async fn verify_email(name: &str, data: &Arc<AppState>) {
+    let id = user_id(name, data).await;
+    let user = data.db.contact(id).await.unwrap();
+    let token =
+        verification_token(&data.settings().server.secret, id, &user.email.unwrap());
+
+    let app = get_app!(data).await;
+    let resp = test::call_service(
+        &app,
+        get_req!(&format!("{}?token={}", ROUTES.account.verify_email, token))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::FOUND);
+}
+
 /// pub duplicate test
 pub async fn bad_post_req_test<T: Serialize>(
     name: &str,
@@ -0,0 +1,718 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Certificates from an ACME CA like Let's Encrypt (RFC 8555).
+//!
+//! The certificate and its key are written to the files of `[tls]` and
+//! loaded without a restart.
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use derive_more::Display;
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::ssl::{
+    select_next_proto, AlpnError, SslAcceptor, SslConnector, SslContext, SslMethod,
+};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Builder, X509Extension, X509NameBuilder, X509ReqBuilder, X509};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::{Certificates, ACME_TLS_ALPN};
+use crate::config::{Acme, AcmeChallenge, AppConfig, Tls};
+use crate::AppData;
+
+/// Path of HTTP-01 challenges
+pub const CHALLENGE_ROUTE: &str = "/.well-known/acme-challenge/{token}";
+
+/// Time in seconds between checks whether the certificate has to be renewed
+const CHECK_INTERVAL: u64 = 12 * 60 * 60;
+/// Time in seconds before a failed request for a certificate is retried
+const RETRY_INTERVAL: u64 = 60 * 60;
+/// Orders are polled every `POLL_INTERVAL` seconds up to `MAX_POLLS` times
+const POLL_INTERVAL: u64 = 2;
+const MAX_POLLS: u32 = 30;
+/// Maximal size of responses of the CA
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+/// id-pe-acmeIdentifier extension of TLS-ALPN-01 certificates
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+#[derive(Debug, Display)]
+pub enum AcmeError {
+    #[display(fmt = "request to the CA failed: {}", _0)]
+    Request(String),
+    /// Error document returned by the CA
+    #[display(fmt = "{}: {}", _0, _1)]
+    Problem(String, String),
+    #[display(fmt = "{}", _0)]
+    Openssl(ErrorStack),
+    #[display(fmt = "{}", _0)]
+    Io(std::io::Error),
+    #[display(fmt = "{}", _0)]
+    Failed(String),
+}
+
+impl From<ErrorStack> for AcmeError {
+    fn from(e: ErrorStack) -> Self {
+        AcmeError::Openssl(e)
+    }
+}
+
+impl From<std::io::Error> for AcmeError {
+    fn from(e: std::io::Error) -> Self {
+        AcmeError::Io(e)
+    }
+}
+
+fn request_failed<E: std::fmt::Display>(e: E) -> AcmeError {
+    AcmeError::Request(e.to_string())
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+struct Response {
+    /// URL of a created resource
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, AcmeError> {
+        serde_json::from_slice(&self.body).map_err(request_failed)
+    }
+}
+
+fn generate_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// Writes a file that only the owner can read
+fn write_private(path: &str, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)
+}
+
+/// Loads the account key or creates it on first use
+fn account_key(path: &str) -> Result<PKey<Private>, AcmeError> {
+    if Path::new(path).is_file() {
+        return Ok(PKey::private_key_from_pem(&std::fs::read(path)?)?);
+    }
+    log::info!("Creating ACME account key {}", path);
+    let key = generate_key()?;
+    write_private(path, &key.private_key_to_pem_pkcs8()?)?;
+    Ok(key)
+}
+
+/// Self-signed certificate for `domain` that answers a TLS-ALPN-01 challenge
+pub fn challenge_certificate(
+    domain: &str,
+    key_authorization: &str,
+) -> Result<(PKey<Private>, X509), ErrorStack> {
+    let key = generate_key()?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, domain)?;
+    let name = name.build();
+
+    let mut certificate = X509Builder::new()?;
+    certificate.set_version(2)?;
+    certificate
+        .set_serial_number(&BigNum::from_u32(rand::random())?.to_asn1_integer()?)?;
+    certificate.set_subject_name(&name)?;
+    certificate.set_issuer_name(&name)?;
+    certificate.set_pubkey(&key)?;
+    certificate.set_not_before(&Asn1Time::days_from_now(0)?)?;
+    certificate.set_not_after(&Asn1Time::days_from_now(7)?)?;
+    let names = SubjectAlternativeName::new()
+        .dns(domain)
+        .build(&certificate.x509v3_context(None, None))?;
+    certificate.append_extension(names)?;
+
+    // DER encoded octet string of the SHA-256 digest
+    let mut digest = vec![0x04, 0x20];
+    digest.extend_from_slice(&Sha256::digest(key_authorization.as_bytes()));
+    certificate.append_extension(X509Extension::new_from_der(
+        &Asn1Object::from_str(ACME_IDENTIFIER_OID)?,
+        true,
+        &Asn1OctetString::new_from_bytes(&digest)?,
+    )?)?;
+    certificate.sign(&key, MessageDigest::sha256())?;
+    Ok((key, certificate.build()))
+}
+
+fn challenge_context(
+    domain: &str,
+    key_authorization: &str,
+) -> Result<SslContext, ErrorStack> {
+    let (key, certificate) = challenge_certificate(domain, key_authorization)?;
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key(&key)?;
+    builder.set_certificate(&certificate)?;
+    builder.set_alpn_select_callback(|_, client| {
+        select_next_proto(ACME_TLS_ALPN, client).ok_or(AlpnError::ALERT_FATAL)
+    });
+    Ok(builder.build().into_context())
+}
+
+/// DER encoded certificate signing request for `domains`
+fn signing_request(
+    key: &PKey<Private>,
+    domains: &[String],
+) -> Result<Vec<u8>, ErrorStack> {
+    let mut request = X509ReqBuilder::new()?;
+    request.set_pubkey(key)?;
+    let mut names = SubjectAlternativeName::new();
+    for domain in domains {
+        names.dns(domain);
+    }
+    let names = names.build(&request.x509v3_context(None))?;
+    let mut extensions = Stack::new()?;
+    extensions.push(names)?;
+    request.add_extensions(&extensions)?;
+    request.sign(key, MessageDigest::sha256())?;
+    request.build().to_der()
+}
+
+/// Client for the ACME API of a CA
+struct Client {
+    http: awc::Client,
+    directory: Directory,
+    key: PKey<Private>,
+    /// Account URL, used as key ID once the account is registered
+    account: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    async fn new(acme: &Acme) -> Result<Self, AcmeError> {
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        if let Some(path) = &acme.ca_certificate {
+            connector.set_ca_file(path)?;
+        }
+        let http = awc::Client::builder()
+            .connector(awc::Connector::new().openssl(connector.build()))
+            .timeout(Duration::from_secs(30))
+            .finish();
+
+        let directory = http
+            .get(&acme.directory)
+            .send()
+            .await
+            .map_err(request_failed)?
+            .json()
+            .await
+            .map_err(request_failed)?;
+
+        Ok(Self {
+            http,
+            directory,
+            key: account_key(&acme.account_key_path)?,
+            account: None,
+            nonce: None,
+        })
+    }
+
+    /// Public account key as JWK
+    fn jwk(&self) -> Result<(String, String), ErrorStack> {
+        let key = self.key.ec_key()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        key.public_key().affine_coordinates_gfp(
+            key.group(),
+            &mut x,
+            &mut y,
+            &mut BigNumContext::new()?,
+        )?;
+        Ok((b64(&x.to_vec_padded(32)?), b64(&y.to_vec_padded(32)?)))
+    }
+
+    /// Thumbprint of the account key (RFC 7638), the members of the key
+    /// have to be in lexicographic order
+    fn thumbprint(&self) -> Result<String, ErrorStack> {
+        let (x, y) = self.jwk()?;
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        Ok(b64(&Sha256::digest(jwk.as_bytes())))
+    }
+
+    /// ES256 signature in JWS format
+    fn sign(&self, data: &[u8]) -> Result<String, ErrorStack> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(data)?;
+        let signature = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+        let mut raw = signature.r().to_vec_padded(32)?;
+        raw.extend(signature.s().to_vec_padded(32)?);
+        Ok(b64(&raw))
+    }
+
+    async fn new_nonce(&self) -> Result<String, AcmeError> {
+        let res = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(request_failed)?;
+        header_value(res.headers(), "replay-nonce")
+            .ok_or_else(|| AcmeError::Failed("the CA didn't return a nonce".into()))
+    }
+
+    /// Sends a signed request, without payload for POST-as-GET requests
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<Response, AcmeError> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.account {
+                Some(account) => protected["kid"] = json!(account),
+                None => {
+                    let (x, y) = self.jwk()?;
+                    protected["jwk"] =
+                        json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y });
+                }
+            }
+            let protected = b64(protected.to_string().as_bytes());
+            let payload = payload
+                .map(|payload| b64(payload.to_string().as_bytes()))
+                .unwrap_or_default();
+            let signature =
+                self.sign(format!("{}.{}", protected, payload).as_bytes())?;
+            let body = json!({ "protected": protected, "payload": payload, "signature": signature });
+
+            let mut res = self
+                .http
+                .post(url)
+                .insert_header((header::CONTENT_TYPE, "application/jose+json"))
+                .send_body(body.to_string())
+                .await
+                .map_err(request_failed)?;
+            self.nonce = header_value(res.headers(), "replay-nonce");
+            let status: StatusCode = res.status();
+            let response = Response {
+                location: header_value(res.headers(), "location"),
+                body: res
+                    .body()
+                    .limit(MAX_RESPONSE_SIZE)
+                    .await
+                    .map_err(request_failed)?
+                    .to_vec(),
+            };
+            if status.is_success() {
+                return Ok(response);
+            }
+
+            let problem: Problem = response.json()?;
+            // the CA may reject a nonce at any time, the next one is in the response
+            if problem.kind == BAD_NONCE && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(AcmeError::Problem(problem.kind, problem.detail));
+        }
+    }
+
+    async fn register(&mut self, email: Option<&str>) -> Result<(), AcmeError> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = self.directory.new_account.clone();
+        let res = self.post(&url, Some(&payload)).await?;
+        self.account = Some(res.location.ok_or_else(|| {
+            AcmeError::Failed("the CA didn't return the account".into())
+        })?);
+        Ok(())
+    }
+
+    /// Responds to the challenges of all pending authorizations of the order
+    async fn authorize(
+        &mut self,
+        order: &Order,
+        challenge: AcmeChallenge,
+        certificates: &Certificates,
+    ) -> Result<(), AcmeError> {
+        let kind = match challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+        let thumbprint = self.thumbprint()?;
+
+        for url in order.authorizations.iter() {
+            let authorization: Authorization = self.post(url, None).await?.json()?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let domain = &authorization.identifier.value;
+            let offered = authorization
+                .challenges
+                .iter()
+                .find(|offered| offered.kind == kind)
+                .ok_or_else(|| {
+                    AcmeError::Failed(format!(
+                        "the CA doesn't offer {} for {}",
+                        kind, domain
+                    ))
+                })?;
+
+            let key_authorization = format!("{}.{}", offered.token, thumbprint);
+            match challenge {
+                AcmeChallenge::Http01 => {
+                    certificates.set_http_challenge(&offered.token, &key_authorization)
+                }
+                AcmeChallenge::TlsAlpn01 => certificates.set_alpn_challenge(
+                    domain,
+                    challenge_context(domain, &key_authorization)?,
+                ),
+            }
+            // the CA checks the challenge after this request
+            self.post(&offered.url, Some(&json!({}))).await?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the order until the CA finished processing it
+    async fn poll(&mut self, url: &str) -> Result<Order, AcmeError> {
+        for _ in 0..MAX_POLLS {
+            let order: Order = self.post(url, None).await?.json()?;
+            match order.status.as_str() {
+                "pending" | "processing" => {
+                    actix_rt::time::sleep(Duration::from_secs(POLL_INTERVAL)).await
+                }
+                _ => return Ok(order),
+            }
+        }
+        Err(AcmeError::Failed(
+            "the CA didn't process the order in time".into(),
+        ))
+    }
+}
+
+fn expect_status(order: &Order, status: &str) -> Result<(), AcmeError> {
+    if order.status == status {
+        Ok(())
+    } else {
+        Err(AcmeError::Failed(format!("the order is {}", order.status)))
+    }
+}
+
+/// Requests a certificate for the domains of `[tls.acme]`, stores it in the
+/// files of `[tls]` and loads it.
+pub async fn obtain(
+    settings: &AppConfig,
+    certificates: &Certificates,
+) -> Result<(), AcmeError> {
+    let (acme, certificate_path, key_path) = match &settings.tls {
+        Tls {
+            acme: Some(acme),
+            certificate_path: Some(certificate_path),
+            key_path: Some(key_path),
+            ..
+        } => (acme, certificate_path, key_path),
+        _ => return Err(AcmeError::Failed("ACME isn't configured".into())),
+    };
+    let domains = settings.acme_domains();
+
+    let mut client = Client::new(acme).await?;
+    client.register(acme.email.as_deref()).await?;
+
+    let identifiers: Vec<Value> = domains
+        .iter()
+        .map(|domain| json!({ "type": "dns", "value": domain }))
+        .collect();
+    let url = client.directory.new_order.clone();
+    let res = client
+        .post(&url, Some(&json!({ "identifiers": identifiers })))
+        .await?;
+    let order_url = res
+        .location
+        .clone()
+        .ok_or_else(|| AcmeError::Failed("the CA didn't return the order".into()))?;
+    let order: Order = res.json()?;
+
+    let authorized = client.authorize(&order, acme.challenge, certificates).await;
+    let order = match authorized {
+        Ok(()) => client.poll(&order_url).await,
+        Err(e) => Err(e),
+    };
+    certificates.clear_challenges();
+    let order = order?;
+    expect_status(&order, "ready")?;
+
+    let key = generate_key()?;
+    let request = signing_request(&key, &domains)?;
+    client
+        .post(&order.finalize, Some(&json!({ "csr": b64(&request) })))
+        .await?;
+    let order = client.poll(&order_url).await?;
+    expect_status(&order, "valid")?;
+    let url = order.certificate.ok_or_else(|| {
+        AcmeError::Failed("the CA didn't return the certificate".into())
+    })?;
+    let chain = client.post(&url, None).await?.body;
+
+    write_private(key_path, &key.private_key_to_pem_pkcs8()?)?;
+    std::fs::write(certificate_path, chain)?;
+    certificates.load(&settings.tls)?;
+    Ok(())
+}
+
+/// Returns true if the certificate is missing, expires soon or doesn't cover
+/// all domains
+pub fn needs_renewal(settings: &AppConfig) -> bool {
+    let renew_before = match &settings.tls.acme {
+        Some(acme) => acme.renew_before,
+        None => return false,
+    };
+    let certificate = match settings
+        .tls
+        .certificate_path
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|pem| X509::from_pem(&pem).ok())
+    {
+        Some(certificate) => certificate,
+        None => return true,
+    };
+
+    let names: Vec<String> = certificate
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname().map(str::to_lowercase))
+                .collect()
+        })
+        .unwrap_or_default();
+    let expires_soon = match Asn1Time::days_from_now(renew_before) {
+        Ok(renew_at) => certificate.not_after() < renew_at,
+        Err(_) => true,
+    };
+    expires_soon
+        || settings
+            .acme_domains()
+            .iter()
+            .any(|domain| !names.contains(&domain.to_lowercase()))
+}
+
+/// Obtains a certificate if there is none and renews it before it expires
+pub async fn renew(settings: Arc<AppConfig>, certificates: Arc<Certificates>) {
+    loop {
+        let delay = if needs_renewal(&settings) {
+            log::info!(
+                "Requesting a certificate for {}",
+                settings.acme_domains().join(", ")
+            );
+            match obtain(&settings, &certificates).await {
+                Ok(()) => {
+                    log::info!("Installed the new certificate");
+                    CHECK_INTERVAL
+                }
+                Err(e) => {
+                    log::error!("Couldn't obtain a certificate: {}", e);
+                    RETRY_INTERVAL
+                }
+            }
+        } else {
+            CHECK_INTERVAL
+        };
+        actix_rt::time::sleep(Duration::from_secs(delay)).await;
+    }
+}
+
+/// Answers HTTP-01 challenges
+async fn challenge(token: web::Path<String>, data: AppData) -> HttpResponse {
+    match data.tls.http_challenge(&token) {
+        Some(key_authorization) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(key_authorization),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.route(CHALLENGE_ROUTE, web::get().to(challenge));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn challenge_works() {
+        let data = crate::tests::app_state().await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(data.clone()))
+                .configure(services),
+        )
+        .await;
+        let url = "/.well-known/acme-challenge/token";
+
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri(url).to_request())
+                .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        data.tls.set_http_challenge("token", "token.thumbprint");
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri(url).to_request())
+                .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, "token.thumbprint");
+
+        data.tls.clear_challenges();
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri(url).to_request())
+                .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        assert!(challenge_context("triox.example", "token.thumbprint").is_ok());
+    }
+
+    /// Runs against a Pebble test server at `TRIOX_TEST_ACME_DIRECTORY` that
+    /// doesn't validate challenges (`PEBBLE_VA_ALWAYS_VALID=1`), the root
+    /// certificate of Pebble is read from `TRIOX_TEST_ACME_CA`.
+    #[actix_rt::test]
+    async fn pebble_works() {
+        let directory = match std::env::var("TRIOX_TEST_ACME_DIRECTORY") {
+            Ok(directory) => directory,
+            Err(_) => return,
+        };
+        let dir =
+            std::env::temp_dir().join(format!("triox-acme-{}", rand::random::<u32>()));
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+
+        let mut settings = crate::tests::settings();
+        settings.server.domain = "triox.example".into();
+        settings.tls = Tls {
+            enabled: true,
+            certificate_path: Some(path("cert.pem")),
+            key_path: Some(path("key.pem")),
+            acme: Some(Acme {
+                directory,
+                email: Some("admin@triox.example".into()),
+                domains: Vec::new(),
+                challenge: AcmeChallenge::Http01,
+                http_listen: "127.0.0.1:5002".into(),
+                account_key_path: path("account.pem"),
+                renew_before: 30,
+                ca_certificate: std::env::var("TRIOX_TEST_ACME_CA").ok(),
+            }),
+            redirect_listen: None,
+            hsts: None,
+        };
+        assert!(needs_renewal(&settings));
+
+        let certificates = Certificates::default();
+        obtain(&settings, &certificates).await.unwrap();
+        assert!(certificates.current.read().unwrap().is_some());
+        assert!(!needs_renewal(&settings));
+
+        // the account is reused
+        obtain(&settings, &certificates).await.unwrap();
+        settings.tls.acme.as_mut().unwrap().renew_before = 10_000;
+        assert!(needs_renewal(&settings));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
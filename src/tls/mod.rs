@@ -0,0 +1,262 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+//! Certificates of the HTTPS server.
+//!
+//! The acceptor picks the certificate for every handshake, so a certificate
+//! loaded with [Certificates::load] is used for new connections right away.
+
+use std::sync::{Arc, RwLock};
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use dashmap::DashMap;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{
+    select_next_proto, AlpnError, ClientHelloResponse, NameType, SniError, Ssl,
+    SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod, SslRef,
+};
+
+use crate::config::Tls;
+use crate::AppData;
+
+pub mod acme;
+
+/// ALPN protocols of HTTP in order of preference
+const HTTP_ALPN: &[u8] = b"\x02h2\x08http/1.1";
+/// ALPN protocol of TLS-ALPN-01 challenges (RFC 8737)
+const ACME_TLS_ALPN: &[u8] = b"\x0aacme-tls/1";
+
+struct Current {
+    context: SslContext,
+    /// SHA-256 digest of the certificate
+    fingerprint: Vec<u8>,
+}
+
+/// Current certificate and pending ACME challenges
+#[derive(Default)]
+pub struct Certificates {
+    /// `None` until a certificate was loaded
+    current: RwLock<Option<Current>>,
+    /// Certificates answering TLS-ALPN-01 challenges by domain
+    alpn_challenges: DashMap<String, SslContext>,
+    /// Key authorizations answering HTTP-01 challenges by token
+    http_challenges: DashMap<String, String>,
+}
+
+impl Certificates {
+    /// Loads the certificate and key of `[tls]`. Returns `false` if the
+    /// certificate didn't change, the current one is kept on errors.
+    pub fn load(&self, tls: &Tls) -> Result<bool, ErrorStack> {
+        let (certificate, key) = match (&tls.certificate_path, &tls.key_path) {
+            (Some(certificate), Some(key)) => (certificate, key),
+            _ => return Ok(false),
+        };
+
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_private_key_file(key, SslFiletype::PEM)?;
+        builder.set_certificate_chain_file(certificate)?;
+        builder.check_private_key()?;
+        builder.set_alpn_select_callback(|_, client| {
+            select_next_proto(HTTP_ALPN, client).ok_or(AlpnError::NOACK)
+        });
+        let context = builder.build().into_context();
+        let fingerprint = match context.certificate() {
+            Some(certificate) => certificate.digest(MessageDigest::sha256())?.to_vec(),
+            None => Vec::new(),
+        };
+
+        let mut current = self.current.write().unwrap();
+        if matches!(&*current, Some(current) if current.fingerprint == fingerprint) {
+            return Ok(false);
+        }
+        *current = Some(Current {
+            context,
+            fingerprint,
+        });
+        Ok(true)
+    }
+
+    /// Acceptor for the HTTPS server that uses the current certificate.
+    /// Handshakes fail until a certificate is loaded.
+    pub fn acceptor(self: &Arc<Self>) -> Result<SslAcceptorBuilder, ErrorStack> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        let acme = Ssl::new_ex_index::<bool>()?;
+
+        // the offered protocols are only available in this callback
+        builder.set_client_hello_callback(move |ssl, _| {
+            let offered = ssl.client_hello_alpn().map_or(false, |protocols| {
+                protocols
+                    .windows(ACME_TLS_ALPN.len())
+                    .any(|protocol| protocol == ACME_TLS_ALPN)
+            });
+            ssl.set_ex_data(acme, offered);
+            Ok(ClientHelloResponse::SUCCESS)
+        });
+
+        let certificates = self.clone();
+        builder.set_servername_callback(move |ssl, _| {
+            let context = if ssl.ex_data(acme) == Some(&true) {
+                certificates.alpn_challenge(ssl)
+            } else {
+                certificates
+                    .current
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|current| current.context.clone())
+            };
+            match context {
+                Some(context) => ssl
+                    .set_ssl_context(&context)
+                    .map_err(|_| SniError::ALERT_FATAL),
+                None => Err(SniError::ALERT_FATAL),
+            }
+        });
+        Ok(builder)
+    }
+
+    fn alpn_challenge(&self, ssl: &SslRef) -> Option<SslContext> {
+        let domain = ssl.servername(NameType::HOST_NAME)?.to_lowercase();
+        self.alpn_challenges
+            .get(&domain)
+            .map(|context| context.clone())
+    }
+
+    /// Answers TLS-ALPN-01 challenges for `domain` with the certificate of `context`
+    pub fn set_alpn_challenge(&self, domain: &str, context: SslContext) {
+        self.alpn_challenges.insert(domain.to_lowercase(), context);
+    }
+
+    pub fn set_http_challenge(&self, token: &str, key_authorization: &str) {
+        self.http_challenges
+            .insert(token.to_owned(), key_authorization.to_owned());
+    }
+
+    /// Key authorization of a pending HTTP-01 challenge
+    pub fn http_challenge(&self, token: &str) -> Option<String> {
+        self.http_challenges.get(token).map(|value| value.clone())
+    }
+
+    pub fn clear_challenges(&self) {
+        self.alpn_challenges.clear();
+        self.http_challenges.clear();
+    }
+}
+
+/// Redirects plain HTTP requests to the same path on the HTTPS server
+async fn redirect(req: HttpRequest, data: AppData) -> HttpResponse {
+    // the configured domain is used, the Host header can't be trusted
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    HttpResponse::PermanentRedirect()
+        .append_header((
+            header::LOCATION,
+            format!("{}{}", data.settings().base_url(), path),
+        ))
+        .finish()
+}
+
+/// Redirects all requests that don't match services configured before
+pub fn redirect_services(cfg: &mut web::ServiceConfig) {
+    cfg.route("/{path:.*}", web::route().to(redirect));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn redirect_works() {
+        let mut settings = crate::tests::settings();
+        settings.tls.enabled = true;
+        settings.server.domain = "triox.example".into();
+        settings.server.port = 8443;
+        settings.server.public_url = None;
+        let data = crate::tests::app_state_with(settings).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(data.clone()))
+                .configure(acme::services)
+                .configure(redirect_services),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/files/upload?dir=a%20b")
+            .insert_header((header::HOST, "attacker.example"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers().get(header::LOCATION).unwrap(),
+            "https://triox.example:8443/api/v1/files/upload?dir=a%20b"
+        );
+
+        // ACME challenges are still answered
+        data.tls.set_http_challenge("token", "token.thumbprint");
+        let req = test::TestRequest::get()
+            .uri("/.well-known/acme-challenge/token")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn load_works() {
+        let dir =
+            std::env::temp_dir().join(format!("triox-tls-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        let tls = Tls {
+            enabled: true,
+            certificate_path: Some(path("cert.pem")),
+            key_path: Some(path("key.pem")),
+            acme: None,
+            redirect_listen: None,
+            hsts: None,
+        };
+        let write = |domain: &str| {
+            let (key, certificate) =
+                acme::challenge_certificate(domain, "token.thumbprint").unwrap();
+            std::fs::write(path("cert.pem"), certificate.to_pem().unwrap()).unwrap();
+            std::fs::write(path("key.pem"), key.private_key_to_pem_pkcs8().unwrap())
+                .unwrap();
+        };
+
+        let certificates = Arc::new(Certificates::default());
+        assert!(certificates.acceptor().is_ok());
+        assert!(certificates.load(&tls).is_err());
+
+        write("triox.example");
+        assert!(certificates.load(&tls).unwrap());
+        assert!(!certificates.load(&tls).unwrap());
+        write("triox.example");
+        assert!(certificates.load(&tls).unwrap());
+
+        // mismatching key and certificate are rejected, the current one is kept
+        let (key, _) = acme::challenge_certificate("triox.example", "other").unwrap();
+        std::fs::write(path("key.pem"), key.private_key_to_pem_pkcs8().unwrap())
+            .unwrap();
+        assert!(certificates.load(&tls).is_err());
+        assert!(certificates.current.read().unwrap().is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
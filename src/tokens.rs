@@ -0,0 +1,60 @@
+/*
+* Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU Affero General Public License as
+* published by the Free Software Foundation, either version 3 of the
+* License, or (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU Affero General Public License for more details.
+*
+* You should have received a copy of the GNU Affero General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// current UNIX timestamp in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn mac(secret: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size")
+}
+
+/// Appends an HMAC signature, keyed by `secret`, to the payload.
+/// The payload is readable by anyone holding the token.
+pub fn sign(secret: &str, payload: &str) -> String {
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    let signature = mac.finalize().into_bytes();
+
+    format!(
+        "{}.{}",
+        base64::encode_config(payload, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Returns the payload of a token created with [sign] if the signature is valid.
+pub fn unsign(secret: &str, token: &str) -> Option<String> {
+    let (payload, signature) = token.split_once('.')?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+
+    let mut mac = mac(secret);
+    mac.update(&payload);
+    mac.verify_slice(&signature).ok()?;
+
+    String::from_utf8(payload).ok()
+}
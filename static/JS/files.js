@@ -6,7 +6,10 @@ function upload_files(path = "", form, success_fn) {
 
     fetch(`/app/files/upload?path=${path}`, {
         method: "POST",
-        headers: get_jwt_header(),
+        headers: {
+            ...get_jwt_header(),
+            ...csrf_header()
+        },
         body: formData
     }).then(() => {
         if (success_fn) {
@@ -20,7 +23,8 @@ function move_file(old_path, new_path) {
     fetch(`/app/files/move`, {
             method: "POST",
             headers: {
-                'Content-Type': 'application/json'
+                'Content-Type': 'application/json',
+                ...csrf_header()
             },
             body: JSON.stringify({
                 from: old_path,
@@ -35,7 +39,8 @@ function copy_file(old_path, new_path) {
     fetch(`/app/files/copy`, {
             method: "POST",
             headers: {
-                'Content-Type': 'application/json'
+                'Content-Type': 'application/json',
+                ...csrf_header()
             },
             body: JSON.stringify({
                 from: old_path,
@@ -48,15 +53,23 @@ function copy_file(old_path, new_path) {
 function delete_file(path) {
 
     fetch(`/app/files/remove?path=${encodeURIComponent(path)}`, {
-            method: "GET",
+            method: "DELETE",
+            headers: csrf_header(),
         }).then(response => response.body)
         .then(response => load_files());
 }
 
 function create_dir(path) {
 
-    fetch(`/app/files/create_dir?path=${encodeURIComponent(path)}`, {
-            method: "GET",
+    fetch(`/app/files/create_dir`, {
+            method: "POST",
+            headers: {
+                'Content-Type': 'application/json',
+                ...csrf_header()
+            },
+            body: JSON.stringify({
+                path: path
+            }),
         }).then(response => response.body)
         .then(response => load_files());
 }
@@ -65,7 +78,10 @@ async function list_files(path = "/") {
 
     const response = await fetch(`/app/files/list?path=${encodeURIComponent(path)}`, {
         method: 'GET',
-        headers: get_jwt_header(),
+        headers: {
+            ...get_jwt_header(),
+            ...csrf_header()
+        },
     });
 
     const result = response.json();
@@ -77,7 +93,10 @@ function download_file(path) {
 
     fetch(`/app/files/get/${path}`, {
             method: "GET",
-            headers: get_jwt_header(),
+            headers: {
+            ...get_jwt_header(),
+            ...csrf_header()
+        },
         }).then(response => response.blob())
         .then(blob => {
             var url = window.URL.createObjectURL(blob);
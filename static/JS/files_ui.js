@@ -90,17 +90,16 @@ function new_list_entry(file, type) {
 
     const name_td = document.createElement("td");
 
+    // names are inserted as text, they come from the user
+    const link = document.createElement("a");
+    link.innerText = file.name;
     if (type === "File") {
-        name_td.innerHTML = `
-<a href="/app/files/get?path=${encodeURIComponent(get_dir_string(current_path, [file.name]))}" download>
-  ${file.name}
-</a>`
+        link.href = `/app/files/get?path=${encodeURIComponent(get_dir_string(current_path, [file.name]))}`;
+        link.download = "";
     } else {
-        name_td.innerHTML = `
-<a onclick="open_dir('${file.name}')">
-  ${file.name}
-</a>`
+        link.addEventListener("click", () => open_dir(file.name));
     }
+    name_td.appendChild(link);
     row.appendChild(name_td);
 
 
@@ -193,7 +192,7 @@ function new_list_entry(file, type) {
     const dropdown_td = document.createElement("td");
 
     const drop_down_html = `
-<div class="dropdown" onclick="toggle_dropdown(event)">
+<div class="dropdown">
   <div class="dropdown-trigger">
     <span class="icon has-background-link-light">
       <img src="/static/icons/keyboard_arrow_down.svg"></img>
@@ -218,6 +217,7 @@ function new_list_entry(file, type) {
 </div>`;
 
     dropdown_td.innerHTML = drop_down_html;
+    dropdown_td.firstElementChild.addEventListener("click", toggle_dropdown);
 
     row.appendChild(dropdown_td);
 
@@ -313,4 +313,9 @@ function download_file_ev(ev) {
     download_file(ev.target.innerText);
 }
 
-window.addEventListener('load', load_files);
+window.addEventListener('load', () => {
+    document.getElementById("upload-input").addEventListener("change", upload_files_ev);
+    document.getElementById("reload-btn").addEventListener("click", load_files);
+    document.getElementById("create-folder-btn").addEventListener("click", create_folder);
+    load_files();
+});
@@ -30,6 +30,9 @@ function send_json(json, url, success_fn, error_fn) {
   var xhr = new XMLHttpRequest();
   xhr.open('POST', url, true);
   xhr.setRequestHeader('Content-Type', 'application/json');
+  for (const [name, value] of Object.entries(csrf_header())) {
+    xhr.setRequestHeader(name, value);
+  }
   xhr.onreadystatechange = () => {
     if (xhr.readyState === 4) {
       console.log(`Response: ${xhr.responseText}`);
@@ -43,6 +46,21 @@ function send_json(json, url, success_fn, error_fn) {
   xhr.send(json);
 }
 
+// the server hands out the token in a cookie to signed in users
+function csrf_header() {
+  const cookie = document.cookie
+    .split('; ')
+    .find(cookie => cookie.startsWith('csrf_token='));
+
+  if (cookie) {
+    return {
+      'X-CSRF-Token': cookie.substring('csrf_token='.length),
+    };
+  } else {
+    return {};
+  }
+}
+
 function get_jwt_header() {
   const jwt = localStorage.getItem('triox-jwt');
 
@@ -0,0 +1,40 @@
+'use strict';
+
+function submitform(ev) {
+  ev.preventDefault();
+  const data = JSON.stringify({
+    login: document.getElementById('username').value,
+    password: document.getElementById('password').value,
+  });
+  send_json(
+    data,
+    '/api/v1/signin',
+    text => {
+      window.location.href = '/static/files.html';
+    },
+    text => {
+      insert_notification_on_top(document.getElementById('form'), text);
+    },
+  );
+}
+
+// single sign-on buttons
+function load_sso_providers() {
+  fetch('/api/v1/oidc/providers')
+    .then(resp => resp.json())
+    .then(providers => {
+      const sso = document.getElementById('sso');
+      for (const provider of providers) {
+        const link = document.createElement('a');
+        link.className = 'button is-block is-link is-large is-fullwidth mt-4';
+        link.href = provider.login;
+        link.textContent = provider.display_name;
+        sso.appendChild(link);
+      }
+    });
+}
+
+window.addEventListener('DOMContentLoaded', () => {
+  document.getElementById('submit-btn').addEventListener('click', submitform);
+  load_sso_providers();
+});
@@ -0,0 +1,33 @@
+'use strict';
+
+function submitform(e) {
+  e.preventDefault();
+
+  const emailElement = document.getElementById('email');
+  let email = emailElement.value;
+  if (!email.replace(/\s/g, '').length) {
+    email = null;
+  }
+
+  const data = JSON.stringify({
+    username: document.getElementById('username').value,
+    password: document.getElementById('password').value,
+    // TODO create configm password input element and get val from it
+    confirm_password: document.getElementById('password').value,
+    email,
+  });
+  send_json(
+    data,
+    '/api/v1/signup',
+    text => {
+      window.location.href = '/sign_in';
+    },
+    text => {
+      insert_notification_on_top(document.getElementById('form'), text);
+    },
+  );
+}
+
+window.addEventListener('DOMContentLoaded', () => {
+  document.getElementById('submit-btn').addEventListener('click', submitform);
+});